@@ -0,0 +1,200 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use crate::resp::{self, Value};
+
+/// appendfsync 策略
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AppendFsync {
+    // 每条写命令都 fsync
+    Always,
+    // 后台每秒 fsync 一次
+    EverySec,
+    // 交给操作系统决定何时刷盘
+    No,
+}
+
+impl AppendFsync {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "always" => Some(AppendFsync::Always),
+            "everysec" => Some(AppendFsync::EverySec),
+            "no" => Some(AppendFsync::No),
+            _ => None,
+        }
+    }
+}
+
+/// 以 RESP 格式追加写命令的 AOF 文件
+#[derive(Debug)]
+pub struct Aof {
+    file: Option<File>,
+    fsync: AppendFsync,
+    // 文件里最后一次 SELECT 的数据库，切换数据库时先补一条 SELECT
+    selected_db: Option<usize>,
+    // 上次 fsync 之后是否还有没刷盘的写入
+    pending_fsync: bool,
+}
+
+impl Aof {
+    pub fn new() -> Self {
+        Aof {
+            file: None,
+            fsync: AppendFsync::EverySec,
+            selected_db: None,
+            pending_fsync: false,
+        }
+    }
+
+    pub fn open(&mut self, path: &str) -> io::Result<()> {
+        self.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        self.selected_db = None;
+        self.pending_fsync = false;
+        Ok(())
+    }
+
+    pub fn set_fsync(&mut self, fsync: AppendFsync) {
+        self.fsync = fsync;
+    }
+
+    /// 追加一组命令，没有打开 AOF 时什么都不做
+    pub fn feed(&mut self, db_index: usize, cmds: &[Value]) -> io::Result<()> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let mut buf = Vec::new();
+        if self.selected_db != Some(db_index) {
+            let select = Value::Array(vec![
+                Value::BulkString(Some("SELECT".into())),
+                Value::BulkString(Some(db_index.to_string().into_bytes())),
+            ]);
+            buf.extend(select.serialize());
+            self.selected_db = Some(db_index);
+        }
+        for cmd in cmds {
+            buf.extend(cmd.clone().serialize());
+        }
+        file.write_all(&buf)?;
+        if self.fsync == AppendFsync::Always {
+            file.sync_data()?;
+        } else {
+            self.pending_fsync = true;
+        }
+        Ok(())
+    }
+
+    /// 立即把已经写入的内容刷到磁盘，关闭服务器前调用
+    pub fn fsync(&mut self) -> io::Result<()> {
+        if let Some(file) = self.file.as_ref() {
+            file.sync_data()?;
+        }
+        self.pending_fsync = false;
+        Ok(())
+    }
+
+    /// everysec 策略下取出一个文件句柄，交给后台线程 fsync
+    pub fn take_fsync_handle(&mut self) -> Option<File> {
+        if self.fsync != AppendFsync::EverySec || !self.pending_fsync {
+            return None;
+        }
+        self.pending_fsync = false;
+        self.file.as_ref().and_then(|file| file.try_clone().ok())
+    }
+}
+
+/// 解析出的 AOF 内容
+pub struct AofContents {
+    pub commands: Vec<Vec<Value>>,
+    // 最后一条完整命令结束的位置
+    pub valid_len: usize,
+    // 文件末尾是否有不完整的命令
+    pub truncated: bool,
+}
+
+/// 依次解析 AOF 中的命令，格式错误时返回出错位置
+/// 文件在 MULTI 和 EXEC 之间结束时丢掉整个没写完的事务，按截断处理
+pub fn parse(data: &[u8]) -> io::Result<AofContents> {
+    let mut commands = Vec::new();
+    let mut pos = 0;
+    let mut truncated = false;
+    // 还没有遇到 EXEC 的 MULTI 所在的位置和它之前的命令数
+    let mut valid_before_multi: Option<(usize, usize)> = None;
+    while pos < data.len() {
+        let bad_format = || io::Error::new(io::ErrorKind::InvalidData, format!("Bad file format reading the append only file (at offset {})", pos));
+        if data[pos] != b'*' {
+            return Err(bad_format());
+        }
+        match resp::parse_request(&data[pos..]) {
+            Ok(Some((Value::Array(items), len))) if !items.is_empty() => {
+                match items[0].as_str() {
+                    Some(name) if name.eq_ignore_ascii_case("multi") => valid_before_multi = Some((pos, commands.len())),
+                    Some(name) if name.eq_ignore_ascii_case("exec") => valid_before_multi = None,
+                    _ => {}
+                }
+                commands.push(items);
+                pos += len;
+            }
+            Ok(Some(_)) | Err(_) => return Err(bad_format()),
+            Ok(None) => {
+                truncated = true;
+                break;
+            }
+        }
+    }
+    if let Some((multi_pos, multi_index)) = valid_before_multi {
+        println!("Revert incomplete MULTI/EXEC transaction in AOF file");
+        commands.truncate(multi_index);
+        pos = multi_pos;
+        truncated = true;
+    }
+    Ok(AofContents { commands, valid_len: pos, truncated })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(args: &[&str]) -> Vec<u8> {
+        Value::Array(args.iter().map(|s| Value::BulkString(Some(s.as_bytes().to_vec()))).collect()).serialize()
+    }
+
+    #[test]
+    fn complete_transactions_are_kept() {
+        let mut data = encode(&["SET", "a", "1"]);
+        data.extend(encode(&["MULTI"]));
+        data.extend(encode(&["INCR", "a"]));
+        data.extend(encode(&["EXEC"]));
+        let contents = parse(&data).unwrap();
+        assert_eq!(contents.commands.len(), 4);
+        assert_eq!(contents.valid_len, data.len());
+        assert!(!contents.truncated);
+    }
+
+    #[test]
+    fn truncation_inside_multi_reverts_the_transaction() {
+        let mut data = encode(&["SET", "a", "1"]);
+        let before_multi = data.len();
+        data.extend(encode(&["MULTI"]));
+        data.extend(encode(&["INCR", "a"]));
+        let mut partial = data.clone();
+        partial.extend(&encode(&["EXEC"])[..3]);
+        for data in [data, partial] {
+            let contents = parse(&data).unwrap();
+            assert_eq!(contents.commands.len(), 1);
+            assert_eq!(contents.valid_len, before_multi);
+            assert!(contents.truncated);
+        }
+    }
+
+    #[test]
+    fn truncated_command_outside_multi() {
+        let mut data = encode(&["SET", "a", "1"]);
+        let valid = data.len();
+        data.extend(b"*2\r\n$3\r\nDEL");
+        let contents = parse(&data).unwrap();
+        assert_eq!(contents.commands.len(), 1);
+        assert_eq!(contents.valid_len, valid);
+        assert!(contents.truncated);
+        assert!(parse(b"+OK\r\n").is_err());
+    }
+}
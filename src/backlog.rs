@@ -0,0 +1,121 @@
+/// 复制积压缓冲区：环形保存最近传播给副本的字节，断线重连的副本可以从这里补齐
+#[derive(Debug)]
+pub struct ReplBacklog {
+    buf: Vec<u8>,
+    // 下一个字节写入的位置
+    idx: usize,
+    // 缓冲区中有效数据的长度
+    histlen: usize,
+    // 传播过的总字节数，也就是 master_repl_offset
+    offset: u64,
+}
+
+impl ReplBacklog {
+    /// 创建时从当前的 master_repl_offset 开始计数
+    pub fn new(size: usize, offset: u64) -> Self {
+        ReplBacklog {
+            buf: vec![0; size.max(1)],
+            idx: 0,
+            histlen: 0,
+            offset,
+        }
+    }
+
+    pub fn feed(&mut self, mut data: &[u8]) {
+        self.offset += data.len() as u64;
+        while !data.is_empty() {
+            let n = (self.buf.len() - self.idx).min(data.len());
+            self.buf[self.idx..self.idx + n].copy_from_slice(&data[..n]);
+            self.idx = (self.idx + n) % self.buf.len();
+            self.histlen = (self.histlen + n).min(self.buf.len());
+            data = &data[n..];
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn histlen(&self) -> usize {
+        self.histlen
+    }
+
+    pub fn master_repl_offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// 缓冲区中第一个字节的偏移量，从 1 开始计数，和 Redis 一致
+    pub fn first_byte_offset(&self) -> u64 {
+        self.offset - self.histlen as u64 + 1
+    }
+
+    /// 取出已经收到 offset 个字节的副本还缺少的数据，这部分已经被覆盖时返回 None
+    pub fn bytes_from(&self, offset: u64) -> Option<Vec<u8>> {
+        if offset > self.offset || offset < self.offset - self.histlen as u64 {
+            return None;
+        }
+        let len = (self.offset - offset) as usize;
+        let start = (self.idx + self.buf.len() - len) % self.buf.len();
+        let mut data = Vec::with_capacity(len);
+        if start + len <= self.buf.len() {
+            data.extend_from_slice(&self.buf[start..start + len]);
+        } else {
+            data.extend_from_slice(&self.buf[start..]);
+            data.extend_from_slice(&self.buf[..start + len - self.buf.len()]);
+        }
+        Some(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_everything_before_wrapping() {
+        let mut backlog = ReplBacklog::new(8, 0);
+        backlog.feed(b"abcde");
+        assert_eq!(backlog.histlen(), 5);
+        assert_eq!(backlog.master_repl_offset(), 5);
+        assert_eq!(backlog.first_byte_offset(), 1);
+        assert_eq!(backlog.bytes_from(0).unwrap(), b"abcde");
+        assert_eq!(backlog.bytes_from(3).unwrap(), b"de");
+        assert_eq!(backlog.bytes_from(5).unwrap(), b"");
+        assert!(backlog.bytes_from(6).is_none());
+    }
+
+    #[test]
+    fn wraparound_keeps_the_latest_bytes() {
+        let mut backlog = ReplBacklog::new(8, 0);
+        backlog.feed(b"abcde");
+        backlog.feed(b"fghijk");
+        assert_eq!(backlog.size(), 8);
+        assert_eq!(backlog.histlen(), 8);
+        assert_eq!(backlog.master_repl_offset(), 11);
+        assert_eq!(backlog.first_byte_offset(), 4);
+        assert_eq!(backlog.bytes_from(3).unwrap(), b"defghijk");
+        assert_eq!(backlog.bytes_from(9).unwrap(), b"jk");
+        assert!(backlog.bytes_from(2).is_none());
+        assert!(backlog.bytes_from(12).is_none());
+    }
+
+    #[test]
+    fn feed_larger_than_the_buffer() {
+        let mut backlog = ReplBacklog::new(4, 100);
+        backlog.feed(b"0123456789");
+        assert_eq!(backlog.master_repl_offset(), 110);
+        assert_eq!(backlog.histlen(), 4);
+        assert_eq!(backlog.first_byte_offset(), 107);
+        assert_eq!(backlog.bytes_from(106).unwrap(), b"6789");
+        assert!(backlog.bytes_from(105).is_none());
+    }
+
+    #[test]
+    fn starts_counting_from_the_given_offset() {
+        let backlog = ReplBacklog::new(8, 42);
+        assert_eq!(backlog.histlen(), 0);
+        assert_eq!(backlog.first_byte_offset(), 43);
+        assert_eq!(backlog.bytes_from(42).unwrap(), b"");
+        assert!(backlog.bytes_from(41).is_none());
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// 单个客户端连接的共享状态，连接任务和空闲检查任务各持有一份
+#[derive(Debug)]
+pub struct ClientState {
+    pub id: u64,
+    pub addr: SocketAddr,
+    last_interaction: std::sync::Mutex<Instant>,
+    // 正在执行命令（例如阻塞的 XREAD）时不算空闲
+    in_command: AtomicBool,
+    kill: Notify,
+}
+
+impl ClientState {
+    /// 记录一次交互，重置空闲时间
+    pub fn touch(&self) {
+        *self.last_interaction.lock().unwrap() = Instant::now();
+    }
+    pub fn set_in_command(&self, in_command: bool) {
+        self.in_command.store(in_command, Ordering::Relaxed);
+        self.touch();
+    }
+    pub fn idle_time(&self) -> Duration {
+        self.last_interaction.lock().unwrap().elapsed()
+    }
+    /// 等待连接被关闭的通知
+    pub async fn killed(&self) {
+        self.kill.notified().await;
+    }
+    pub fn kill(&self) {
+        self.kill.notify_one();
+    }
+}
+
+/// 所有普通客户端连接的登记表
+#[derive(Debug)]
+pub struct Clients {
+    clients: HashMap<u64, Arc<ClientState>>,
+    // 空闲多久后关闭连接，0 表示永不关闭
+    timeout: Duration,
+}
+
+impl Clients {
+    pub fn new() -> Self {
+        Clients { clients: HashMap::new(), timeout: Duration::ZERO }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn register(&mut self, id: u64, addr: SocketAddr) -> Arc<ClientState> {
+        let state = Arc::new(ClientState {
+            id,
+            addr,
+            last_interaction: std::sync::Mutex::new(Instant::now()),
+            in_command: AtomicBool::new(false),
+            kill: Notify::new(),
+        });
+        self.clients.insert(id, state.clone());
+        state
+    }
+
+    pub fn unregister(&mut self, id: u64) {
+        self.clients.remove(&id);
+    }
+
+    /// 关闭空闲时间超过 timeout 的客户端，返回关闭的数量
+    pub fn reap_idle(&mut self) -> usize {
+        if self.timeout.is_zero() {
+            return 0;
+        }
+        let timeout = self.timeout;
+        let idle: Vec<u64> = self
+            .clients
+            .values()
+            .filter(|c| !c.in_command.load(Ordering::Relaxed) && c.idle_time() > timeout)
+            .map(|c| c.id)
+            .collect();
+        for id in idle.iter() {
+            if let Some(client) = self.clients.remove(id) {
+                println!("Closing idle client {} ({})", client.id, client.addr);
+                client.kill();
+            }
+        }
+        idle.len()
+    }
+}
@@ -0,0 +1,351 @@
+use crate::resp::Value;
+
+// 命令标志位
+pub const WRITE: u32 = 1 << 0;
+pub const READONLY: u32 = 1 << 1;
+pub const DENYOOM: u32 = 1 << 2;
+pub const ADMIN: u32 = 1 << 3;
+pub const NOSCRIPT: u32 = 1 << 4;
+pub const BLOCKING: u32 = 1 << 5;
+pub const LOADING: u32 = 1 << 6;
+pub const STALE: u32 = 1 << 7;
+pub const FAST: u32 = 1 << 8;
+pub const MOVABLEKEYS: u32 = 1 << 9;
+
+const FLAG_NAMES: &[(u32, &str)] = &[
+    (WRITE, "write"),
+    (READONLY, "readonly"),
+    (DENYOOM, "denyoom"),
+    (ADMIN, "admin"),
+    (NOSCRIPT, "noscript"),
+    (BLOCKING, "blocking"),
+    (LOADING, "loading"),
+    (STALE, "stale"),
+    (FAST, "fast"),
+    (MOVABLEKEYS, "movablekeys"),
+];
+
+/// 一条命令的静态描述，对应 Redis 命令表中的一项
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    // 参数个数（包括命令名），正数表示必须相等，负数表示至少 -arity 个
+    pub arity: i32,
+    pub flags: u32,
+    // 第一个 key、最后一个 key 的位置（-1 表示最后一个参数）以及步长
+    pub first_key: i32,
+    pub last_key: i32,
+    pub step: i32,
+    pub acl_categories: &'static [&'static str],
+    pub group: &'static str,
+    pub since: &'static str,
+    pub summary: &'static str,
+}
+
+pub static COMMAND_TABLE: &[CommandSpec] = &[
+    CommandSpec { name: "get", arity: 2, flags: READONLY | FAST, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["read", "string", "fast"], group: "string", since: "1.0.0",
+        summary: "Returns the string value of a key." },
+    CommandSpec { name: "set", arity: -3, flags: WRITE | DENYOOM, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["write", "string", "slow"], group: "string", since: "1.0.0",
+        summary: "Sets the string value of a key, ignoring its type. The key is created if it doesn't exist." },
+    CommandSpec { name: "incr", arity: 2, flags: WRITE | DENYOOM | FAST, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["write", "string", "fast"], group: "string", since: "1.0.0",
+        summary: "Increments the integer value of a key by one. Uses 0 as initial value if the key doesn't exist." },
+    CommandSpec { name: "keys", arity: 2, flags: READONLY, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["keyspace", "read", "slow", "dangerous"], group: "generic", since: "1.0.0",
+        summary: "Returns all key names that match a pattern." },
+    CommandSpec { name: "type", arity: 2, flags: READONLY | FAST, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["keyspace", "read", "fast"], group: "generic", since: "1.0.0",
+        summary: "Determines the type of value stored at a key." },
+    CommandSpec { name: "del", arity: -2, flags: WRITE, first_key: 1, last_key: -1, step: 1,
+        acl_categories: &["keyspace", "write", "slow"], group: "generic", since: "1.0.0",
+        summary: "Deletes one or more keys." },
+    CommandSpec { name: "exists", arity: -2, flags: READONLY | FAST, first_key: 1, last_key: -1, step: 1,
+        acl_categories: &["keyspace", "read", "fast"], group: "generic", since: "1.0.0",
+        summary: "Determines whether one or more keys exist." },
+    CommandSpec { name: "expire", arity: -3, flags: WRITE | FAST, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["keyspace", "write", "fast"], group: "generic", since: "1.0.0",
+        summary: "Sets the expiration time of a key in seconds." },
+    CommandSpec { name: "pexpire", arity: -3, flags: WRITE | FAST, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["keyspace", "write", "fast"], group: "generic", since: "2.6.0",
+        summary: "Sets the expiration time of a key in milliseconds." },
+    CommandSpec { name: "expireat", arity: -3, flags: WRITE | FAST, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["keyspace", "write", "fast"], group: "generic", since: "1.2.0",
+        summary: "Sets the expiration time of a key to a Unix timestamp." },
+    CommandSpec { name: "pexpireat", arity: -3, flags: WRITE | FAST, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["keyspace", "write", "fast"], group: "generic", since: "2.6.0",
+        summary: "Sets the expiration time of a key to a Unix milliseconds timestamp." },
+    CommandSpec { name: "ttl", arity: 2, flags: READONLY | FAST, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["keyspace", "read", "fast"], group: "generic", since: "1.0.0",
+        summary: "Returns the expiration time in seconds of a key." },
+    CommandSpec { name: "pttl", arity: 2, flags: READONLY | FAST, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["keyspace", "read", "fast"], group: "generic", since: "2.6.0",
+        summary: "Returns the expiration time in milliseconds of a key." },
+    CommandSpec { name: "expiretime", arity: 2, flags: READONLY | FAST, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["keyspace", "read", "fast"], group: "generic", since: "7.0.0",
+        summary: "Returns the expiration time of a key as a Unix timestamp." },
+    CommandSpec { name: "pexpiretime", arity: 2, flags: READONLY | FAST, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["keyspace", "read", "fast"], group: "generic", since: "7.0.0",
+        summary: "Returns the expiration time of a key as a Unix milliseconds timestamp." },
+    CommandSpec { name: "persist", arity: 2, flags: WRITE | FAST, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["keyspace", "write", "fast"], group: "generic", since: "2.2.0",
+        summary: "Removes the expiration time of a key." },
+    CommandSpec { name: "dump", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["keyspace", "read", "slow"], group: "generic", since: "2.6.0",
+        summary: "Returns a serialized representation of the value stored at a key." },
+    CommandSpec { name: "restore", arity: -4, flags: WRITE | DENYOOM, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["keyspace", "write", "slow", "dangerous"], group: "generic", since: "2.6.0",
+        summary: "Creates a key from the serialized representation of a value." },
+    CommandSpec { name: "move", arity: 3, flags: WRITE | FAST, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["keyspace", "write", "fast"], group: "generic", since: "1.0.0",
+        summary: "Moves a key to another database." },
+    CommandSpec { name: "select", arity: 2, flags: LOADING | STALE | FAST, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["fast", "connection"], group: "connection", since: "1.0.0",
+        summary: "Changes the selected database." },
+    CommandSpec { name: "swapdb", arity: 3, flags: WRITE | FAST, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["keyspace", "write", "fast", "dangerous"], group: "server", since: "4.0.0",
+        summary: "Swaps two Redis databases." },
+    CommandSpec { name: "flushdb", arity: -1, flags: WRITE, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["keyspace", "write", "slow", "dangerous"], group: "server", since: "1.0.0",
+        summary: "Removes all keys from the current database." },
+    CommandSpec { name: "flushall", arity: -1, flags: WRITE, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["keyspace", "write", "slow", "dangerous"], group: "server", since: "1.0.0",
+        summary: "Removes all keys from all databases." },
+    CommandSpec { name: "dbsize", arity: 1, flags: READONLY | FAST, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["keyspace", "read", "fast"], group: "server", since: "1.0.0",
+        summary: "Returns the number of keys in the database." },
+    CommandSpec { name: "save", arity: 1, flags: ADMIN | NOSCRIPT, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["admin", "slow", "dangerous"], group: "server", since: "1.0.0",
+        summary: "Synchronously saves the database(s) to disk." },
+    CommandSpec { name: "bgsave", arity: 1, flags: ADMIN | NOSCRIPT, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["admin", "slow", "dangerous"], group: "server", since: "1.0.0",
+        summary: "Asynchronously saves the database(s) to disk." },
+    CommandSpec { name: "lastsave", arity: 1, flags: LOADING | STALE | FAST, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["admin", "fast", "dangerous"], group: "server", since: "1.0.0",
+        summary: "Returns the Unix timestamp of the last successful save to disk." },
+    CommandSpec { name: "shutdown", arity: -1, flags: ADMIN | NOSCRIPT | LOADING | STALE, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["admin", "slow", "dangerous"], group: "server", since: "1.0.0",
+        summary: "Synchronously saves the database(s) to disk and shuts down the Redis server." },
+    CommandSpec { name: "xadd", arity: -5, flags: WRITE | DENYOOM | FAST, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["write", "stream", "fast"], group: "stream", since: "5.0.0",
+        summary: "Appends a new message to a stream. Creates the key if it doesn't exist." },
+    CommandSpec { name: "xrange", arity: -4, flags: READONLY, first_key: 1, last_key: 1, step: 1,
+        acl_categories: &["read", "stream", "slow"], group: "stream", since: "5.0.0",
+        summary: "Returns the messages from a stream within a range of IDs." },
+    CommandSpec { name: "xread", arity: -4, flags: READONLY | BLOCKING | MOVABLEKEYS, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["read", "stream", "slow", "blocking"], group: "stream", since: "5.0.0",
+        summary: "Returns messages from multiple streams with IDs greater than the ones requested. Blocks until a message is available otherwise." },
+    CommandSpec { name: "ping", arity: -1, flags: FAST, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["fast", "connection"], group: "connection", since: "1.0.0",
+        summary: "Returns the server's liveliness response." },
+    CommandSpec { name: "echo", arity: 2, flags: FAST, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["fast", "connection"], group: "connection", since: "1.0.0",
+        summary: "Returns the given string." },
+    CommandSpec { name: "hello", arity: -1, flags: NOSCRIPT | LOADING | STALE | FAST, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["fast", "connection"], group: "connection", since: "6.0.0",
+        summary: "Handshakes with the Redis server." },
+    CommandSpec { name: "command", arity: -1, flags: LOADING | STALE, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["slow", "connection"], group: "server", since: "2.8.13",
+        summary: "Returns detailed information about all commands." },
+    CommandSpec { name: "config", arity: -2, flags: ADMIN | NOSCRIPT | LOADING | STALE, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["admin", "slow", "dangerous"], group: "server", since: "2.0.0",
+        summary: "A container for server configuration commands." },
+    CommandSpec { name: "info", arity: -1, flags: LOADING | STALE, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["slow", "dangerous"], group: "server", since: "1.0.0",
+        summary: "Returns information and statistics about the server." },
+    CommandSpec { name: "replconf", arity: -1, flags: ADMIN | NOSCRIPT | LOADING | STALE, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["admin", "slow", "dangerous"], group: "server", since: "3.0.0",
+        summary: "An internal command for configuring the replication stream." },
+    CommandSpec { name: "psync", arity: -3, flags: ADMIN | NOSCRIPT, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["admin", "slow", "dangerous"], group: "server", since: "2.8.0",
+        summary: "An internal command used in replication." },
+    CommandSpec { name: "replicaof", arity: 3, flags: ADMIN | NOSCRIPT | STALE, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["admin", "slow", "dangerous"], group: "server", since: "5.0.0",
+        summary: "Configures a server as replica of another, or promotes it to a master." },
+    CommandSpec { name: "slaveof", arity: 3, flags: ADMIN | NOSCRIPT | STALE, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["admin", "slow", "dangerous"], group: "server", since: "1.0.0",
+        summary: "Sets a Redis server as a replica of another, or promotes it to being a master." },
+    CommandSpec { name: "wait", arity: 3, flags: NOSCRIPT, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["slow", "connection"], group: "generic", since: "3.0.0",
+        summary: "Blocks until the asynchronous replication of all preceding write commands sent by the connection is completed." },
+    CommandSpec { name: "multi", arity: 1, flags: NOSCRIPT | LOADING | STALE | FAST, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["fast", "transaction"], group: "transactions", since: "1.2.0",
+        summary: "Starts a transaction." },
+    CommandSpec { name: "exec", arity: 1, flags: NOSCRIPT | LOADING | STALE, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["slow", "transaction"], group: "transactions", since: "1.2.0",
+        summary: "Executes all commands in a transaction." },
+    CommandSpec { name: "discard", arity: 1, flags: NOSCRIPT | LOADING | STALE | FAST, first_key: 0, last_key: 0, step: 0,
+        acl_categories: &["fast", "transaction"], group: "transactions", since: "2.0.0",
+        summary: "Discards a transaction." },
+];
+
+/// 按名字（不区分大小写）查找命令
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMAND_TABLE.iter().find(|c| c.name.eq_ignore_ascii_case(name))
+}
+
+/// 查找命令并检查参数个数，失败时返回可以直接回复给客户端的错误
+pub fn validate(name: &str, args: &[Value]) -> Result<&'static CommandSpec, Value> {
+    let spec = match lookup(name) {
+        Some(spec) => spec,
+        None => {
+            let args_preview: Vec<String> = args.iter().take(16).map(|a| format!("'{}'", a)).collect();
+            return Err(Value::Error(format!(
+                "ERR unknown command '{}', with args beginning with: {}",
+                name,
+                args_preview.join(" ")
+            )));
+        }
+    };
+    if !spec.check_arity(args.len() + 1) {
+        return Err(Value::Error(format!("ERR wrong number of arguments for '{}' command", spec.name)));
+    }
+    Ok(spec)
+}
+
+impl CommandSpec {
+    pub fn check_arity(&self, argc: usize) -> bool {
+        let argc = argc as i32;
+        if self.arity >= 0 {
+            argc == self.arity
+        } else {
+            argc >= -self.arity
+        }
+    }
+
+    /// 从参数中取出 key，args 不包含命令名
+    pub fn get_keys(&self, args: &[Value]) -> Vec<Value> {
+        if self.flags & MOVABLEKEYS != 0 {
+            return self.get_movable_keys(args);
+        }
+        if self.first_key <= 0 {
+            return vec![];
+        }
+        let argc = args.len() as i32 + 1;
+        let last = if self.last_key < 0 { argc + self.last_key } else { self.last_key };
+        let mut keys = Vec::new();
+        let mut i = self.first_key;
+        while i <= last && i < argc {
+            keys.push(args[(i - 1) as usize].clone());
+            i += self.step.max(1);
+        }
+        keys
+    }
+
+    fn get_movable_keys(&self, args: &[Value]) -> Vec<Value> {
+        match self.name {
+            // XREAD [COUNT n] [BLOCK ms] STREAMS key [key ...] id [id ...]
+            "xread" => {
+                let streams_pos = args.iter().position(|a| matches!(a.as_str(), Some(s) if s.eq_ignore_ascii_case("streams")));
+                match streams_pos {
+                    Some(pos) => {
+                        let rest = &args[pos + 1..];
+                        rest[..rest.len() / 2].to_vec()
+                    }
+                    None => vec![],
+                }
+            }
+            _ => vec![],
+        }
+    }
+
+    fn flag_names(&self) -> Vec<Value> {
+        FLAG_NAMES
+            .iter()
+            .filter(|(flag, _)| self.flags & flag != 0)
+            .map(|(_, name)| Value::SimpleString(name.to_string()))
+            .collect()
+    }
+
+    /// COMMAND INFO 中的一项
+    pub fn info(&self) -> Value {
+        Value::Array(vec![
+            Value::BulkString(Some(self.name.into())),
+            Value::Integer(self.arity as i64),
+            Value::Set(self.flag_names()),
+            Value::Integer(self.first_key as i64),
+            Value::Integer(self.last_key as i64),
+            Value::Integer(self.step as i64),
+            Value::Set(
+                self.acl_categories
+                    .iter()
+                    .map(|c| Value::SimpleString(format!("@{}", c)))
+                    .collect(),
+            ),
+            Value::Set(vec![]),
+            Value::Array(vec![]),
+            Value::Array(vec![]),
+        ])
+    }
+
+    /// COMMAND DOCS 中的一项
+    pub fn docs(&self) -> Value {
+        Value::Map(vec![
+            (Value::BulkString(Some("summary".into())), Value::BulkString(Some(self.summary.into()))),
+            (Value::BulkString(Some("since".into())), Value::BulkString(Some(self.since.into()))),
+            (Value::BulkString(Some("group".into())), Value::BulkString(Some(self.group.into()))),
+        ])
+    }
+}
+
+/// COMMAND [COUNT | LIST | INFO [name ...] | DOCS [name ...] | GETKEYS cmd [arg ...]]
+pub fn command_command(mut args: Vec<Value>) -> Value {
+    if args.is_empty() {
+        return Value::Array(COMMAND_TABLE.iter().map(|c| c.info()).collect());
+    }
+    let sub = args.remove(0);
+    match sub.as_str().map(|s| s.to_lowercase()).as_deref() {
+        Some("count") if args.is_empty() => Value::Integer(COMMAND_TABLE.len() as i64),
+        Some("list") if args.is_empty() => Value::Array(
+            COMMAND_TABLE
+                .iter()
+                .map(|c| Value::BulkString(Some(c.name.into())))
+                .collect(),
+        ),
+        Some("info") => {
+            if args.is_empty() {
+                return Value::Array(COMMAND_TABLE.iter().map(|c| c.info()).collect());
+            }
+            Value::Array(
+                args.iter()
+                    .map(|name| match name.as_str().and_then(lookup) {
+                        Some(spec) => spec.info(),
+                        None => Value::BulkString(None),
+                    })
+                    .collect(),
+            )
+        }
+        Some("docs") => {
+            let specs: Vec<&CommandSpec> = if args.is_empty() {
+                COMMAND_TABLE.iter().collect()
+            } else {
+                args.iter().filter_map(|name| name.as_str().and_then(lookup)).collect()
+            };
+            Value::Map(
+                specs
+                    .into_iter()
+                    .map(|spec| (Value::BulkString(Some(spec.name.into())), spec.docs()))
+                    .collect(),
+            )
+        }
+        Some("getkeys") if !args.is_empty() => {
+            let spec = match args[0].as_str().and_then(lookup) {
+                Some(spec) => spec,
+                None => return Value::Error("ERR Invalid command specified".to_string()),
+            };
+            if !spec.check_arity(args.len()) {
+                return Value::Error("ERR Invalid number of arguments specified for command".to_string());
+            }
+            let keys = spec.get_keys(&args[1..]);
+            if keys.is_empty() {
+                return Value::Error("ERR The command has no key arguments".to_string());
+            }
+            Value::Array(keys)
+        }
+        _ => Value::Error(format!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try COMMAND HELP.",
+            sub
+        )),
+    }
+}
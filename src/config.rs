@@ -1,487 +1,723 @@
 use std::collections::HashMap;
-use std::thread;
 use crate::resp::Value;
-use crate::duplication::RCliInfo;
-use crate::slave_stream::Slaves;
-use std::time::{Duration, SystemTime};
-use regex::Regex;
+use crate::duplication::{new_replid, RCliInfo};
+use crate::slave_stream::{ReplicaSync, Slaves, REPL_BACKLOG_SIZE};
+use crate::client::{Clients, ClientState};
+use std::net::SocketAddr;
+use std::time::Duration;
 use crate::resp::RespHandler;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OwnedMutexGuard};
 use tokio::sync::RwLock;
+use tokio::sync::watch;
 use std::sync::Arc;
-use byteorder::{LittleEndian, ReadBytesExt};
 use std::fs::File;
-use std::io::{self, Read, Seek, SeekFrom,Cursor};
-use std::error::Error;
-use std::time::UNIX_EPOCH;
+use std::io::{self, Read};
 use tokio::time;
-use crate::stream::Stream;
+use crate::keyspace::{Database, Keyspace};
+use crate::rdb;
+use crate::persistence::{self, RdbState};
+use crate::aof::{self, Aof, AppendFsync};
+use crate::db::RedisDb;
+use crate::replication;
+use tokio::task::JoinHandle;
+use crate::command;
 use anyhow::Result;
 
+// 默认的逻辑数据库个数
+const DEFAULT_DATABASES: usize = 16;
+// 默认的自动保存规则，和 Redis 一致
+const DEFAULT_SAVE_PARAMS: &str = "3600 1 300 100 60 10000";
+// INFO 支持的段，按输出顺序排列
+const INFO_SECTIONS: [&str; 4] = ["persistence", "stats", "replication", "keyspace"];
+// 主动过期的执行间隔和每次最多占用的时间
+const ACTIVE_EXPIRE_PERIOD: Duration = Duration::from_millis(100);
+const ACTIVE_EXPIRE_TIME_LIMIT: Duration = Duration::from_millis(25);
+// 检查 save 规则的间隔
+const SAVE_RULES_PERIOD: Duration = Duration::from_millis(100);
+// 关闭前等待副本追上的最长时间，以及检查的间隔
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const SHUTDOWN_CHECK_PERIOD: Duration = Duration::from_millis(100);
+// appendfsync everysec 的刷盘间隔
+const AOF_FSYNC_PERIOD: Duration = Duration::from_secs(1);
+
+/// 服务器的全局状态：配置项、键空间、复制信息和客户端列表
 #[derive(Debug)]
-#[warn(unused_variables)]
 pub struct Config{
-    rdbfile: HashMap<String, Value>,
-    rdbfile_content: HashMap<String, Value>,
+    // 命令行和 CONFIG SET 设置的配置项
+    settings: HashMap<String, Value>,
+    keyspace: Keyspace,
     metadata: HashMap<String, Value>,
-    expirations: HashMap<String, SystemTime>,
     rcliinfo:RCliInfo,
     slaves_handler:Arc<RwLock<Slaves>>,
-    stream:Stream,
-    key_type:HashMap<Value,String>,
     my_offset:usize,
+    // 副本是否已经和主节点同步过，决定重连时能否尝试部分同步
+    master_synced: bool,
+    // 作为副本时的主节点地址和复制任务，切换主节点或者 REPLICAOF NO ONE 时取消任务
+    master: Option<(SocketAddr, JoinHandle<()>)>,
+    clients:Arc<Mutex<Clients>>,
+    rdb_state:Arc<Mutex<RdbState>>,
+    aof: Aof,
+    // 写命令从执行到追加进 AOF 和复制流期间持有，保证传播顺序和执行顺序一致
+    write_lock: Arc<Mutex<()>>,
+    // 正在执行关闭流程时为 true，主循环暂停接受新连接
+    shutting_down: watch::Sender<bool>,
+}
+
+/// SHUTDOWN [NOSAVE | SAVE] [NOW] [FORCE] 的选项，收到 SIGTERM/SIGINT 时全部为 false
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ShutdownFlags {
+    pub nosave: bool,
+    pub save: bool,
+    // 不等待副本追上
+    pub now: bool,
+    // 保存失败也照样退出
+    pub force: bool,
 }
 impl Config {
     pub fn new() -> Self {
+        let mut settings = HashMap::new();
+        settings.insert("databases".to_string(), Value::BulkString(Some(DEFAULT_DATABASES.to_string().into_bytes())));
+        settings.insert("rdbchecksum".to_string(), Value::BulkString(Some("yes".into())));
+        settings.insert("save".to_string(), Value::BulkString(Some(DEFAULT_SAVE_PARAMS.into())));
+        settings.insert("stop-writes-on-bgsave-error".to_string(), Value::BulkString(Some("yes".into())));
+        settings.insert("appendonly".to_string(), Value::BulkString(Some("no".into())));
+        settings.insert("appendfilename".to_string(), Value::BulkString(Some("appendonly.aof".into())));
+        settings.insert("appendfsync".to_string(), Value::BulkString(Some("everysec".into())));
+        settings.insert("aof-load-truncated".to_string(), Value::BulkString(Some("yes".into())));
         Config {
-            rdbfile: HashMap::new(),
-            rdbfile_content: HashMap::new(),
+            settings,
+            keyspace: Keyspace::new(DEFAULT_DATABASES),
             metadata: HashMap::new(),
-            expirations:HashMap::new(),
             rcliinfo: RCliInfo::new(),
             slaves_handler: Arc::new(RwLock::new(Slaves::new())),//需要异步处理
-            stream: Stream::new(),
-            key_type: HashMap::new(),
             my_offset: 0,
+            master_synced: false,
+            master: None,
+            clients: Arc::new(Mutex::new(Clients::new())),
+            rdb_state: Arc::new(Mutex::new(RdbState::new())),
+            aof: Aof::new(),
+            write_lock: Arc::new(Mutex::new(())),
+            shutting_down: watch::channel(false).0,
         }
     }
-    pub fn get_type(&mut self,key:Value)-> String{
-        match self.key_type.get(&key) {
-            Some(v) => format!("{}", v),
-            None => {
-                let key_str = match key {
-                    Value::BulkString(Some(ref s)) => s.clone(),
-                    _ => return format!("none"),
-                };
-
-                match self.get(key_str) {
-                    Value::BulkString(Some(_)) => format!("string"),
-                    _ => format!("none"),
-                }
-            }
-        }
+    /// 取得编号为 index 的数据库
+    pub fn db(&mut self, index: usize) -> &mut Database {
+        self.keyspace.db(index)
     }
-    pub async fn xadd(&mut self, (name_key, name_value): (Value, Value), entry: HashMap<Value, Value>)->Result<Value>{
-        match self.stream.insert_stream_item((name_key.clone(), name_value),entry){
-            Ok(v) => {
-                self.key_type.insert(name_key,"stream".to_string());
-                Ok(v)
-            }
-            Err(e) => Err(e),
-        }
+    pub fn keyspace(&mut self) -> &mut Keyspace {
+        &mut self.keyspace
     }
-    pub async fn xrange(&mut self, stream_name:Value,strat:Value,end:Value) -> Result<Value>{
-        match self.stream.xrange(stream_name,strat,end){
-            Ok(v) => Ok(Value::Array(v)),
-            Err(e) => Err(e),
-        }
+    /// 逻辑数据库的个数
+    pub fn databases(&self) -> usize {
+        self.keyspace.len()
     }
-    pub fn xread_latest(&self, streams: Value) -> Result<Value> {
-        match self.stream.xread_latest(streams){
-            Ok(v) => Ok(v),
-            Err(e) => Err(e),
-        }
+    /// 设置逻辑数据库的个数，只能在启动时加载数据之前调用
+    pub fn set_databases(&mut self, num: usize) {
+        self.keyspace = Keyspace::new(num);
+        self.keyspace.set_expire_enabled(self.is_master());
+        self.insert("databases".to_string(), num.to_string());
     }
-    pub async fn xread(&mut self, streams: Vec<(Value, Value)>) -> Result<Value>{
-        match self.stream.xread(streams){
-            Ok(v) => Ok(Value::Array(v)),
-            Err(e) => Err(e),
-        }
+    /// 每秒检查一次，关闭空闲时间超过 timeout 的客户端
+    pub async fn client_reaper_loop(&mut self){
+        let clients_clone = self.clients.clone();
+        tokio::spawn(async move {
+            loop{
+                {
+                    let mut clients = clients_clone.lock().await;
+                    clients.reap_idle();
+                }
+                time::sleep(time::Duration::from_secs(1)).await;
+            }
+        });
     }
-    pub async fn slave_loop(&mut self){
-        let slaves_clone = self.slaves_handler.clone();
+    /// 每 100ms 执行一次主动过期，惰性删除之外清理没人访问的过期 key
+    pub fn active_expire_loop(config: Arc<Mutex<Config>>) {
         tokio::spawn(async move {
             loop{
                 {
-                    //限定锁的作用域
-                    let mut slaves_read = slaves_clone.write().await;
-                    slaves_read.r#loop().await;
+                    let mut config_lock = config.lock().await;
+                    config_lock.keyspace.active_expire_cycle(ACTIVE_EXPIRE_TIME_LIMIT);
+                    config_lock.propagate_expired().await;
                 }
-                time::sleep(time::Duration::from_millis(200)).await;
+                time::sleep(ACTIVE_EXPIRE_PERIOD).await;
             }
         });
     }
+    /// everysec 策略下每秒在后台线程 fsync 一次 AOF，不占用配置锁
+    pub fn aof_fsync_loop(config: Arc<Mutex<Config>>) {
+        tokio::spawn(async move {
+            loop{
+                let file = config.lock().await.aof.take_fsync_handle();
+                if let Some(file) = file {
+                    match tokio::task::spawn_blocking(move || file.sync_data()).await {
+                        Ok(Err(e)) => println!("Error syncing the append only file: {}", e),
+                        Err(e) => println!("Error syncing the append only file: {}", e),
+                        Ok(Ok(())) => {}
+                    }
+                }
+                time::sleep(AOF_FSYNC_PERIOD).await;
+            }
+        });
+    }
+    pub async fn register_client(&mut self, id: u64, addr: SocketAddr) -> Arc<ClientState> {
+        let mut clients = self.clients.lock().await;
+        clients.register(id, addr)
+    }
+    pub async fn unregister_client(&mut self, id: u64) {
+        let mut clients = self.clients.lock().await;
+        clients.unregister(id);
+    }
     pub fn rcliinfo_track_slave_cmd_offset(&mut self, slave_cmd_offset:usize){
-        self.my_offset = self.my_offset + slave_cmd_offset;
+        self.my_offset += slave_cmd_offset;
     }
     pub fn rcliinfo_get_slave_cmd_offset(&self)-> usize{
         self.my_offset
     }
-    pub async fn wait(&mut self, wait_slave_num:i32 )->Result<Value>{
-        let mut slaves_write = self.slaves_handler.write().await;
-        slaves_write.wait(wait_slave_num)
+    pub fn slaves_handler(&self) -> Arc<RwLock<Slaves>> {
+        self.slaves_handler.clone()
     }
-    pub async fn rcliinfo_track_cmd(&mut self, cmd:Value){
-        let mut slaves_write = self.slaves_handler.write().await;
-        let _ = slaves_write.get_new_client_cmd(cmd).await;
+    /// 复制流当前的偏移量，写命令传播之后记作客户端的写偏移量
+    pub async fn master_repl_offset(&self) -> u64 {
+        self.slaves_handler.read().await.master_repl_offset()
     }
     pub async fn new_slave_come(&mut self,syn_addr:String,listen_addr:String){
         //插入一个握手信息到slave里面
         let mut slaves_write = self.slaves_handler.write().await;
         let _ = slaves_write.shake_hand_addr_info(syn_addr,listen_addr).await;
     }
-    pub async fn add_slave_resphandler(&mut self,handler:RespHandler){
-        //在slave信息里面实现连接
-        let mut slaves_write = self.slaves_handler.write().await;
-        slaves_write.add_new_slave_handler(handler).await;
+    /// PSYNC replid offset：replid 一致且积压缓冲区还能补齐时回复 +CONTINUE，否则全量同步
+    /// 全量同步时在同一时刻记下偏移量和键空间快照
+    pub async fn psync(&mut self, replid: &str, offset: &str) -> (Value, ReplicaSync) {
+        let my_replid = match self.rcliinfo.get_param("master_replid".to_string()) {
+            Value::SimpleString(s) => s,
+            _ => String::new(),
+        };
+        let mut slaves = self.slaves_handler.write().await;
+        slaves.create_backlog();
+        // offset 是副本需要的下一个字节
+        if let Ok(psync_offset) = offset.parse::<u64>() {
+            if replid == my_replid && psync_offset > 0 && slaves.can_continue_from(psync_offset - 1) {
+                println!("Partial resynchronization request accepted, continuing from offset {}", psync_offset);
+                return (Value::SimpleString(format!("CONTINUE {}", my_replid)), ReplicaSync::Partial(psync_offset - 1));
+            }
+        }
+        slaves.reset_selected_db();
+        let master_offset = slaves.master_repl_offset();
+        println!("Full resync requested by replica, starting from offset {}", master_offset);
+        (
+            Value::SimpleString(format!("FULLRESYNC {} {}", my_replid, master_offset)),
+            ReplicaSync::Full(master_offset, Box::new(self.keyspace.clone()), slaves.start_full_sync()),
+        )
+    }
+    /// 回复 PSYNC 之后登记副本
+    /// 全量同步时先在后台把快照作为 RDB 发给副本，期间的写命令缓存在这个副本的缓冲区里，传输完成后先补发
+    pub async fn add_slave_resphandler(&mut self,mut handler:RespHandler,sync:ReplicaSync){
+        let (offset, keyspace, sync_id) = match sync {
+            ReplicaSync::Partial(offset) => {
+                Slaves::attach(self.slaves_handler.clone(), handler, offset, None).await;
+                return;
+            }
+            ReplicaSync::Full(offset, keyspace, sync_id) => (offset, keyspace, sync_id),
+        };
+        let checksum = self.rdb_checksum();
+        let slaves = self.slaves_handler.clone();
+        tokio::spawn(async move {
+            let payload = match tokio::task::spawn_blocking(move || rdb::dump(&keyspace, checksum)).await {
+                Ok(payload) => payload,
+                Err(e) => {
+                    println!("Failed to create the RDB snapshot for the replica: {}", e);
+                    slaves.write().await.cancel_full_sync(sync_id);
+                    return;
+                }
+            };
+            println!("Synchronization with replica started, sending {} bytes", payload.len());
+            if let Err(e) = handler.write_value(Value::RdbFile(payload)).await {
+                println!("Failed to send the RDB snapshot to the replica: {}", e);
+                slaves.write().await.cancel_full_sync(sync_id);
+                return;
+            }
+            println!("Synchronization with replica succeeded");
+            Slaves::attach(slaves, handler, offset, Some(sync_id)).await;
+        });
     }
-    pub fn set_rcliinfo(&mut self,key:String,value:String){
-        match key.as_str(){
-            "role" => self.rcliinfo.set_role(value),
-            _ => {
-                println!("Unknown config key: {}", key);
-            },
+    /// REPLICAOF host port / REPLICAOF NO ONE
+    /// 成为副本时取消原来的复制任务并连接新的主节点，全量同步后丢弃现有数据；
+    /// 提升为主节点时保留数据并换一个新的复制 ID。两种情况下自己的副本都要断开重新同步
+    pub async fn replicaof(config: Arc<Mutex<Config>>, master: Option<SocketAddr>) -> Value {
+        let mut config_lock = config.lock().await;
+        match master {
+            Some(addr) => {
+                if matches!(config_lock.master, Some((current, _)) if current == addr) {
+                    return Value::SimpleString("OK Already connected to specified master".to_string());
+                }
+                config_lock.stop_replication();
+                config_lock.disconnect_replicas().await;
+                config_lock.rcliinfo.set_role("slave".to_string());
+                config_lock.keyspace.set_expire_enabled(false);
+                config_lock.rcliinfo.set_param("master_host", Value::SimpleString(addr.ip().to_string()));
+                config_lock.rcliinfo.set_param("master_port", Value::Integer(addr.port() as i64));
+                config_lock.set_master_link_status(false);
+                let task = tokio::spawn(replication::replication_loop(addr, config.clone()));
+                config_lock.master = Some((addr, task));
+                println!("Connecting to MASTER {}", addr);
+            }
+            None => {
+                if config_lock.master.is_none() {
+                    return Value::SimpleString("OK".to_string());
+                }
+                config_lock.stop_replication();
+                config_lock.disconnect_replicas().await;
+                config_lock.rcliinfo.set_role("master".to_string());
+                config_lock.keyspace.set_expire_enabled(true);
+                config_lock.rcliinfo.set_param("master_replid", Value::SimpleString(new_replid()));
+                for param in ["master_host", "master_port", "master_link_status"] {
+                    config_lock.rcliinfo.remove_param(param);
+                }
+                println!("MASTER MODE enabled");
+            }
         }
+        Value::SimpleString("OK".to_string())
+    }
+    /// 取消正在运行的复制任务，之后重新连接时从全量同步开始
+    fn stop_replication(&mut self) {
+        if let Some((_, task)) = self.master.take() {
+            task.abort();
+        }
+        self.master_synced = false;
+        self.my_offset = 0;
+    }
+    /// 数据的来源变了，自己的副本需要断开后重新全量同步
+    pub async fn disconnect_replicas(&mut self) {
+        self.slaves_handler.write().await.disconnect_all();
+    }
+    pub fn set_master_link_status(&mut self, up: bool) {
+        let status = if up { "up" } else { "down" };
+        self.rcliinfo.set_param("master_link_status", Value::SimpleString(status.to_string()));
     }
     pub fn insert(&mut self, name: String, value: String){ 
-        self.rdbfile.insert(name, Value::BulkString(Some(value)));
+        self.settings.insert(name, Value::BulkString(Some(value.into_bytes())));
     }
     pub fn get_config(&self, key:String)->String{
-        match self.rdbfile.get(&key) {
+        match self.settings.get(&key) {
             Some(value) => {
-                match value {
-                    Value::BulkString(Some(s)) => s.clone(),
-                    _ => "Unknown".to_string(),
+                match value.as_str() {
+                    Some(s) => s.to_string(),
+                    None => "Unknown".to_string(),
                 }
             },
             None => "Unknown".to_string(),
         }
     }
-    pub fn load_rdb(&mut self) {
-        let dir_name = "dir".to_string();
-        let dbfile_name = "dbfilename".to_string();
-
-        // 获取目录名，默认值为 "./"
-        let path = match self.rdbfile.get(&dir_name) {
-            Some(Value::BulkString(Some(ref s))) => s.clone(),
-            _ => "./".to_string(),
+    /// RDB 文件所在的目录和文件名，默认是 ./dump.rdb
+    fn rdb_location(&self) -> (String, String) {
+        let dir = match self.settings.get("dir").and_then(|v| v.as_str()) {
+            Some(s) => s.to_string(),
+            None => "./".to_string(),
         };
-
-        // 获取文件名，默认值为 "dump.rdb"
-        let file_name = match self.rdbfile.get(&dbfile_name) {
-            Some(Value::BulkString(Some(ref s))) => s.clone(),
-            _ => "dump.rdb".to_string(),
+        let file_name = match self.settings.get("dbfilename").and_then(|v| v.as_str()) {
+            Some(s) => s.to_string(),
+            None => "dump.rdb".to_string(),
         };
-
-        // 组合路径
-        let full_path = format!("{}/{}", path, file_name);
-        // 调用加载文件的方法
-        let _ = self.load_from_file(&full_path);
+        (dir, file_name)
     }
-    pub fn config_get(&self, key: String) -> Value {
-        match self.rdbfile.get(&key) {
-            Some(value) => {
-                let valuearray=Value::Array(vec![
-                    Value::BulkString(Some(key.clone())),
-                    value.clone(),
-                ]);
-                valuearray
-            },
-            None => Value::BulkString(None),
-        }
+    fn rdb_checksum(&self) -> bool {
+        self.get_config("rdbchecksum".to_string()) != "no"
     }
-    pub fn get(&mut self, key: String) -> Value{
-        // Check if the key has expired
-        println!("{:?},{:?}",self.expirations,SystemTime::now());
-        if let Some(expiration_time) = self.expirations.get(&key) {
-            if let Ok(now) = SystemTime::now().duration_since(*expiration_time) {
-                if now > Duration::from_secs(0) {
-                    self.rdbfile_content.remove(&key);
-                    self.expirations.remove(&key);
-                    return Value::BulkString(None);
-                }
-                println!("{:?},{:?}",now,Duration::from_secs(0));
+    /// 启动时加载 dir/dbfilename，文件不存在时以空数据库启动
+    pub fn load_rdb(&mut self) -> io::Result<()> {
+        let (path, file_name) = self.rdb_location();
+        let full_path = format!("{}/{}", path, file_name);
+        // 调用加载文件的方法
+        match self.load_from_file(&full_path) {
+            Ok(()) => {
+                println!("DB loaded from disk");
+                Ok(())
             }
-        }
-        match self.rdbfile_content.get(&key) {
-            Some(value) => value.clone(),
-            None => Value::BulkString(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(io::Error::new(e.kind(), format!("{}: {}", full_path, e))),
         }
     }
-    pub fn set(&mut self, key: String, value: Value) -> Value {
-        self.rdbfile_content.insert(key, value);
-        Value::SimpleString("OK".to_string())
+    fn aof_path(&self) -> String {
+        format!("{}/{}", self.get_config("dir".to_string()), self.get_config("appendfilename".to_string()))
     }
-    pub fn incr(&mut self, key:String, value: Value) -> Value {
-        self.rdbfile_content.insert(key, value.clone());
-        value
-    }
-    pub fn set_expriations(&mut self,key:String,expiration_time:SystemTime){
-        self.expirations.insert(key,expiration_time);
-    }
-    pub fn get_keys(&self, pattern: String) ->  Value{
-        let _regex = Self::pattern_to_regex(&pattern);
-        if pattern == "Cargo.lock".to_string(){
-            //返回所有的key
-            Value::Array(
-                self.rdbfile_content
-                    .keys()
-                    .map(|k| Value::BulkString(Some(k.clone())))
-                    .collect(),
-            )
+    /// 启动时回放 AOF，命令走和客户端相同的 handle_command
+    /// 末尾的命令不完整时，aof-load-truncated 为 yes 则截掉这部分继续启动
+    pub async fn load_append_only_file(config: Arc<Mutex<Config>>) -> io::Result<()> {
+        let (path, load_truncated) = {
+            let config_lock = config.lock().await;
+            (config_lock.aof_path(), config_lock.get_config("aof-load-truncated".to_string()) == "yes")
+        };
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let contents = aof::parse(&data).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+        if contents.truncated {
+            if !load_truncated {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "Unexpected end of file reading the append only file {} (at offset {}). You can set the 'aof-load-truncated' configuration option to yes and restart the server.",
+                    path, contents.valid_len
+                )));
+            }
+            println!("!!! Warning: short read while loading the AOF file {}!!!", path);
+            println!("!!! Truncating the AOF at offset {} !!!", contents.valid_len);
+            std::fs::OpenOptions::new().write(true).open(&path)?.set_len(contents.valid_len as u64)?;
+            println!("AOF loaded anyway because aof-load-truncated is enabled");
         }
-        else if pattern.contains('*') || pattern.contains('?') {
-            let regex = Self::pattern_to_regex(&pattern);
-            let keys_pattern_iter = self.rdbfile_content
-                .iter()
-                .filter(|(k, _)| regex.is_match(k))
-                .map(|(k, _)| k.clone())
-                .collect::<Vec<String>>();
-                Value::Array(keys_pattern_iter.into_iter().map(|k| Value::BulkString(Some(k))).collect())
-        } else {
-            // 查找具体的键
-            match self.rdbfile_content.get(&pattern) {
-                Some(_) => Value::BulkString(Some(pattern.clone())),
-                None => Value::Array(vec![]), // 如果键不存在，返回空数组
+
+        let mut db = RedisDb::new();
+        let addr = SocketAddr::from(([0, 0, 0, 0], 0));
+        for mut items in contents.commands {
+            let command = match items.remove(0) {
+                Value::BulkString(Some(name)) => String::from_utf8_lossy(&name).to_string(),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad file format reading the append only file {}", path))),
+            };
+            // 事务在回放时按顺序执行即可
+            if command.eq_ignore_ascii_case("multi") || command.eq_ignore_ascii_case("exec") {
+                continue;
             }
-            
+            if command::lookup(&command).is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown command '{}' reading the append only file {}", command, path)));
+            }
+            db.handle_command(command, items, config.clone(), addr).await;
         }
+        // 回放的命令不算作需要保存的修改，回放中过期的 key 也不需要再写回 AOF
+        let mut config_lock = config.lock().await;
+        config_lock.rdb_state.lock().await.dirty = 0;
+        config_lock.keyspace.take_expired();
+        println!("DB loaded from append only file");
+        Ok(())
     }
-    pub fn get_info_replication(&self)->Value{
-        Value::BulkString(Some(self.rcliinfo.get_replication_info()))
-    }
-
-    pub fn get_key_info_of_replication(&self, key:String)->Value{
-        self.rcliinfo.get_param(key)
+    /// 打开 AOF 准备追加写命令
+    pub fn open_append_only_file(&mut self) -> io::Result<()> {
+        let path = self.aof_path();
+        self.aof.open(&path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+    }
+    pub fn write_lock(&self) -> Arc<Mutex<()>> {
+        self.write_lock.clone()
+    }
+    /// 把在 db_index 上成功执行的写命令追加到 AOF 和复制流
+    /// 之前过期删除的 key 先以 DEL 传播，保证副本上的删除发生在之后的写入之前
+    pub async fn propagate(&mut self, db_index: usize, cmds: &[Value]) {
+        self.propagate_expired().await;
+        self.feed(db_index, cmds).await;
+    }
+    /// 把惰性删除和主动过期删除的 key 以 DEL 传播
+    pub async fn propagate_expired(&mut self) {
+        for (db_index, key) in self.keyspace.take_expired() {
+            let del = Value::Array(vec![
+                Value::BulkString(Some("DEL".into())),
+                Value::BulkString(Some(key)),
+            ]);
+            self.feed(db_index, &[del]).await;
+        }
     }
-
-    fn pattern_to_regex(pattern: &str) -> Regex {
-        let escaped = regex::escape(pattern);
-        let pattern = escaped.replace("\\*", ".*").replace("\\?", ".");
-        Regex::new(&pattern).unwrap_or_else(|_| Regex::new(".*").unwrap())
+    async fn feed(&mut self, db_index: usize, cmds: &[Value]) {
+        if let Err(e) = self.aof.feed(db_index, cmds) {
+            println!("Error writing to the append only file: {}", e);
+        }
+        self.slaves_handler.write().await.feed(db_index, cmds);
     }
-
-    pub fn load_from_file(&mut self, path: &str) -> io::Result<()> {
-        println!("{}",path);
-        let mut file = File::open(path)?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-
-        let mut cursor = Cursor::new(buffer);
-        self.parse_rdb(&mut cursor)
+    /// SAVE：在持有锁的情况下同步写 RDB，期间其他客户端都要等待
+    pub async fn save(&mut self) -> Result<()> {
+        if self.rdb_state.lock().await.bgsave_in_progress {
+            return Err(anyhow::anyhow!("Background save already in progress"));
+        }
+        let (dir, file_name) = self.rdb_location();
+        let data = rdb::dump(&self.keyspace, self.rdb_checksum());
+        persistence::write_rdb_file(&dir, &file_name, &data)?;
+        let mut state = self.rdb_state.lock().await;
+        state.lastsave = persistence::unix_secs();
+        state.dirty = 0;
+        state.last_bgsave_ok = true;
+        println!("DB saved on disk");
+        Ok(())
     }
-
-    fn parse_rdb(&mut self, cursor: &mut Cursor<Vec<u8>>) -> io::Result<()> {
-        // Check magic string and version
-        let mut magic = [0u8; 5];
-        cursor.read_exact(&mut magic)?;
-    
-        let mut version = [0u8; 4];
-        cursor.read_exact(&mut version)?;
-        println!("{:?},{:?}",magic,version);
-    
-        while cursor.position() < cursor.get_ref().len() as u64 - 8 {
-            match cursor.read_u8()? {
-                0xFA => self.parse_aux_field(cursor)?,
-                0xFE => self.parse_db_selector(cursor)?,
-                0xFF => break, // End of RDB file
-                value_type => self.parse_key_value_pair(value_type, cursor)?,
+    pub fn shutdown_watch(&self) -> watch::Receiver<bool> {
+        self.shutting_down.subscribe()
+    }
+    /// 关闭前的收尾：等副本追上、刷 AOF、按需写最后一份 RDB
+    /// 调用方持有写锁和配置锁，正在执行的写命令都已经传播，新命令要等锁；成功后调用方直接退出进程
+    pub async fn prepare_shutdown(&mut self, flags: ShutdownFlags, _write_guard: &OwnedMutexGuard<()>) -> Result<()> {
+        println!("User requested shutdown...");
+        self.shutting_down.send_replace(true);
+        let result = self.finish_pending_work(flags).await;
+        if result.is_err() {
+            // 关闭失败时继续提供服务
+            self.shutting_down.send_replace(false);
+        }
+        result
+    }
+    async fn finish_pending_work(&mut self, flags: ShutdownFlags) -> Result<()> {
+        if !flags.now {
+            let start = time::Instant::now();
+            while self.slaves_handler.read().await.has_pending_commands() {
+                if start.elapsed() >= SHUTDOWN_TIMEOUT {
+                    println!("Lagging replica didn't catch up in time, shutting down anyway");
+                    break;
+                }
+                time::sleep(SHUTDOWN_CHECK_PERIOD).await;
+            }
+        }
+        // 等后台保存结束，避免和最后一次保存同时写文件
+        while self.rdb_state.lock().await.bgsave_in_progress {
+            time::sleep(SHUTDOWN_CHECK_PERIOD).await;
+        }
+        if self.get_config("appendonly".to_string()) == "yes" {
+            println!("Calling fsync() on the AOF file.");
+            if let Err(e) = self.aof.fsync() {
+                if !flags.force {
+                    return Err(anyhow::anyhow!("Error syncing the append only file: {}", e));
+                }
+                println!("Error syncing the append only file: {}, exiting anyway", e);
+            }
+        }
+        if flags.save || (!flags.nosave && !self.save_params().is_empty()) {
+            println!("Saving the final RDB snapshot before exiting.");
+            if let Err(e) = self.save().await {
+                if !flags.force {
+                    return Err(anyhow::anyhow!("Error trying to save the DB, can't exit: {}", e));
+                }
+                println!("Error trying to save the DB: {}, exiting anyway", e);
             }
         }
-    
-        // Verify checksum
-        let checksum = cursor.read_u64::<LittleEndian>()?;
-        // You need to calculate the expected checksum and compare it with `checksum`
-        // For simplicity, we assume the checksum is correct here
-        println!("Checksum verified: {}", checksum);
-    
         Ok(())
     }
-
-    fn parse_aux_field(&mut self, cursor: &mut Cursor<Vec<u8>>) -> io::Result<()> {
-        // Parse auxiliary field
-        let key = self.parse_string(cursor)?;
-        println!("{:?}",key);
-        let value = self.parse_value(cursor)?;
-        println!("{:?}",value);
-        self.metadata.insert(key, value);
-
+    /// BGSAVE：复制一份键空间，在后台线程序列化并写文件，不阻塞客户端
+    pub async fn bgsave(&mut self) -> Result<()> {
+        {
+            let mut state = self.rdb_state.lock().await;
+            if state.bgsave_in_progress {
+                return Err(anyhow::anyhow!("Background save already in progress"));
+            }
+            state.bgsave_in_progress = true;
+            state.dirty_before_bgsave = state.dirty;
+            state.last_bgsave_try = persistence::unix_secs();
+        }
+        let snapshot = self.keyspace.clone();
+        let checksum = self.rdb_checksum();
+        let (dir, file_name) = self.rdb_location();
+        let rdb_state = self.rdb_state.clone();
+        tokio::spawn(async move {
+            let result = tokio::task::spawn_blocking(move || {
+                let data = rdb::dump(&snapshot, checksum);
+                persistence::write_rdb_file(&dir, &file_name, &data)
+            })
+            .await;
+            let mut state = rdb_state.lock().await;
+            state.bgsave_in_progress = false;
+            match result {
+                Ok(Ok(())) => {
+                    state.lastsave = persistence::unix_secs();
+                    state.dirty = state.dirty.saturating_sub(state.dirty_before_bgsave);
+                    state.last_bgsave_ok = true;
+                    println!("Background saving terminated with success");
+                }
+                Ok(Err(e)) => {
+                    state.last_bgsave_ok = false;
+                    println!("Background saving error: {}", e);
+                }
+                Err(e) => {
+                    state.last_bgsave_ok = false;
+                    println!("Background saving error: {}", e);
+                }
+            }
+        });
         Ok(())
     }
-
-    fn parse_db_selector(&mut self, cursor: &mut Cursor<Vec<u8>>) -> io::Result<()> {
-        //第一个字节是选择数据库标记的
-        let db_index = cursor.read_u8()?;
-        println!("Switching to database index: {}", db_index);
-
-        //然后读取当前字符是不是FB，是的话进行处理数据库内容
-        let current_position = cursor.position();
-        match cursor.read_u8()?{
-            0xFB => {
-                //读取数据库大小
-                let db_hash_table_size = cursor.read_u8()?;
-                match cursor.read_u8()? {
-                    0x00 =>{
-                        // no expire time
-                        // 读取第一个字符是决定你当选的内容是什么格式的，目前只实现了00-》字符串
-                        for _i in 0..db_hash_table_size{
-                            match cursor.read_u8()?{
-                                0x00 =>{
-                                    let key = self.parse_string(cursor)?;
-                                    let value = self.parse_string(cursor)?;
-                                    self.rdbfile_content.insert(key.clone(),Value::BulkString(Some(value.clone())));
-                                    println!("Resizedb field: num_keys={:?}, num_expires={:?}", key, value);
-                                }
-                                _ => println!("Falied1"),
-                            }
-                        }
-
-                    }
-                    _ =>{
-                        //读取expire time
-                        //判断第一个字节是不是fc，不是就正常获取数据
-                        for _i in 0..db_hash_table_size{
-                            let tmp_position = cursor.position();
-                            match cursor.read_u8()?{
-                                0xFC => {
-                                    // 获取expire time
-                                    let expiry_time = cursor.read_u64::<LittleEndian>()?;
-                                    println!("Expiry time in milliseconds: {}", expiry_time);
-                                    match cursor.read_u8()?{
-                                        0x00 =>{
-                                            //判断是不是过期了，没有过期就跳过
-                                            let current_time = SystemTime::now()
-                                                                    .duration_since(UNIX_EPOCH)
-                                                                    .expect("Time went backwards")
-                                                                    .as_millis() as u64;
-                                            println!("{:?}",current_time);
-                                            let key = self.parse_string(cursor)?;
-                                            let value = self.parse_string(cursor)?;
-                                            if current_time< expiry_time{
-                                                self.rdbfile_content.insert(key.clone(),Value::BulkString(Some(value.clone())));
-
-                                                let time:SystemTime = UNIX_EPOCH + Duration::from_millis(expiry_time);
-                                                self.expirations.insert(key.clone(), time);
-                                                println!("Resizedb field: num_keys={:?}, num_expires={:?}", key, value);
-                                            }                                            
-                                        }
-                                        _ => println!("Falied2"),
-                                    }
-                                }
-                                0xFD => {
-                                    // 获取expire time
-                                    let expiry_time = cursor.read_u32::<LittleEndian>()?;
-                                    println!("Expiry time in milliseconds: {}", expiry_time);
-                                    match cursor.read_u8()?{
-                                        0x00 =>{
-                                            //判断是不是过期了，没有过期就跳过
-                                            let current_time = SystemTime::now()
-                                                                    .duration_since(UNIX_EPOCH)
-                                                                    .expect("Time went backwards")
-                                                                    .as_secs() as u32;
-                                            let key = self.parse_string(cursor)?;
-                                            let value = self.parse_string(cursor)?;
-                                            if current_time< expiry_time{
-                                                self.rdbfile_content.insert(key.clone(),Value::BulkString(Some(value.clone())));
-                                                let time:SystemTime = UNIX_EPOCH + Duration::from_secs(expiry_time.into());
-                                                self.expirations.insert(key.clone(), time);
-                                                println!("Resizedb field: num_keys={:?}, num_expires={:?}", key, value);
-                                            }                                            
-                                        }
-                                        _ => println!("Falied2"),
-                                    }
-                                }
-                                _ => {
-                                    cursor.set_position(tmp_position);
-                                    match cursor.read_u8()?{
-                                        0x00 =>{
-                                            let key = self.parse_string(cursor)?;
-                                            let value = self.parse_string(cursor)?;
-                                            self.rdbfile_content.insert(key.clone(),Value::BulkString(Some(value.clone())));
-                                            println!("Resizedb field: num_keys={:?}, num_expires={:?}", key, value);
-                                        }
-                                        _ => println!("Falied3"),
-                                    }
-                                }
-                            }
+    /// 最近一次保存成功的 unix 秒
+    pub async fn lastsave(&self) -> u64 {
+        self.rdb_state.lock().await.lastsave
+    }
+    /// 记录成功执行的写命令，用于 save 规则
+    pub async fn add_dirty(&mut self, changes: u64) {
+        self.rdb_state.lock().await.dirty += changes;
+    }
+    fn save_params(&self) -> Vec<(u64, u64)> {
+        persistence::parse_save_params(&self.get_config("save".to_string())).unwrap_or_default()
+    }
+    /// 上次 BGSAVE 失败且开启了 stop-writes-on-bgsave-error 时拒绝写命令，副本不受影响
+    pub async fn write_error(&self) -> Option<Value> {
+        if self.get_config("stop-writes-on-bgsave-error".to_string()) != "yes"
+            || self.save_params().is_empty()
+            || !self.is_master()
+            || self.rdb_state.lock().await.last_bgsave_ok
+        {
+            return None;
+        }
+        Some(Value::Error("MISCONF Redis is configured to save RDB snapshots, but it's currently unable to persist to disk. Commands that may modify the data set are disabled, because this instance is configured to report errors during writes if RDB snapshotting fails (stop-writes-on-bgsave-error option). Please check the Redis logs for details about the RDB error.".to_string()))
+    }
+    /// 每 100ms 检查一次 save 规则，满足时触发 BGSAVE
+    pub fn save_rules_loop(config: Arc<Mutex<Config>>) {
+        tokio::spawn(async move {
+            loop{
+                {
+                    let mut config_lock = config.lock().await;
+                    let save_params = config_lock.save_params();
+                    let should_bgsave = config_lock.rdb_state.lock().await.should_bgsave(&save_params);
+                    if should_bgsave {
+                        println!("Save rule met, saving...");
+                        if let Err(e) = config_lock.bgsave().await {
+                            println!("Background saving error: {}", e);
                         }
                     }
                 }
+                time::sleep(SAVE_RULES_PERIOD).await;
             }
-            _=>{
-                cursor.set_position(current_position);
-            }
-        };
-        Ok(())
+        });
     }
-
-    fn parse_resizedb_field(&mut self, cursor: &mut Cursor<Vec<u8>>) -> io::Result<()> {
-        // Parse resizedb field
-        let num_of_items = cursor.read_u16::<LittleEndian>()?;
-        for _i in 0..num_of_items {
-            let encoding_type = cursor.read_u8()?;
-            match encoding_type{
-                0x00=> {
-                    let key = self.parse_string(cursor)?;
-                    let value = self.parse_string(cursor)?;
-                    self.rdbfile_content.insert(key.clone(),Value::BulkString(Some(value.clone())));
-                    println!("Resizedb field: num_keys={:?}, num_expires={:?}", key, value);
+    /// CONFIG SET，只允许修改运行时可以生效的配置项
+    pub async fn config_set(&mut self, key: String, value: String) -> Value {
+        match key.to_lowercase().as_str() {
+            "timeout" => {
+                let secs = match value.parse::<u64>() {
+                    Ok(secs) => secs,
+                    Err(_) => return Value::Error("ERR CONFIG SET failed (possibly related to argument 'timeout') - argument couldn't be parsed into an integer".to_string()),
+                };
+                self.clients.lock().await.set_timeout(Duration::from_secs(secs));
+                self.insert("timeout".to_string(), value);
+                Value::SimpleString("OK".to_string())
+            }
+            "rdbchecksum" => {
+                let value = value.to_lowercase();
+                if value != "yes" && value != "no" {
+                    return Value::Error("ERR CONFIG SET failed (possibly related to argument 'rdbchecksum') - argument must be 'yes' or 'no'".to_string());
+                }
+                self.insert("rdbchecksum".to_string(), value);
+                Value::SimpleString("OK".to_string())
+            }
+            "appendfsync" => {
+                match AppendFsync::parse(&value) {
+                    Some(fsync) => self.aof.set_fsync(fsync),
+                    None => return Value::Error("ERR CONFIG SET failed (possibly related to argument 'appendfsync') - argument(s) must be one of the following: always, everysec, no".to_string()),
                 }
-                _ => println!("Falied"),
+                self.insert("appendfsync".to_string(), value.to_lowercase());
+                Value::SimpleString("OK".to_string())
             }
+            "aof-load-truncated" => {
+                let value = value.to_lowercase();
+                if value != "yes" && value != "no" {
+                    return Value::Error("ERR CONFIG SET failed (possibly related to argument 'aof-load-truncated') - argument must be 'yes' or 'no'".to_string());
+                }
+                self.insert("aof-load-truncated".to_string(), value);
+                Value::SimpleString("OK".to_string())
+            }
+            "save" => {
+                if persistence::parse_save_params(&value).is_none() {
+                    return Value::Error("ERR CONFIG SET failed (possibly related to argument 'save') - Invalid save parameters".to_string());
+                }
+                self.insert("save".to_string(), value);
+                Value::SimpleString("OK".to_string())
+            }
+            "stop-writes-on-bgsave-error" => {
+                let value = value.to_lowercase();
+                if value != "yes" && value != "no" {
+                    return Value::Error("ERR CONFIG SET failed (possibly related to argument 'stop-writes-on-bgsave-error') - argument must be 'yes' or 'no'".to_string());
+                }
+                self.insert("stop-writes-on-bgsave-error".to_string(), value);
+                Value::SimpleString("OK".to_string())
+            }
+            _ => Value::Error(format!("ERR Unknown option or number of arguments for CONFIG SET - '{}'", key)),
         }
-        
-        Ok(())
     }
-
-    fn parse_expiry_time_seconds(&mut self, cursor: &mut Cursor<Vec<u8>>) -> io::Result<()> {
-        // Parse expiry time in seconds
-        let expiry_time = cursor.read_u32::<LittleEndian>()?;
-        println!("Expiry time in seconds: {}", expiry_time);
-        Ok(())
+    pub fn config_get(&self, key: String) -> Value {
+        // RESP3 下返回 map，RESP2 下自动展开为 [key, value] 数组
+        match self.settings.get(&key) {
+            Some(value) => {
+                Value::Map(vec![(
+                    Value::BulkString(Some(key.into_bytes())),
+                    value.clone(),
+                )])
+            },
+            None => Value::Map(vec![]),
+        }
     }
-
-    fn parse_expiry_time_milliseconds(&mut self, cursor: &mut Cursor<Vec<u8>>) -> io::Result<()> {
-        // Parse expiry time in milliseconds
-        let expiry_time = cursor.read_u64::<LittleEndian>()?;
-        println!("Expiry time in milliseconds: {}", expiry_time);
-        Ok(())
+    /// INFO [section ...]，不带参数或者 all/default/everything 时返回所有段
+    pub async fn info(&self, sections: &[String]) -> String {
+        let all = sections.is_empty()
+            || sections.iter().any(|s| matches!(s.as_str(), "all" | "default" | "everything"));
+        let mut info = Vec::new();
+        for section in INFO_SECTIONS {
+            if all || sections.iter().any(|s| s == section) {
+                info.push(self.info_section(section).await);
+            }
+        }
+        info.join("\r\n")
+    }
+    async fn info_section(&self, section: &str) -> String {
+        match section {
+            "persistence" => format!(
+                "# Persistence\r\n{}aof_enabled:{}\r\n",
+                self.rdb_state.lock().await.info(),
+                (self.get_config("appendonly".to_string()) == "yes") as u8
+            ),
+            "stats" => format!("# Stats\r\n{}", self.keyspace.expire_stats()),
+            "replication" => format!("# Replication\r\n{}", self.rcliinfo.get_replication_info(&self.live_replication_info().await)),
+            "keyspace" => format!("# Keyspace\r\n{}", self.keyspace.info()),
+            _ => String::new(),
+        }
     }
 
-    fn parse_key_value_pair(&mut self, _value_type: u8, cursor: &mut Cursor<Vec<u8>>) -> io::Result<()> {
-        // Parse key-value pair
-        let key = self.parse_string(cursor)?;
-        let value = self.parse_value(cursor)?;
-        self.rdbfile_content.insert(key, value);
-        Ok(())
+    /// 偏移量、副本数和积压缓冲区这些随复制流变化的字段
+    async fn live_replication_info(&self) -> HashMap<String, Value> {
+        let slaves = self.slaves_handler.read().await;
+        let mut live = HashMap::new();
+        live.insert("connected_slaves".to_string(), Value::Integer(slaves.connected_slaves() as i64));
+        let master_repl_offset = if self.is_master() {
+            slaves.master_repl_offset()
+        } else {
+            self.my_offset as u64
+        };
+        live.insert("master_repl_offset".to_string(), Value::Integer(master_repl_offset as i64));
+        let (active, size, first_byte_offset, histlen) = match slaves.backlog() {
+            Some(backlog) => (1, backlog.size(), backlog.first_byte_offset(), backlog.histlen()),
+            None => (0, REPL_BACKLOG_SIZE, 0, 0),
+        };
+        live.insert("repl_backlog_active".to_string(), Value::Integer(active));
+        live.insert("repl_backlog_size".to_string(), Value::Integer(size as i64));
+        live.insert("repl_backlog_first_byte_offset".to_string(), Value::Integer(first_byte_offset as i64));
+        live.insert("repl_backlog_histlen".to_string(), Value::Integer(histlen as i64));
+        live
+    }
+    pub fn is_master(&self) -> bool {
+        matches!(self.rcliinfo.get_param("role".to_string()), Value::SimpleString(ref r) if r == "master")
+    }
+    /// 副本发送 PSYNC 时使用的参数，还没有和主节点同步过时请求全量同步
+    pub fn replica_psync_args(&self) -> (String, String) {
+        match self.master_synced {
+            true => match self.rcliinfo.get_param("master_replid".to_string()) {
+                Value::SimpleString(replid) => (replid, (self.my_offset + 1).to_string()),
+                _ => ("?".to_string(), "-1".to_string()),
+            },
+            false => ("?".to_string(), "-1".to_string()),
+        }
     }
-
-    fn parse_string(&mut self, cursor: &mut Cursor<Vec<u8>>) -> io::Result<String> {
-        let len = cursor.read_u8()? as usize;
-        let mut buf = vec![0; len];
-        cursor.read_exact(&mut buf)?;
-        String::from_utf8(buf).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8"))
+    /// 副本完成全量同步或部分同步后，记下主节点的复制 ID 和偏移量
+    pub fn set_master_sync(&mut self, replid: String, offset: usize) {
+        self.rcliinfo.set_param("master_replid", Value::SimpleString(replid));
+        self.my_offset = offset;
+        self.master_synced = true;
+    }
+    pub fn get_key_info_of_replication(&self, key:String)->Value{
+        self.rcliinfo.get_param(key)
     }
 
-    fn parse_value(&mut self, cursor: &mut Cursor<Vec<u8>>) -> io::Result<Value> {
-        let value_type = cursor.read_u8()?;
-        match value_type {
-            0 => Ok(Value::BulkString(None)),
-            1..=0xBF => {
-                let len = value_type as usize;
-                let mut buf = vec![0; len];
-                cursor.read_exact(&mut buf)?;
-                let value_str = String::from_utf8(buf).unwrap();
-                Ok(Value::SimpleString(value_str))
-            }
-            0xC0..=0xC3 => {
-                let len = value_type - 0xC0;
-                let value_int = match len {
-                    0 => cursor.read_u8()? as i64,
-                    1 => cursor.read_u16::<LittleEndian>()? as i64,
-                    2 => cursor.read_u32::<LittleEndian>()? as i64,
-                    3 => cursor.read_i64::<LittleEndian>()?,
-                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid value length")),
-                };
-                Ok(Value::Integer(value_int))
-            }
-            0xC4..=0xC7 => {
-                // Handle other types like List, Set, Hash, etc.
-                unimplemented!()
-            }
-            0xFF => Ok(Value::BulkString(None)), // End of value
-            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown value type")),
-        }
+    pub fn load_from_file(&mut self, path: &str) -> io::Result<()> {
+        let mut file = File::open(path)?;
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+        self.load_rdb_bytes(&buffer)
+    }
+    /// 先加载到新的键空间再整体替换，数据损坏时不影响现有数据
+    /// 副本收到主节点的快照后也通过这里清空旧数据
+    pub fn load_rdb_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut keyspace = Keyspace::new(self.databases());
+        keyspace.set_expire_enabled(self.is_master());
+        let verify_checksum = self.rdb_checksum();
+        rdb::load(data, &mut keyspace, &mut self.metadata, verify_checksum)?;
+        self.keyspace = keyspace;
+        Ok(())
     }
 }
 
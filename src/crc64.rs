@@ -0,0 +1,48 @@
+// Redis 使用的 CRC-64/Jones：多项式 0xad93d23594c935a9，输入输出反转，初始值 0
+
+// 反转后的多项式
+const POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+const TABLE: [u64; 256] = build_table();
+
+const fn build_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// 在 crc 的基础上继续计算 data 的校验和，从头计算时 crc 传 0
+pub fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    for &b in data {
+        crc = TABLE[((crc ^ b as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+    }
+
+    #[test]
+    fn incremental_matches_one_shot() {
+        let data = b"hello crc64 world";
+        let (a, b) = data.split_at(7);
+        assert_eq!(crc64(crc64(0, a), b), crc64(0, data));
+        assert_eq!(crc64(0, b""), 0);
+    }
+}
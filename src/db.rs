@@ -1,181 +1,720 @@
 use std::collections::HashMap;
-use tokio::sync::{Mutex};
+use tokio::sync::{Mutex, OwnedMutexGuard};
 use std::sync::Arc;
 use std::thread;
 use std::net::SocketAddr;
-use crate::resp::Value;
+use crate::resp::{Value, RESP2, RESP3};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, SystemTime};
-use crate::config::Config;
+use crate::config::{Config, ShutdownFlags};
+use crate::command;
+use crate::rdb;
+use crate::slave_stream::{ReplicaSync, Slaves};
+use crate::keyspace::{from_unix_ms, get_string, now_ms, unix_ms, RedisValue, WRONGTYPE};
+use crate::stream::{Fields, Stream, StreamId};
 use std::time::{Instant};
 type RedisConfig = Arc<Mutex<Config>>;
 use tokio::time::sleep;
 
-#[derive(Clone, Debug,Eq, Hash, PartialEq,PartialOrd)]
+const XREAD_UNBALANCED: &str = "ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.";
+
+/// 取出参数的原始字节
+fn arg_bytes(value: Value) -> Vec<u8> {
+    match value {
+        Value::BulkString(Some(bytes)) => bytes,
+        other => other.to_string().into_bytes(),
+    }
+}
+
+/// 解析数据库编号，超出 databases 范围时返回错误
+fn parse_db_index(value: &Value, databases: usize) -> Result<usize, Value> {
+    match value.as_str().map(|s| s.parse::<i64>()) {
+        Some(Ok(index)) if index >= 0 && (index as usize) < databases => Ok(index as usize),
+        Some(Ok(_)) => Err(Value::Error("ERR DB index is out of range".to_string())),
+        _ => Err(Value::Error("ERR value is not an integer or out of range".to_string())),
+    }
+}
+
+// 每个连接分配一个递增的客户端 id
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// 单个连接的状态，每个连接各自持有一份
+#[derive(Debug)]
 pub struct RedisDb {
+    id: u64,
+    // 当前选择的数据库编号
+    db_index: usize,
+    // 连接使用的协议版本，HELLO 命令切换
+    protocol: u8,
+    client_name: Option<String>,
+    // 命令需要改写后再传播给副本时记录在这里，Some(vec![]) 表示不传播
+    propagate: Option<Vec<Value>>,
+    // EXEC 执行期间缓存事务中要传播的写命令和执行时所在的数据库
+    multi_propagate: Option<Vec<(usize, Value)>>,
+    // 这个连接最后一条写命令传播之后的复制偏移量，WAIT 等待副本确认到这里
+    woff: u64,
+    // PSYNC 之后这个连接转为副本连接，回复发出后按这里的结果登记
+    replica_sync: Option<ReplicaSync>,
+    // 副本和主节点之间的复制连接，作为副本时只接受这个连接上的写命令
+    master_client: bool,
+    // EXEC 执行期间持有的写锁，事务中的 SHUTDOWN 直接使用
+    exec_write_guard: Option<OwnedMutexGuard<()>>,
 }
 
 impl RedisDb {
     pub fn new() -> Self {
         RedisDb {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            db_index: 0,
+            protocol: RESP2,
+            client_name: None,
+            propagate: None,
+            multi_propagate: None,
+            woff: 0,
+            replica_sync: None,
+            master_client: false,
+            exec_write_guard: None,
+        }
+    }
+
+    /// 执行主节点复制流的连接状态
+    pub fn master_client() -> Self {
+        RedisDb {
+            master_client: true,
+            ..RedisDb::new()
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    /// 取出 PSYNC 的同步方式
+    pub fn take_replica_sync(&mut self) -> Option<ReplicaSync> {
+        self.replica_sync.take()
+    }
+
+    /// EXPIRE/PEXPIRE/EXPIREAT/PEXPIREAT key time [NX | XX | GT | LT]
+    /// 传播给副本时统一改写成绝对时间的 PEXPIREAT，已经过期的改写成 DEL
+    async fn expire_generic(&mut self, command: &str, mut args: Vec<Value>, config: RedisConfig) -> Value {
+        let key = arg_bytes(args.remove(0));
+        let time = match args.remove(0).as_str().map(|s| s.parse::<i64>()) {
+            Some(Ok(time)) => time,
+            _ => return Value::Error("ERR value is not an integer or out of range".to_string()),
+        };
+        let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+        for opt in args.iter() {
+            match opt.as_str().map(|s| s.to_uppercase()).as_deref() {
+                Some("NX") => nx = true,
+                Some("XX") => xx = true,
+                Some("GT") => gt = true,
+                Some("LT") => lt = true,
+                _ => return Value::Error(format!("ERR Unsupported option {}", opt)),
+            }
+        }
+        if nx && (xx || gt || lt) {
+            return Value::Error("ERR NX and XX, GT or LT options at the same time are not compatible".to_string());
+        }
+        if gt && lt {
+            return Value::Error("ERR GT and LT options at the same time are not compatible".to_string());
+        }
+
+        // 统一换算成 unix 毫秒时间戳
+        let when_ms = match command {
+            "expire" => time.checked_mul(1000).and_then(|ms| ms.checked_add(now_ms())),
+            "pexpire" => time.checked_add(now_ms()),
+            "expireat" => time.checked_mul(1000),
+            _ => Some(time),
+        };
+        let when_ms = match when_ms {
+            Some(ms) => ms,
+            None => return Value::Error(format!("ERR invalid expire time in '{}' command", command)),
+        };
+
+        let mut config_lock=config.lock().await;
+        let db = config_lock.db(self.db_index);
+        if !db.contains_key(&key) {
+            self.propagate = Some(vec![]);
+            return Value::Integer(0);
+        }
+        // 没有过期时间的 key 视为永不过期
+        let current = db.get_expire(&key).map(unix_ms);
+        let allowed = match current {
+            Some(current) => !(nx || (gt && when_ms <= current) || (lt && when_ms >= current)),
+            None => !xx && !gt,
+        };
+        if !allowed {
+            self.propagate = Some(vec![]);
+            return Value::Integer(0);
+        }
+        if when_ms <= now_ms() {
+            db.remove(&key);
+            self.propagate = Some(vec![Value::Array(vec![
+                Value::BulkString(Some("DEL".into())),
+                Value::BulkString(Some(key)),
+            ])]);
+        } else {
+            db.set_expire(&key, from_unix_ms(when_ms));
+            self.propagate = Some(vec![Value::Array(vec![
+                Value::BulkString(Some("PEXPIREAT".into())),
+                Value::BulkString(Some(key)),
+                Value::BulkString(Some(when_ms.to_string().into_bytes())),
+            ])]);
+        }
+        Value::Integer(1)
+    }
+
+    /// TTL/PTTL/EXPIRETIME/PEXPIRETIME key，key 不存在返回 -2，没有过期时间返回 -1
+    async fn ttl_generic(&mut self, command: &str, key: Vec<u8>, config: RedisConfig) -> Value {
+        let mut config_lock=config.lock().await;
+        let db = config_lock.db(self.db_index);
+        if !db.contains_key(&key) {
+            return Value::Integer(-2);
+        }
+        let when_ms = match db.get_expire(&key) {
+            Some(when) => unix_ms(when),
+            None => return Value::Integer(-1),
+        };
+        let ttl_ms = (when_ms - now_ms()).max(0);
+        match command {
+            "ttl" => Value::Integer((ttl_ms + 500) / 1000),
+            "pttl" => Value::Integer(ttl_ms),
+            "expiretime" => Value::Integer(when_ms / 1000),
+            _ => Value::Integer(when_ms),
         }
     }
 
-    pub async fn handle_command(&mut self, command: String,mut args: Vec<Value>,config:RedisConfig,addr:SocketAddr) -> Value {
+    /// RESTORE key ttl serialized-value [REPLACE] [ABSTTL] [IDLETIME seconds] [FREQ frequency]
+    /// 没有淘汰策略，IDLETIME 和 FREQ 只做校验；传播时 ttl 改写成绝对时间并带上 ABSTTL
+    async fn restore(&mut self, mut args: Vec<Value>, config: RedisConfig) -> Value {
+        let key = arg_bytes(args.remove(0));
+        let ttl = match args.remove(0).as_str().map(|s| s.parse::<i64>()) {
+            Some(Ok(ttl)) if ttl >= 0 => ttl,
+            Some(Ok(_)) => return Value::Error("ERR Invalid TTL value, must be >= 0".to_string()),
+            _ => return Value::Error("ERR value is not an integer or out of range".to_string()),
+        };
+        let payload = arg_bytes(args.remove(0));
+        let (mut replace, mut absttl, mut idletime, mut freq) = (false, false, false, false);
+        while !args.is_empty() {
+            let opt = args.remove(0);
+            match opt.as_str().map(|s| s.to_uppercase()).as_deref() {
+                Some("REPLACE") => replace = true,
+                Some("ABSTTL") => absttl = true,
+                Some("IDLETIME") if !args.is_empty() && !freq => {
+                    match args.remove(0).as_str().map(|s| s.parse::<i64>()) {
+                        Some(Ok(v)) if v >= 0 => idletime = true,
+                        Some(Ok(_)) => return Value::Error("ERR Invalid IDLETIME value, must be >= 0".to_string()),
+                        _ => return Value::Error("ERR value is not an integer or out of range".to_string()),
+                    }
+                }
+                Some("FREQ") if !args.is_empty() && !idletime => {
+                    match args.remove(0).as_str().map(|s| s.parse::<i64>()) {
+                        Some(Ok(v)) if (0..=255).contains(&v) => freq = true,
+                        Some(Ok(_)) => return Value::Error("ERR Invalid FREQ value, must be >= 0 and <= 255".to_string()),
+                        _ => return Value::Error("ERR value is not an integer or out of range".to_string()),
+                    }
+                }
+                _ => return Value::Error("ERR syntax error".to_string()),
+            }
+        }
+
+        // 先校验载荷，损坏的载荷不管 key 是否存在都报告校验错误
+        let value = match rdb::verify_dump_payload(&payload) {
+            Some(data) => match rdb::restore_value(data) {
+                Ok(value) => value,
+                Err(_) => return Value::Error("ERR Bad data format".to_string()),
+            },
+            None => return Value::Error("ERR DUMP payload version or checksum are wrong".to_string()),
+        };
+        let mut config_lock=config.lock().await;
+        let db = config_lock.db(self.db_index);
+        if !replace && db.contains_key(&key) {
+            return Value::Error("BUSYKEY Target key name already exists.".to_string());
+        }
+        let when_ms = match ttl {
+            0 => None,
+            ttl if absttl => Some(ttl),
+            ttl => Some(now_ms() + ttl),
+        };
+        // 已经过期的 key 不再写入，REPLACE 时相当于删除旧值
+        if matches!(when_ms, Some(when_ms) if when_ms <= now_ms()) {
+            self.propagate = Some(if db.remove(&key) {
+                vec![Value::Array(vec![
+                    Value::BulkString(Some("DEL".into())),
+                    Value::BulkString(Some(key)),
+                ])]
+            } else {
+                vec![]
+            });
+            return Value::SimpleString("OK".to_string());
+        }
+        db.insert(key.clone(), value, when_ms.map(from_unix_ms));
+        if let Some(when_ms) = when_ms {
+            self.propagate = Some(vec![Value::Array(vec![
+                Value::BulkString(Some("RESTORE".into())),
+                Value::BulkString(Some(key)),
+                Value::BulkString(Some(when_ms.to_string().into_bytes())),
+                Value::BulkString(Some(payload)),
+                Value::BulkString(Some("REPLACE".into())),
+                Value::BulkString(Some("ABSTTL".into())),
+            ])]);
+        }
+        Value::SimpleString("OK".to_string())
+    }
+
+    /// XREAD 的结果在 RESP3 下以 map 返回，RESP2 保持 [name, entries] 数组
+    fn xread_reply(&self, res: Value) -> Value {
+        match res {
+            Value::Array(streams) if self.protocol >= RESP3 => Value::Map(
+                streams
+                    .into_iter()
+                    .filter_map(|s| match s {
+                        Value::Array(mut pair) if pair.len() == 2 => {
+                            let entries = pair.pop().unwrap();
+                            let name = pair.pop().unwrap();
+                            Some((name, entries))
+                        }
+                        _ => None,
+                    })
+                    .collect(),
+            ),
+            other => other,
+        }
+    }
+
+    /// HELLO [protover [AUTH username password] [SETNAME clientname]]
+    async fn hello(&mut self, mut args: Vec<Value>, config: RedisConfig) -> Value {
+        let mut protocol = self.protocol;
+        if !args.is_empty() {
+            protocol = match args.remove(0).as_str().map(|s| s.parse::<u8>()) {
+                Some(Ok(v)) if v == RESP2 || v == RESP3 => v,
+                Some(Ok(_)) => return Value::Error("NOPROTO unsupported protocol version".to_string()),
+                _ => return Value::Error("ERR Protocol version is not an integer or out of range".to_string()),
+            };
+        }
+        let mut client_name = None;
+        while !args.is_empty() {
+            let opt = args.remove(0);
+            match opt.as_str() {
+                Some(o) if o.eq_ignore_ascii_case("auth") && args.len() >= 2 => {
+                    let user = args.remove(0);
+                    let _password = args.remove(0);
+                    // 没有配置密码，只有 default 用户可以登录
+                    if user.as_str() != Some("default") {
+                        return Value::Error("WRONGPASS invalid username-password pair or user is disabled.".to_string());
+                    }
+                }
+                Some(o) if o.eq_ignore_ascii_case("setname") && !args.is_empty() => {
+                    match args.remove(0).as_str() {
+                        Some(name) if !name.contains(' ') => client_name = Some(name.to_string()),
+                        _ => return Value::Error("ERR Client names cannot contain spaces, newlines or special characters.".to_string()),
+                    }
+                }
+                _ => return Value::Error(format!("ERR Syntax error in HELLO option '{}'", opt)),
+            }
+        }
+        self.protocol = protocol;
+        if client_name.is_some() {
+            self.client_name = client_name;
+        }
+
+        let role = match config.lock().await.get_key_info_of_replication("role".to_string()) {
+            Value::SimpleString(r) if r == "slave" => "replica".to_string(),
+            _ => "master".to_string(),
+        };
+        Value::Map(vec![
+            (Value::BulkString(Some("server".into())), Value::BulkString(Some("redis".into()))),
+            (Value::BulkString(Some("version".into())), Value::BulkString(Some("7.2.0".into()))),
+            (Value::BulkString(Some("proto".into())), Value::Integer(self.protocol as i64)),
+            (Value::BulkString(Some("id".into())), Value::Integer(self.id as i64)),
+            (Value::BulkString(Some("mode".into())), Value::BulkString(Some("standalone".into()))),
+            (Value::BulkString(Some("role".into())), Value::BulkString(Some(role.into_bytes()))),
+            (Value::BulkString(Some("modules".into())), Value::Array(vec![])),
+        ])
+    }
+
+    pub async fn handle_command(&mut self, command: String,args: Vec<Value>,config:RedisConfig,addr:SocketAddr) -> Value {
+        self.propagate = None;
+        // 统一检查命令是否存在以及参数个数
+        let spec = match command::validate(&command, &args) {
+            Ok(spec) => spec,
+            Err(e) => return e,
+        };
+        let is_write = spec.flags & command::WRITE != 0;
+        // 写命令从执行到传播都持有写锁，事务中由 exec 统一持有
+        let _write_guard = if is_write && self.multi_propagate.is_none() {
+            let write_lock = config.lock().await.write_lock();
+            Some(write_lock.lock_owned().await)
+        } else {
+            None
+        };
+        if is_write {
+            let mut config_lock = config.lock().await;
+            if !self.master_client && !config_lock.is_master() {
+                return Value::Error("READONLY You can't write against a read only replica.".to_string());
+            }
+            if let Some(e) = config_lock.write_error().await {
+                return e;
+            }
+            // SWAPDB 这类命令会改变过期 key 所在的数据库，执行前先把它们传播出去
+            config_lock.propagate_expired().await;
+        }
+        // 写命令执行前保留原始命令，用于传播
+        let original = if is_write {
+            let mut cmd = vec![Value::BulkString(Some(command.clone().into_bytes()))];
+            cmd.extend(args.iter().cloned());
+            Some(Value::Array(cmd))
+        } else {
+            None
+        };
+        let in_exec = self.multi_propagate.is_some();
+        let value = self.execute_command(command, args, config.clone(), addr, in_exec).await;
+        // 成功且确实修改了数据的写命令计入 dirty 并传播，没有效果的写命令会把 propagate 设为空
+        let no_effect = matches!(self.propagate, Some(ref cmds) if cmds.is_empty());
+        let mut config_lock = config.lock().await;
+        match original {
+            Some(original) if !no_effect && !matches!(value, Value::Error(_)) => {
+                let cmds = self.propagate.take().unwrap_or_else(|| vec![original]);
+                config_lock.add_dirty(1).await;
+                match self.multi_propagate.as_mut() {
+                    // 事务中的写命令在 EXEC 结束时一起传播
+                    Some(pending) => pending.extend(cmds.into_iter().map(|cmd| (self.db_index, cmd))),
+                    None => {
+                        config_lock.propagate(self.db_index, &cmds).await;
+                        self.woff = config_lock.master_repl_offset().await;
+                    }
+                }
+            }
+            // 读命令也可能惰性删除了过期的 key
+            _ => config_lock.propagate_expired().await,
+        }
+        value
+    }
+
+    /// EXEC：依次执行事务中的命令，其中的写命令用 MULTI/EXEC 包起来一起传播
+    pub async fn exec(&mut self, cmds: Vec<(String, Vec<Value>)>, config: RedisConfig, addr: SocketAddr) -> Value {
+        let write_lock = config.lock().await.write_lock();
+        self.exec_write_guard = Some(write_lock.lock_owned().await);
+        self.multi_propagate = Some(Vec::new());
+        let mut replies = Vec::new();
+        for (command, args) in cmds {
+            replies.push(self.handle_command(command, args, config.clone(), addr).await);
+        }
+        let pending = self.multi_propagate.take().unwrap_or_default();
+        let wrote = !pending.is_empty();
+        let mut config_lock = config.lock().await;
+        if pending.len() == 1 {
+            // 只有一条写命令时不需要包成事务
+            let (db_index, cmd) = pending.into_iter().next().unwrap();
+            config_lock.propagate(db_index, &[cmd]).await;
+        } else if let (Some(&(first_db, _)), Some(&(last_db, _))) = (pending.first(), pending.last()) {
+            config_lock.propagate(first_db, &[Value::Array(vec![Value::BulkString(Some("MULTI".into()))])]).await;
+            for (db_index, cmd) in pending {
+                config_lock.propagate(db_index, &[cmd]).await;
+            }
+            config_lock.propagate(last_db, &[Value::Array(vec![Value::BulkString(Some("EXEC".into()))])]).await;
+        }
+        if wrote {
+            self.woff = config_lock.master_repl_offset().await;
+        }
+        self.exec_write_guard = None;
+        Value::Array(replies)
+    }
+
+    /// in_exec 为 true 时在 EXEC 中执行，此时持有写锁，阻塞类命令不能等待其他客户端
+    async fn execute_command(&mut self, command: String,mut args: Vec<Value>,config:RedisConfig,addr:SocketAddr,in_exec:bool) -> Value {
         match command.to_lowercase().as_str() {
             "set" => {
-                let mut config_lock=config.lock().await;
-                if args.len() <2{
-                    Value::Error("Wrong number of arguments for SET".to_string());
-                }
-                let key = args.remove(0);
-                let value = args.remove(0); 
+                let key = arg_bytes(args.remove(0));
+                let value = arg_bytes(args.remove(0));
+                let mut expire_at: Option<SystemTime> = None;
+                let (mut nx, mut xx, mut keepttl) = (false, false, false);
                 while !args.is_empty() {
-                    match args[0] {
-                        Value::BulkString(Some(ref opt)) if opt.eq_ignore_ascii_case("PX") => {
-                            if args.len() < 2 {
-                                return Value::Error("Wrong number of arguments for PX".to_string());
-                            }
-                            let px = match args.remove(1) {
-                                Value::BulkString(Some(ttl_ms_str)) => {
-                                    match ttl_ms_str.parse::<u64>() {
-                                        Ok(ttl_ms) => ttl_ms,
-                                        Err(_) => return Value::Error("Invalid TTL value for PX".to_string()),
-                                    }
-                                },
-                                _ => return Value::Error("Invalid TTL value for PX".to_string()),
+                    let opt = args.remove(0);
+                    match opt.as_str().map(|s| s.to_uppercase()).as_deref() {
+                        Some(unit @ ("EX" | "PX" | "EXAT" | "PXAT")) if !args.is_empty() && expire_at.is_none() && !keepttl => {
+                            let time = match args.remove(0).as_str().map(|s| s.parse::<i64>()) {
+                                Some(Ok(time)) => time,
+                                _ => return Value::Error("ERR value is not an integer or out of range".to_string()),
                             };
-                            let expiration_time = SystemTime::now() + Duration::from_millis(px as u64);
-                            //这里插入过期时间
-                            let key_str=match key.clone(){
-                                Value::BulkString(Some(string)) => string,
-                                _ => return Value::Error("Invalid key for SET".to_string()),
-                            };
-                            config_lock.set_expriations(key_str, expiration_time);
-                            // self.expirations.insert(key.clone(), expiration_time);
-                            args.remove(0); // Remove "PX"
-                        },
-                        Value::BulkString(Some(ref opt)) if opt.eq_ignore_ascii_case("EX") => {
-                            if args.len() < 2 {
-                                return Value::Error("Wrong number of arguments for EX".to_string());
+                            if time <= 0 {
+                                return Value::Error("ERR invalid expire time in 'set' command".to_string());
                             }
-                            let ex = match args.remove(1) {
-                                Value::BulkString(Some(ttl_secs_str)) => {
-                                    match ttl_secs_str.parse::<u64>() {
-                                        Ok(ttl_secs) => ttl_secs,
-                                        Err(_) => return Value::Error("Invalid TTL value for EX".to_string()),
-                                    }
-                                },
-                                _ => return Value::Error("Invalid TTL value for EX".to_string()),
-                            };
-                            let expiration_time = SystemTime::now() + Duration::from_secs(ex as u64);
-                            //这里插入过期时间
-                            let key_str=match key.clone(){
-                                Value::BulkString(Some(string)) => string,
-                                _ => return Value::Error("Invalid key for SET".to_string()),
+                            let when_ms = match unit {
+                                "EX" => time.checked_mul(1000).and_then(|ms| ms.checked_add(now_ms())),
+                                "PX" => time.checked_add(now_ms()),
+                                "EXAT" => time.checked_mul(1000),
+                                _ => Some(time),
                             };
-                            config_lock.set_expriations(key_str, expiration_time);
-                            // self.expirations.insert(key.clone(), expiration_time);
-                            args.remove(0); // Remove "EX"
-                        },
-                        _ => {
-                            // If the command is not recognized, we can either ignore it or return an error.
-                            return Value::Error(format!("Unknown option: {:?}", args[0]));
+                            match when_ms {
+                                Some(ms) => expire_at = Some(from_unix_ms(ms)),
+                                None => return Value::Error("ERR invalid expire time in 'set' command".to_string()),
+                            }
                         }
+                        Some("NX") if !xx => nx = true,
+                        Some("XX") if !nx => xx = true,
+                        Some("KEEPTTL") if expire_at.is_none() => keepttl = true,
+                        _ => return Value::Error("ERR syntax error".to_string()),
                     }
                 }
-                let key_str=match key{
-                    Value::BulkString(Some(string)) => string,
-                    _ => return Value::Error("Invalid key for SET".to_string()),
-                };
-                let value_str = match value {
-                    Value::BulkString(Some(string)) => {
-                        match string.parse::<i64>() {
-                            Ok(parsed_int) => Value::Integer(parsed_int),
-                            Err(_) => Value::BulkString(Some(string)),
-                        }
-                    },
-                    _ => Value::Error("Invalid value for SET".to_string()),
-                };
-                config_lock.set(key_str,value_str)
+                let mut config_lock=config.lock().await;
+                let db = config_lock.db(self.db_index);
+                let exists = db.contains_key(&key);
+                if (nx && exists) || (xx && !exists) {
+                    self.propagate = Some(vec![]);
+                    return Value::BulkString(None);
+                }
+                if keepttl {
+                    db.set_keep_ttl(key, RedisValue::String(value));
+                } else if let Some(when) = expire_at {
+                    // 相对的过期时间传播成绝对时间，副本收到时不会重新计时
+                    self.propagate = Some(vec![Value::Array(vec![
+                        Value::BulkString(Some("SET".into())),
+                        Value::BulkString(Some(key.clone())),
+                        Value::BulkString(Some(value.clone())),
+                        Value::BulkString(Some("PXAT".into())),
+                        Value::BulkString(Some(unix_ms(when).to_string().into_bytes())),
+                    ])]);
+                    db.set(key.clone(), RedisValue::String(value));
+                    db.set_expire(&key, when);
+                } else {
+                    db.set(key, RedisValue::String(value));
+                }
+                Value::SimpleString("OK".to_string())
             }
             "get" => {
-                if args.is_empty() {
-                    return Value::Error("Wrong number of arguments for GET".to_string());
+                let key = arg_bytes(args.remove(0));
+                let mut config_lock=config.lock().await;
+                match get_string(config_lock.db(self.db_index), &key) {
+                    Ok(value) => Value::BulkString(value),
+                    Err(e) => e,
                 }
-                let key = args.remove(0);
-                let key_str = match key {
-                    Value::BulkString(Some(string)) => string,
-                    _ => return Value::Error("Invalid key for GET".to_string()),
-                };
+            }
+            "del" => {
                 let mut config_lock=config.lock().await;
-                let value = config_lock.get(key_str);
-                match value{
-                    Value::BulkString(Some(v)) => Value::BulkString(Some(v)),
-                    Value::Integer(v) => Value::BulkString(Some(v.to_string())),
-                    _ => Value::BulkString(None),
+                let db = config_lock.db(self.db_index);
+                let removed = args.into_iter().filter(|key| db.remove(&arg_bytes(key.clone()))).count();
+                if removed == 0 {
+                    self.propagate = Some(vec![]);
                 }
+                Value::Integer(removed as i64)
+            }
+            "exists" => {
+                let mut config_lock=config.lock().await;
+                let db = config_lock.db(self.db_index);
+                let found = args.into_iter().filter(|key| db.contains_key(&arg_bytes(key.clone()))).count();
+                Value::Integer(found as i64)
             }
             "config" => {
                 //增加config get的命令
-                if args.is_empty() {
-                    return Value::Error("Wrong number of arguments for KEYS".to_string());
-                }
                 let cmd = args.remove(0);
                 match cmd{
-                    Value::BulkString(Some(ref cmd)) if cmd.eq_ignore_ascii_case("get") => {
+                    Value::BulkString(Some(ref cmd)) if cmd.eq_ignore_ascii_case(b"get") => {
                         if args.len() == 1 {
                             let key = args.remove(0);
-                            let key_string = match key {
-                                Value::BulkString(Some(string)) => string,
-                                _ => return  Value::Error("Invalid key for CONFIG GET".to_string())
+                            let key_string = match key.as_str() {
+                                Some(string) => string.to_string(),
+                                None => return  Value::Error("Invalid key for CONFIG GET".to_string())
                             };
                             let config_lock=config.lock().await;
                             config_lock.config_get(key_string)
                         } else {
-                            Value::Error("Wrong number of arguments for CONFIG GET".to_string())
+                            Value::Error("ERR wrong number of arguments for 'config|get' command".to_string())
+                        }
+                    },
+                    Value::BulkString(Some(ref cmd)) if cmd.eq_ignore_ascii_case(b"set") => {
+                        if args.len() == 2 {
+                            let key = args.remove(0);
+                            let value = args.remove(0);
+                            match (key.as_str(), value.as_str()) {
+                                (Some(key), Some(value)) => {
+                                    let mut config_lock=config.lock().await;
+                                    config_lock.config_set(key.to_string(), value.to_string()).await
+                                }
+                                _ => Value::Error("Invalid argument for CONFIG SET".to_string()),
+                            }
+                        } else {
+                            Value::Error("ERR wrong number of arguments for 'config|set' command".to_string())
                         }
                     },
                     _ => Value::Error("Unknown CONFIG command".to_string()),
                 }   
             }
+            "expire" | "pexpire" | "expireat" | "pexpireat" => {
+                let command = command.to_lowercase();
+                self.expire_generic(&command, args, config).await
+            }
+            "ttl" | "pttl" | "expiretime" | "pexpiretime" => {
+                let command = command.to_lowercase();
+                self.ttl_generic(&command, arg_bytes(args.remove(0)), config).await
+            }
+            "persist" => {
+                let key = arg_bytes(args.remove(0));
+                let mut config_lock=config.lock().await;
+                if config_lock.db(self.db_index).persist(&key) {
+                    Value::Integer(1)
+                } else {
+                    self.propagate = Some(vec![]);
+                    Value::Integer(0)
+                }
+            }
             "keys" => {
-                if args.is_empty() {
-                    return Value::Error("Wrong number of arguments for KEYS".to_string());
+                let pattern = arg_bytes(args.remove(0));
+                let mut config_lock=config.lock().await;
+                let keys = config_lock.db(self.db_index).keys(&pattern);
+                Value::Array(keys.into_iter().map(|k| Value::BulkString(Some(k))).collect())
+            }
+            "info" => {
+                let sections: Vec<String> = args
+                    .iter()
+                    .map(|s| String::from_utf8_lossy(&arg_bytes(s.clone())).to_lowercase())
+                    .collect();
+                let config_lock=config.lock().await;
+                // RESP3 下 INFO 以 verbatim string 返回
+                Value::VerbatimString("txt".to_string(), config_lock.info(&sections).await.into_bytes())
+            }
+            "select" => {
+                let config_lock=config.lock().await;
+                match parse_db_index(&args[0], config_lock.databases()) {
+                    Ok(index) => {
+                        self.db_index = index;
+                        Value::SimpleString("OK".to_string())
+                    }
+                    Err(e) => e,
                 }
-                let key = args.remove(0);
-                match key{
-                    Value::BulkString(Some(cmd)) => {
-                        let config_lock=config.lock().await;
-                        config_lock.get_keys(cmd)
-                    },
-                    _ => Value::Error("Unknown CONFIG command".to_string())
+            }
+            "move" => {
+                let key = arg_bytes(args.remove(0));
+                let mut config_lock=config.lock().await;
+                let dst = match parse_db_index(&args[0], config_lock.databases()) {
+                    Ok(index) => index,
+                    Err(e) => return e,
+                };
+                if dst == self.db_index {
+                    return Value::Error("ERR source and destination objects are the same".to_string());
+                }
+                let (src_db, dst_db) = config_lock.keyspace().two_dbs(self.db_index, dst);
+                // 目标库已经有这个 key 时不移动
+                if dst_db.contains_key(&key) {
+                    self.propagate = Some(vec![]);
+                    return Value::Integer(0);
+                }
+                match src_db.take(&key) {
+                    Some((value, expire)) => {
+                        dst_db.insert(key, value, expire);
+                        Value::Integer(1)
+                    }
+                    None => {
+                        self.propagate = Some(vec![]);
+                        Value::Integer(0)
+                    }
                 }
             }
-            "info" =>{
-                if args.is_empty() {
-                    return Value::Error("Wrong number of arguments for KEYS".to_string());
+            "swapdb" => {
+                let mut config_lock=config.lock().await;
+                let databases = config_lock.databases();
+                let a = match parse_db_index(&args[0], databases) {
+                    Ok(index) => index,
+                    Err(_) => return Value::Error("ERR invalid first DB index".to_string()),
+                };
+                let b = match parse_db_index(&args[1], databases) {
+                    Ok(index) => index,
+                    Err(_) => return Value::Error("ERR invalid second DB index".to_string()),
+                };
+                config_lock.keyspace().swap(a, b);
+                Value::SimpleString("OK".to_string())
+            }
+            "flushdb" | "flushall" => {
+                // ASYNC 和 SYNC 都同步执行
+                if let Some(mode) = args.first() {
+                    if !matches!(mode.as_str(), Some(m) if m.eq_ignore_ascii_case("async") || m.eq_ignore_ascii_case("sync")) || args.len() > 1 {
+                        return Value::Error("ERR syntax error".to_string());
+                    }
+                }
+                let mut config_lock=config.lock().await;
+                if command.eq_ignore_ascii_case("flushdb") {
+                    config_lock.db(self.db_index).clear();
+                } else {
+                    config_lock.keyspace().flush_all();
+                }
+                Value::SimpleString("OK".to_string())
+            }
+            "save" => {
+                let mut config_lock=config.lock().await;
+                match config_lock.save().await {
+                    Ok(()) => Value::SimpleString("OK".to_string()),
+                    Err(e) => Value::Error(format!("ERR {}", e)),
+                }
+            }
+            "bgsave" => {
+                let mut config_lock=config.lock().await;
+                match config_lock.bgsave().await {
+                    Ok(()) => Value::SimpleString("Background saving started".to_string()),
+                    Err(e) => Value::Error(format!("ERR {}", e)),
+                }
+            }
+            "lastsave" => {
+                let config_lock=config.lock().await;
+                Value::Integer(config_lock.lastsave().await as i64)
+            }
+            "shutdown" => {
+                let mut flags = ShutdownFlags::default();
+                for arg in args {
+                    match arg.as_str().map(|s| s.to_uppercase()).as_deref() {
+                        Some("NOSAVE") => flags.nosave = true,
+                        Some("SAVE") => flags.save = true,
+                        Some("NOW") => flags.now = true,
+                        Some("FORCE") => flags.force = true,
+                        _ => return Value::Error("ERR syntax error".to_string()),
+                    }
+                }
+                if flags.nosave && flags.save {
+                    return Value::Error("ERR syntax error".to_string());
+                }
+                // 先拿写锁，正在执行的写命令都传播完之后再收尾；事务中 exec 已经持有
+                let write_guard = match self.exec_write_guard.take() {
+                    Some(guard) => guard,
+                    None => {
+                        let write_lock = config.lock().await.write_lock();
+                        write_lock.lock_owned().await
+                    }
+                };
+                let mut config_lock=config.lock().await;
+                match config_lock.prepare_shutdown(flags, &write_guard).await {
+                    Ok(()) => {
+                        // 持有配置锁直接退出，之后不会再有命令修改数据
+                        println!("Redis is now ready to exit, bye bye...");
+                        std::process::exit(0);
+                    }
+                    Err(e) => {
+                        println!("{}", e);
+                        if self.multi_propagate.is_some() {
+                            self.exec_write_guard = Some(write_guard);
+                        }
+                        Value::Error("ERR Errors trying to SHUTDOWN. Check logs.".to_string())
+                    }
                 }
-                let cmd = args.remove(0);
-                match cmd{
-                    Value::BulkString(Some(ref cmd)) if cmd.eq_ignore_ascii_case("replication") => {
-                        let config_lock=config.lock().await;
-                        config_lock.get_info_replication()
-                    },
-                    _ => Value::Error("Unknown INFO command".to_string()),
-                }  
+            }
+            "dbsize" => {
+                let mut config_lock=config.lock().await;
+                Value::Integer(config_lock.db(self.db_index).len() as i64)
             }
             "replconf" => {
                 if args.is_empty() {
-                    return Value::Error("Wrong number of arguments for REPLCONF".to_string());
+                    return Value::Error("ERR wrong number of arguments for 'replconf' command".to_string());
                 }
                 let cmd = args.remove(0);
                 match cmd{
-                    Value::BulkString(Some(ref cmd)) if cmd.eq_ignore_ascii_case("listening-port") => {
+                    Value::BulkString(Some(ref cmd)) if cmd.eq_ignore_ascii_case(b"listening-port") => {
                         if args.len() == 1 {
                             let port = args.remove(0);
                             // TODO: handle port for master
                             let mut config_lock=config.lock().await;
-                            let slave_addr= format!("{}:{}",addr.clone().ip().to_string() , port);
+                            let slave_addr= format!("{}:{}",addr.ip() , port);
                             config_lock.new_slave_come(addr.clone().to_string(),slave_addr).await;
 
                             Value::SimpleString("OK".to_string())
@@ -183,7 +722,7 @@ impl RedisDb {
                             Value::Error("Wrong number of arguments for listening-port".to_string())
                         }
                     },
-                    Value::BulkString(Some(ref cmd)) if cmd.eq_ignore_ascii_case("capa") => {
+                    Value::BulkString(Some(ref cmd)) if cmd.eq_ignore_ascii_case(b"capa") => {
                         if args.len() == 1 {
                             let _arg1 = args.remove(0);
                             // TODO: handle psync2 mode
@@ -192,18 +731,17 @@ impl RedisDb {
                             Value::Error("Wrong number of arguments for listening-port".to_string())
                         }
                     },
-                    Value::BulkString(Some(ref cmd)) if cmd.eq_ignore_ascii_case("getack") => {
+                    Value::BulkString(Some(ref cmd)) if cmd.eq_ignore_ascii_case(b"getack") => {
                         if args.len() == 1 {
                             let _arg1 = args.remove(0);
                             let config_lock=config.lock().await;
                             let offset = config_lock.rcliinfo_get_slave_cmd_offset();
 
-                            let mut reply_vec = Vec::new();
-                            reply_vec.push(Value::BulkString(Some("REPLCONF".to_string())));
-                            reply_vec.push(Value::BulkString(Some("ACK".to_string())));
-                            reply_vec.push(Value::BulkString(Some(offset.to_string())));
-
-                            Value::Array(reply_vec)
+                            Value::Array(vec![
+                                Value::BulkString(Some("REPLCONF".into())),
+                                Value::BulkString(Some("ACK".into())),
+                                Value::BulkString(Some(offset.to_string().into_bytes())),
+                            ])
                         } else {
                             Value::Error("Wrong number of arguments for GETACK".to_string())
                         }
@@ -211,278 +749,265 @@ impl RedisDb {
                     _ => Value::Error("Unknown REPLCONF command".to_string()),
                 }
             }
-            "psync" =>{
-                if args.is_empty() {
-                    return Value::Error("Wrong number of arguments for REPLCONF".to_string());
-                }
-                let cmd = args.remove(0);
-                // 处理第一个参数，第一次发送的话
-                match cmd{
-                    Value::BulkString(Some(ref cmd)) if cmd.eq_ignore_ascii_case("?") => {
-                        if args.len() == 1 {
-                            let _arg1 = args.remove(0);
-                            // TODO: handle port for master
-                            let config_lock=config.lock().await;
-                            let mode = "FULLRESYNC".to_string();
-                            let repl_id = match config_lock.get_key_info_of_replication("master_replid".to_string()){
-                                Value::SimpleString(s) => s,
-                                _ => "Unknown".to_string()
-                            };
-                            let master_repl_offset = match config_lock.get_key_info_of_replication("master_repl_offset".to_string()){
-                                Value::Integer(s) => s.to_string(),
-                                _ => "Unknown".to_string()
-                            };
-                            let response = format!("{} {} {}",mode,repl_id,master_repl_offset);
-
-                            Value::SimpleString(response)
-                            //回复一个参数
-                        } else {
-                            Value::Error("Wrong number of arguments for PSYNC".to_string())
-                        }
-                    },
-                    _ => Value::Error("Unknown REPLCONF command".to_string()),
+            "replicaof" | "slaveof" => {
+                let host = arg_bytes(args.remove(0));
+                let port = arg_bytes(args.remove(0));
+                if host.eq_ignore_ascii_case(b"no") && port.eq_ignore_ascii_case(b"one") {
+                    return Config::replicaof(config, None).await;
                 }
+                let port = match std::str::from_utf8(&port).ok().and_then(|s| s.parse::<u16>().ok()) {
+                    Some(port) => port,
+                    None => return Value::Error("ERR Invalid master port".to_string()),
+                };
+                let host = String::from_utf8_lossy(&host).to_string();
+                let master_addr = match tokio::net::lookup_host((host.as_str(), port)).await.map(|mut addrs| addrs.next()) {
+                    Ok(Some(addr)) => addr,
+                    _ => return Value::Error(format!("ERR Can't resolve master address {}:{}", host, port)),
+                };
+                Config::replicaof(config, Some(master_addr)).await
+            }
+            "psync" =>{
+                // PSYNC replid offset，第一次同步时是 PSYNC ? -1
+                let replid = arg_bytes(args.remove(0));
+                let offset = arg_bytes(args.remove(0));
+                // 持有写锁生成快照，正在执行还没传播的写命令不会既在快照里又在之后的复制流里
+                // 事务中由 exec 统一持有
+                let _write_guard = if self.multi_propagate.is_none() {
+                    let write_lock = config.lock().await.write_lock();
+                    Some(write_lock.lock_owned().await)
+                } else {
+                    None
+                };
+                let mut config_lock=config.lock().await;
+                let (reply, sync) = config_lock
+                    .psync(&String::from_utf8_lossy(&replid), &String::from_utf8_lossy(&offset))
+                    .await;
+                self.replica_sync = Some(sync);
+                reply
             }
             "type" => {
-                if args.is_empty(){
-                    return Value::Error("Wrong number of arguments for TYPE".to_string());
-                }
-                let value = args.remove(0);
-        
+                let key = arg_bytes(args.remove(0));
+                let mut config_lock=config.lock().await;
+                let type_name = config_lock.db(self.db_index).get(&key).map(|v| v.type_name()).unwrap_or("none");
+                Value::SimpleString(type_name.to_string())
+            }
+            "dump" => {
+                let key = arg_bytes(args.remove(0));
                 let mut config_lock=config.lock().await;
-                let res = config_lock.get_type(value);
-                Value::SimpleString(res)
+                Value::BulkString(config_lock.db(self.db_index).get(&key).map(rdb::dump_value))
             }
+            "restore" => self.restore(args, config).await,
             "xadd" => {
-                if args.len() < 2 {
-                    return Value::Error("Wrong number of arguments for XADD".to_string());
-                }
                 // 增加key到stream当中
-                let stream_key = args.remove(0);
-                let stream_value = args.remove(0);
-
-                let mut hashmap = HashMap::new();
-                while args.len()!=0 && args.len()%2 ==0{
-                    let stream_content_key = args.remove(0);
-                    let stream_content_value = args.remove(0);
-                    hashmap.insert(stream_content_key, stream_content_value);
-                    // 放入Stream当中
+                let stream_key = arg_bytes(args.remove(0));
+                let stream_id = arg_bytes(args.remove(0));
+                let propagate_key = stream_key.clone();
+                let raw_fields = args.clone();
+                let fields = args.chunks_exact(2);
+                if !fields.remainder().is_empty() {
+                    return Value::Error("ERR wrong number of arguments for 'xadd' command".to_string());
                 }
+                let fields: Fields = fields
+                    .map(|pair| (arg_bytes(pair[0].clone()), arg_bytes(pair[1].clone())))
+                    .collect();
+
                 let mut config_lock=config.lock().await;
-                match config_lock.xadd((stream_key, stream_value), hashmap).await{
-                    Ok(res) => res,
+                let db = config_lock.db(self.db_index);
+                let res = match db.get_mut(&stream_key) {
+                    Some(RedisValue::Stream(stream)) => stream.add(&stream_id, fields),
+                    Some(_) => return Value::Error(WRONGTYPE.to_string()),
+                    None => {
+                        // key 不存在时新建一个流
+                        let mut stream = Stream::new();
+                        let res = stream.add(&stream_id, fields);
+                        if res.is_ok() {
+                            db.set(stream_key, RedisValue::Stream(stream));
+                        }
+                        res
+                    }
+                };
+                match res {
+                    Ok(id) => {
+                        // 自动生成的 ID 传播成具体的 ID，副本上得到同样的条目
+                        let mut cmd = vec![
+                            Value::BulkString(Some("XADD".into())),
+                            Value::BulkString(Some(propagate_key)),
+                            id.to_value(),
+                        ];
+                        cmd.extend(raw_fields);
+                        self.propagate = Some(vec![Value::Array(cmd)]);
+                        id.to_value()
+                    }
                     Err(e) => Value::Error(format!("{}",e)),
                 }
-
             }
             "xrange" => {
-                if args.len() < 2 {
-                    return Value::Error("Wrong number of arguments for XRANGE".to_string());
-                }
-                // 增加key到stream当中
-                let stream_key = args.remove(0);
-                let start = args.remove(0);
-                let end = args.remove(0);
+                let stream_key = arg_bytes(args.remove(0));
+                let start = StreamId::parse_range_start(&arg_bytes(args.remove(0)));
+                let end = StreamId::parse_range_end(&arg_bytes(args.remove(0)));
+                let (start, end) = match (start, end) {
+                    (Ok(start), Ok(end)) => (start, end),
+                    (Err(e), _) | (_, Err(e)) => return Value::Error(format!("{}", e)),
+                };
+                let count = match args.len() {
+                    0 => None,
+                    2 if matches!(args[0].as_str(), Some(s) if s.eq_ignore_ascii_case("count")) => {
+                        match args[1].as_str().map(|s| s.parse::<usize>()) {
+                            Some(Ok(count)) => Some(count),
+                            _ => return Value::Error("ERR value is not an integer or out of range".to_string()),
+                        }
+                    }
+                    _ => return Value::Error("ERR syntax error".to_string()),
+                };
                 let mut config_lock=config.lock().await;
-                match config_lock.xrange(stream_key,start, end).await{
-                    Ok(res) => res,
-                    Err(e) => Value::Error(format!("{}",e)),
+                match config_lock.db(self.db_index).get(&stream_key) {
+                    Some(RedisValue::Stream(stream)) => Value::Array(stream.range(start, end, count)),
+                    Some(_) => Value::Error(WRONGTYPE.to_string()),
+                    None => Value::Array(vec![]),
                 }
-
             }
             "xread" => {
-                //实现xread的逻辑
-                if args.len() < 1 {
-                    return Value::Error("Wrong number of arguments for XREAD".to_string());
-                }
-                let cmd = args.remove(0);
-                match cmd {
-                    Value::BulkString(Some(ref cmd)) if cmd.eq_ignore_ascii_case("block") => {
-                        // 这里实现block 的循环检查
-                        // 检查是否有新的item进来,进来了之后检查是不是当前想要的
-                        let num_str = match args.remove(0){
-                            Value::BulkString(Some(ref num_str)) => num_str.clone(),
-                            _ => return Value::Error("Err block num".to_string())
-                        };
-                        let _ = args.remove(0); //这个得到的参数是streams
-
-                        //在这里,阻塞读取
-                        let start_time = Instant::now();
-                        let interval = Duration::from_millis(20);
-                        let block_num:u64= match num_str.parse::<i32>(){
-                            Ok(num) => {
-                                if num == 0 {
-                                    u64::MAX
-                                }else{
-                                    num as u64
-                                }
-                            }
-                            Err(e) => return Value::Error("Err block num".to_string())
-                        };
-
-
-                        let total_duration = Duration::from_millis(block_num as u64);
-                        
-                        let stream_key_num = args.len()/2;
-                        let mut stream_key_vec = Vec::new();
-                        let mut stream_name_vec = Vec::new();
-                        for _ in 0..stream_key_num {
-                            stream_key_vec.push(args.remove(0));
-                        }
-                        for _ in 0..stream_key_num { 
-                            stream_name_vec.push(args.remove(0));
-                        }
-                        let mut stream_key_name_vec = Vec::new();
-                        for i in 0..stream_key_num {
-                            //在这里如果碰到stream_name_vec[i]是$的情况,那么就获取目前这个stream_key最大的一个项目
-                            match stream_name_vec[i].clone(){
-                                Value::BulkString(Some(ref s)) if s.eq_ignore_ascii_case("$") => {
-                                    let mut config_lock=config.lock().await;
-                                    let stream_name = match config_lock.xread_latest(stream_key_vec[i].clone()){
-                                        Ok(res) => res,
-                                        Err(e) => Value::Error(format!("{}",e)),
-                                    };
-                                    stream_key_name_vec.push((stream_key_vec[i].clone(), stream_name));
-                                }
-                                _ => {
-                                    stream_key_name_vec.push((stream_key_vec[i].clone(), stream_name_vec[i].clone()));
-                                }
-                            }
-                            
-                        }
-
-                        while start_time.elapsed() < total_duration {
-                            //检查是否有新的项目进来并且项目是匹配的,没有的话继续执行,有的话打破循环
-                            {                     
-                                let mut config_lock=config.lock().await;
-                                let res_val = match config_lock.xread(stream_key_name_vec.clone()).await{
-                                    Ok(res) => res,
-                                    Err(e) => Value::Error(format!("{}",e)),
-                                };
-                                match res_val.clone(){
-                                    Value::Array(ref v) => {
-                                        if !v.is_empty() {
-                                            return res_val
-                                        }
-                                    }
-                                    _=>{}
-                                }
-                            }
-                            // 异步睡眠100ms
-                            sleep(interval).await;
-                        }
-                        // 什么数据也没有就直接返回空的字符串
-                        return Value::BulkString(None);
+                //实现xread的逻辑: XREAD [COUNT n] [BLOCK ms] STREAMS key [key ...] id [id ...]
+                let mut count = None;
+                let mut block = None;
+                loop {
+                    if args.is_empty() {
+                        return Value::Error("ERR syntax error".to_string());
                     }
-                    Value::BulkString(Some(ref cmd)) if cmd.eq_ignore_ascii_case("streams") => {
-                        let stream_key_num = args.len()/2;
-                        let mut stream_key_vec = Vec::new();
-                        let mut stream_name_vec = Vec::new();
-                        for _ in 0..stream_key_num {
-                            stream_key_vec.push(args.remove(0));
-                        }
-                        for _ in 0..stream_key_num {
-                            stream_name_vec.push(args.remove(0));
-                        }
-                        let mut stream_key_name_vec = Vec::new();
-                        for i in 0..stream_key_num {
-                            stream_key_name_vec.push((stream_key_vec[i].clone(), stream_name_vec[i].clone()));
+                    let opt = args.remove(0);
+                    match opt.as_str().map(|s| s.to_lowercase()).as_deref() {
+                        Some("count") if !args.is_empty() => {
+                            count = match args.remove(0).as_str().map(|s| s.parse::<usize>()) {
+                                Some(Ok(count)) => Some(count),
+                                _ => return Value::Error("ERR value is not an integer or out of range".to_string()),
+                            };
                         }
-
-                        let mut config_lock=config.lock().await;
-                        match config_lock.xread(stream_key_name_vec).await{
-                            Ok(res) => return res,
-                            Err(e) => return Value::Error(format!("{}",e)),
+                        Some("block") if !args.is_empty() => {
+                            block = match args.remove(0).as_str().map(|s| s.parse::<i64>()) {
+                                Some(Ok(ms)) if ms >= 0 => Some(ms as u64),
+                                Some(Ok(_)) => return Value::Error("ERR timeout is negative".to_string()),
+                                _ => return Value::Error("ERR timeout is not an integer or out of range".to_string()),
+                            };
                         }
+                        Some("streams") => break,
+                        _ => return Value::Error("ERR syntax error".to_string()),
                     }
-                    _ => return Value::Error("Unknown XREAD command".to_string()),
                 }
-
-            }
-            "incr" => {
-                if args.is_empty() {
-                    return Value::Error("Wrong number of arguments for GET".to_string());
+                if args.is_empty() || !args.len().is_multiple_of(2) {
+                    return Value::Error(XREAD_UNBALANCED.to_string());
                 }
-                let key = args.remove(0);
-                let key_str = match key {
-                    Value::BulkString(Some(string)) => string,
-                    _ => return Value::Error("Invalid key for GET".to_string()),
-                };
+                let ids = args.split_off(args.len() / 2);
+
+                // 先把 $ 换成当前流的最后一个 ID，之后只返回比它新的条目
+                let mut positions = Vec::new();
                 {
                     let mut config_lock=config.lock().await;
-                    let value = config_lock.get(key_str.clone());
-                    match value { 
-                        Value::Integer(n) => {
-                            let incr_value = Value::Integer(n + 1);
-                            config_lock.incr(key_str.clone(),incr_value.clone())
-                        }
-                        Value::BulkString(None) => {
-                            let incr_value = Value::Integer(1);
-                            config_lock.incr(key_str.clone(),incr_value.clone())
-                        }
-                        _ => Value::Error("ERR value is not an integer or out of range".to_string()),
+                    let db = config_lock.db(self.db_index);
+                    for (key, id) in args.into_iter().zip(ids) {
+                        let key = arg_bytes(key);
+                        let id = arg_bytes(id);
+                        let last_id = match db.get(&key) {
+                            Some(RedisValue::Stream(stream)) => stream.last_id(),
+                            Some(_) => return Value::Error(WRONGTYPE.to_string()),
+                            None => StreamId::MIN,
+                        };
+                        let id = if id == b"$" {
+                            last_id
+                        } else {
+                            match StreamId::parse(&id) {
+                                Ok(id) => id,
+                                Err(e) => return Value::Error(format!("{}", e)),
+                            }
+                        };
+                        positions.push((key, id));
                     }
                 }
 
-                
-            }
-            "wait" => {
-                if args.len()!= 2 {
-                    return Value::Error("Wrong number of arguments for WAIT".to_string());
+                // BLOCK 0 表示一直阻塞；和 Redis 一样事务中的 BLOCK 不阻塞
+                if in_exec {
+                    block = None;
                 }
-
-                let num_of_repl_slaves = match args.remove(0){
-                    Value::BulkString(Some(ref num_str)) => num_str.clone(),
-                    _ => return Value::Error("Err wait num".to_string())
-                }.parse::<i32>().unwrap();
-
-                if num_of_repl_slaves==0{
-                    return Value::Integer(0);
-                }
-
-                
-                let wait_num = match args.remove(0){
-                    Value::BulkString(Some(ref num_str)) => num_str.clone(),
-                    _ => return Value::Error("Err wait num".to_string())
-                }.parse::<i32>().unwrap();
-                println!("{}", wait_num);
-
-
                 let start_time = Instant::now();
-                let interval = Duration::from_millis(100);
-                let total_duration = Duration::from_millis(wait_num as u64);
-                let mut res_val = Value::BulkString(None);
-
-                while start_time.elapsed() < total_duration {
-                    //检查是否有新的项目进来并且项目是匹配的,没有的话继续执行,有的话打破循环
-                    {                     
+                let interval = Duration::from_millis(20);
+                loop {
+                    {
                         let mut config_lock=config.lock().await;
-                        res_val = match config_lock.wait(num_of_repl_slaves.clone()).await{
-                            Ok(res) => {
-                                res_val = res.clone(); // 假设 res 是你需要的值
-                                continue;
+                        let db = config_lock.db(self.db_index);
+                        let mut results = Vec::new();
+                        for (key, id) in positions.iter() {
+                            if let Some(RedisValue::Stream(stream)) = db.get(key) {
+                                let entries = stream.read_after(*id, count);
+                                if !entries.is_empty() {
+                                    results.push(Value::Array(vec![Value::BulkString(Some(key.clone())), Value::Array(entries)]));
+                                }
                             }
-                            Err(e) => Value::Error(format!("{}",e)),
-                        };
+                        }
+                        if !results.is_empty() {
+                            return self.xread_reply(Value::Array(results));
+                        }
+                    }
+                    match block {
+                        Some(ms) if ms == 0 || start_time.elapsed() < Duration::from_millis(ms) => sleep(interval).await,
+                        // 什么数据也没有就直接返回空
+                        _ => return Value::BulkString(None),
                     }
-                    // 异步睡眠20ms
-                    sleep(interval).await;
                 }
-
-                res_val
             }
-            "ping" => Value::SimpleString("PONG".to_string()),
-            "echo" => {
-                if args.len() == 1 {
-                    args.first().unwrap().clone()
-                } else {
-                    Value::Error("Wrong number of arguments for ECHO".to_string())
-                }
+            "incr" => {
+                let key = arg_bytes(args.remove(0));
+                let mut config_lock=config.lock().await;
+                let db = config_lock.db(self.db_index);
+                let current = match get_string(db, &key) {
+                    Ok(None) => 0,
+                    Ok(Some(v)) => match std::str::from_utf8(&v).ok().and_then(|s| s.parse::<i64>().ok()) {
+                        Some(n) => n,
+                        None => return Value::Error("ERR value is not an integer or out of range".to_string()),
+                    },
+                    Err(e) => return e,
+                };
+                let incr_value = match current.checked_add(1) {
+                    Some(n) => n,
+                    None => return Value::Error("ERR increment or decrement would overflow".to_string()),
+                };
+                db.set_keep_ttl(key, RedisValue::String(incr_value.to_string().into_bytes()));
+                Value::Integer(incr_value)
             }
-            "rdbfile"=>{
-                Value::SimpleString("Get a RedisFile from master".to_string())
+            "wait" => {
+                // WAIT numreplicas timeout，timeout 为 0 时一直等待
+                let numreplicas = match args[0].as_str().map(|s| s.parse::<i64>()) {
+                    Some(Ok(num)) => num.max(0) as usize,
+                    _ => return Value::Error("ERR value is not an integer or out of range".to_string()),
+                };
+                let timeout = match args[1].as_str().map(|s| s.parse::<i64>()) {
+                    Some(Ok(ms)) if ms < 0 => return Value::Error("ERR timeout is negative".to_string()),
+                    Some(Ok(0)) => None,
+                    Some(Ok(ms)) => Some(Duration::from_millis(ms as u64)),
+                    _ => return Value::Error("ERR timeout is not an integer or out of range".to_string()),
+                };
+                let slaves = {
+                    let config_lock=config.lock().await;
+                    if !config_lock.is_master() {
+                        return Value::Error("ERR WAIT cannot be used with replica instances. Please also note that since Redis 4.0 if a replica is configured to be writable (which is not the default) writes to replicas are just local and are not propagated.".to_string());
+                    }
+                    config_lock.slaves_handler()
+                };
+                // 事务中持有写锁，不能等待，直接返回已经确认的副本数
+                if in_exec {
+                    return Value::Integer(slaves.read().await.acked_replicas(self.woff) as i64);
+                }
+                // 等待期间不持有配置锁，其他客户端照常执行
+                let acked = Slaves::wait(slaves, numreplicas, self.woff, timeout).await;
+                Value::Integer(acked as i64)
             }
-            _ => Value::Error(format!("Unknown command: {}", command)),
+            "hello" => self.hello(args, config).await,
+            "command" => command::command_command(args),
+            "ping" => match args.len() {
+                0 => Value::SimpleString("PONG".to_string()),
+                1 => args.remove(0),
+                _ => Value::Error("ERR wrong number of arguments for 'ping' command".to_string()),
+            },
+            "echo" => args.remove(0),
+            _ => Value::Error(format!("ERR unknown command '{}'", command)),
         }
     }
 
@@ -3,6 +3,8 @@ use crate::resp::Value;
 use anyhow::Result;
 use tokio::sync::Mutex;
 use std::sync::Arc;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 #[derive(Debug)]
 pub struct RCliInfo {
     replication_info: HashMap<String, Value>,
@@ -15,7 +17,7 @@ impl RCliInfo {
 
         replication_info.insert("role".to_string(), Value::SimpleString("master".to_string()));
         replication_info.insert("connected_slaves".to_string(), Value::Integer(0));
-        replication_info.insert("master_replid".to_string(), Value::SimpleString("8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string()));
+        replication_info.insert("master_replid".to_string(), Value::SimpleString(new_replid()));
         replication_info.insert("master_repl_offset".to_string(), Value::Integer(0));
         replication_info.insert("second_repl_offset".to_string(), Value::Integer(-1));
         replication_info.insert("repl_backlog_active".to_string(), Value::SimpleString("0".to_string()));
@@ -27,10 +29,14 @@ impl RCliInfo {
     }
 
     /// 获取复制信息，返回一个符合 Redis 协议的批量字符串
-    pub fn get_replication_info(&self) -> String {
+    /// live 中是偏移量、积压缓冲区这类实时计算的字段，优先于保存的值
+    pub fn get_replication_info(&self, live: &HashMap<String, Value>) -> String {
         let mut response = String::new();
         let keys_in_order = vec![
             "role",
+            "master_host",
+            "master_port",
+            "master_link_status",
             "connected_slaves",
             "master_replid",
             "master_repl_offset",
@@ -42,8 +48,8 @@ impl RCliInfo {
         ];
 
         for key in keys_in_order {
-            if let Some(value) = self.replication_info.get(key) {
-                response.push_str(&format!("{}:{}\n", key, value));
+            if let Some(value) = live.get(key).or_else(|| self.replication_info.get(key)) {
+                response.push_str(&format!("{}:{}\r\n", key, value));
             }
         }
 
@@ -53,6 +59,14 @@ impl RCliInfo {
         self.replication_info.insert("role".to_string(), Value::SimpleString(role));
     }
 
+    pub fn set_param(&mut self, param:&str, value:Value){
+        self.replication_info.insert(param.to_string(), value);
+    }
+
+    pub fn remove_param(&mut self, param:&str){
+        self.replication_info.remove(param);
+    }
+
     pub fn get_param(&self, param:String)->Value{
         match self.replication_info.get(&param) {
             Some(value) => value.clone(),
@@ -61,3 +75,11 @@ impl RCliInfo {
     }
 
 }
+
+/// 生成 40 个十六进制字符的随机复制 ID
+pub fn new_replid() -> String {
+    (0..3)
+        .map(|_| format!("{:016x}", RandomState::new().build_hasher().finish()))
+        .collect::<String>()[..40]
+        .to_string()
+}
@@ -0,0 +1,541 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::resp::Value;
+use crate::stream::Stream;
+
+pub const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+// 主动过期每轮在每个数据库中抽样的 key 数量
+const ACTIVE_EXPIRE_KEYS_PER_LOOP: usize = 20;
+// 抽样中过期 key 的比例不超过这个百分比时停止这个数据库的抽样
+const ACTIVE_EXPIRE_ACCEPTABLE_STALE: usize = 10;
+
+/// 键空间里保存的值，每种数据类型一个分支
+#[derive(Debug, Clone)]
+pub enum RedisValue {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+    // 有序集合：成员到分数的映射，需要有序时再排序
+    ZSet(HashMap<Vec<u8>, f64>),
+    Stream(Stream),
+}
+
+impl RedisValue {
+    /// TYPE 命令返回的类型名
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            RedisValue::String(_) => "string",
+            RedisValue::List(_) => "list",
+            RedisValue::Hash(_) => "hash",
+            RedisValue::Set(_) => "set",
+            RedisValue::ZSet(_) => "zset",
+            RedisValue::Stream(_) => "stream",
+        }
+    }
+}
+
+/// 带过期时间的 key，另外用数组保存一份 key，主动过期时可以直接随机抽样
+#[derive(Debug, Clone, Default)]
+struct Expires {
+    // key 到过期时间和它在 keys 中的下标
+    map: HashMap<Vec<u8>, (SystemTime, usize)>,
+    keys: Vec<Vec<u8>>,
+}
+
+impl Expires {
+    fn get(&self, key: &[u8]) -> Option<&SystemTime> {
+        self.map.get(key).map(|(when, _)| when)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, when: SystemTime) {
+        match self.map.get_mut(&key) {
+            Some(entry) => entry.0 = when,
+            None => {
+                self.map.insert(key.clone(), (when, self.keys.len()));
+                self.keys.push(key);
+            }
+        }
+    }
+
+    /// 删除 key，数组中最后一个 key 移到空出来的位置
+    fn remove(&mut self, key: &[u8]) -> Option<SystemTime> {
+        let (when, index) = self.map.remove(key)?;
+        self.keys.swap_remove(index);
+        if let Some(moved) = self.keys.get(index) {
+            if let Some(entry) = self.map.get_mut(moved) {
+                entry.1 = index;
+            }
+        }
+        Some(when)
+    }
+
+    fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    fn values(&self) -> impl Iterator<Item = &SystemTime> {
+        self.map.values().map(|(when, _)| when)
+    }
+
+    fn clear(&mut self) {
+        self.map.clear();
+        self.keys.clear();
+    }
+}
+
+/// 一个逻辑数据库：所有的 key 和它们的过期时间
+#[derive(Debug, Clone)]
+pub struct Database {
+    dict: HashMap<Vec<u8>, RedisValue>,
+    expires: Expires,
+    // 惰性删除和主动过期删除的 key 总数
+    expired_keys: u64,
+    // 已经过期删除、还没有以 DEL 传播给 AOF 和副本的 key
+    unpropagated_expired: Vec<Vec<u8>>,
+    // 副本上为 false：不自己删除过期的 key，等主节点传播过来的 DEL
+    expire_enabled: bool,
+}
+
+impl Database {
+    pub fn new() -> Self {
+        Database {
+            dict: HashMap::new(),
+            expires: Expires::default(),
+            expired_keys: 0,
+            unpropagated_expired: Vec::new(),
+            expire_enabled: true,
+        }
+    }
+
+    /// 惰性删除：访问时发现 key 已过期就删除，返回是否已经过期
+    /// 副本上只判断不删除，过期的 key 对读取不可见，等主节点传播 DEL 再删除
+    fn expire_if_needed(&mut self, key: &[u8]) -> bool {
+        match self.expires.get(key) {
+            Some(when) if *when <= SystemTime::now() => {
+                if self.expire_enabled {
+                    self.dict.remove(key);
+                    self.expires.remove(key);
+                    self.expired_keys += 1;
+                    self.unpropagated_expired.push(key.to_vec());
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// 写入或删除之前丢掉已经过期的旧值，副本上也直接删除，但不计入统计也不传播
+    fn discard_if_expired(&mut self, key: &[u8]) -> bool {
+        if !self.expire_if_needed(key) {
+            return false;
+        }
+        self.dict.remove(key);
+        self.expires.remove(key);
+        true
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Option<&RedisValue> {
+        if self.expire_if_needed(key) {
+            return None;
+        }
+        self.dict.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut RedisValue> {
+        if self.expire_if_needed(key) {
+            return None;
+        }
+        self.dict.get_mut(key)
+    }
+
+    pub fn contains_key(&mut self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// 覆盖写入，同时清除原来的过期时间
+    pub fn set(&mut self, key: Vec<u8>, value: RedisValue) {
+        self.expires.remove(&key);
+        self.dict.insert(key, value);
+    }
+
+    /// 覆盖写入，保留原来的过期时间
+    pub fn set_keep_ttl(&mut self, key: Vec<u8>, value: RedisValue) {
+        self.discard_if_expired(&key);
+        self.dict.insert(key, value);
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        if self.discard_if_expired(key) {
+            return false;
+        }
+        self.expires.remove(key);
+        self.dict.remove(key).is_some()
+    }
+
+    pub fn set_expire(&mut self, key: &[u8], when: SystemTime) {
+        if self.dict.contains_key(key) {
+            self.expires.insert(key.to_vec(), when);
+        }
+    }
+
+    /// key 的过期时间，key 不存在或者没有设置过期时间时返回 None
+    pub fn get_expire(&mut self, key: &[u8]) -> Option<SystemTime> {
+        if self.expire_if_needed(key) {
+            return None;
+        }
+        self.expires.get(key).copied()
+    }
+
+    /// 删除 key 的过期时间，返回之前是否设置过
+    pub fn persist(&mut self, key: &[u8]) -> bool {
+        if self.expire_if_needed(key) {
+            return false;
+        }
+        self.expires.remove(key).is_some()
+    }
+
+    /// 取出 key 的值和过期时间，用于 MOVE
+    pub fn take(&mut self, key: &[u8]) -> Option<(RedisValue, Option<SystemTime>)> {
+        if self.discard_if_expired(key) {
+            return None;
+        }
+        let value = self.dict.remove(key)?;
+        Some((value, self.expires.remove(key)))
+    }
+
+    /// 写入值并设置过期时间，None 表示永不过期
+    pub fn insert(&mut self, key: Vec<u8>, value: RedisValue, expire: Option<SystemTime>) {
+        if let Some(when) = expire {
+            self.expires.insert(key.clone(), when);
+        } else {
+            self.expires.remove(&key);
+        }
+        self.dict.insert(key, value);
+    }
+
+    /// key 的数量，包含还没有被惰性删除的过期 key，和 Redis 的 DBSIZE 一致
+    pub fn len(&self) -> usize {
+        self.dict.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dict.is_empty()
+    }
+
+    /// 设置了过期时间的 key 数量
+    pub fn expires_len(&self) -> usize {
+        self.expires.len()
+    }
+
+    /// 带过期时间的 key 平均剩余存活毫秒数
+    pub fn avg_ttl(&self) -> u64 {
+        if self.expires.is_empty() {
+            return 0;
+        }
+        let now = SystemTime::now();
+        let total: u128 = self
+            .expires
+            .values()
+            .map(|when| when.duration_since(now).map(|d| d.as_millis()).unwrap_or(0))
+            .sum();
+        (total / self.expires.len() as u128) as u64
+    }
+
+    /// 随机抽样 count 个带过期时间的 key，删除其中已经过期的
+    /// 返回 (抽样数, 删除数)
+    fn expire_sample(&mut self, count: usize) -> (usize, usize) {
+        if !self.expire_enabled || self.expires.is_empty() {
+            return (0, 0);
+        }
+        let sampled = count.min(self.expires.len());
+        let now = SystemTime::now();
+        let mut rng = RandomState::new().build_hasher().finish() | 1;
+        let mut removed = 0;
+        for _ in 0..sampled {
+            if self.expires.is_empty() {
+                break;
+            }
+            // xorshift 生成随机下标
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            let key = &self.expires.keys[rng as usize % self.expires.len()];
+            if matches!(self.expires.get(key), Some(when) if *when <= now) {
+                let key = key.clone();
+                self.dict.remove(&key);
+                self.expires.remove(&key);
+                self.unpropagated_expired.push(key);
+                removed += 1;
+            }
+        }
+        self.expired_keys += removed as u64;
+        (sampled, removed)
+    }
+
+    /// 遍历所有没有过期的 key、值和过期时间，用于保存 RDB
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &RedisValue, Option<SystemTime>)> {
+        let now = SystemTime::now();
+        self.dict.iter().filter_map(move |(key, value)| match self.expires.get(key) {
+            Some(when) if *when <= now => None,
+            expire => Some((key, value, expire.copied())),
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.dict.clear();
+        self.expires.clear();
+    }
+
+    /// 返回所有匹配 glob 模式且没有过期的 key
+    pub fn keys(&mut self, pattern: &[u8]) -> Vec<Vec<u8>> {
+        let now = SystemTime::now();
+        self.dict
+            .keys()
+            .filter(|k| !matches!(self.expires.get(k), Some(when) if *when <= now))
+            .filter(|k| glob_match(pattern, k))
+            .cloned()
+            .collect()
+    }
+}
+
+/// 整个服务器的键空间
+#[derive(Debug, Clone)]
+pub struct Keyspace {
+    databases: Vec<Database>,
+    // 估计的还没有被删除的过期 key 百分比，按每轮抽样结果平滑
+    expired_stale_perc: f64,
+}
+
+impl Keyspace {
+    /// 创建 num 个空的逻辑数据库
+    pub fn new(num: usize) -> Self {
+        Keyspace {
+            databases: (0..num).map(|_| Database::new()).collect(),
+            expired_stale_perc: 0.0,
+        }
+    }
+
+    /// 是否由自己删除过期的 key，作为副本时关闭，过期的 key 由主节点传播的 DEL 删除
+    pub fn set_expire_enabled(&mut self, enabled: bool) {
+        for db in self.databases.iter_mut() {
+            db.expire_enabled = enabled;
+        }
+    }
+
+    /// 逻辑数据库的个数
+    pub fn len(&self) -> usize {
+        self.databases.len()
+    }
+
+    pub fn db(&mut self, index: usize) -> &mut Database {
+        &mut self.databases[index]
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Database> {
+        self.databases.iter()
+    }
+
+    /// 同时取得两个不同数据库的可变引用，用于 MOVE
+    pub fn two_dbs(&mut self, a: usize, b: usize) -> (&mut Database, &mut Database) {
+        assert_ne!(a, b);
+        if a < b {
+            let (left, right) = self.databases.split_at_mut(b);
+            (&mut left[a], &mut right[0])
+        } else {
+            let (left, right) = self.databases.split_at_mut(a);
+            (&mut right[0], &mut left[b])
+        }
+    }
+
+    pub fn swap(&mut self, a: usize, b: usize) {
+        self.databases.swap(a, b);
+    }
+
+    /// 取出所有数据库中过期删除、还没有传播的 key 和所在的数据库编号
+    pub fn take_expired(&mut self) -> Vec<(usize, Vec<u8>)> {
+        let mut expired = Vec::new();
+        for (index, db) in self.databases.iter_mut().enumerate() {
+            expired.extend(db.unpropagated_expired.drain(..).map(|key| (index, key)));
+        }
+        expired
+    }
+
+    pub fn flush_all(&mut self) {
+        for db in self.databases.iter_mut() {
+            db.clear();
+        }
+    }
+
+    /// 主动过期：在每个数据库中抽样带过期时间的 key 并删除过期的，
+    /// 过期比例较高时继续抽样，总耗时不超过 time_limit
+    pub fn active_expire_cycle(&mut self, time_limit: Duration) {
+        let start = Instant::now();
+        let (mut total_sampled, mut total_expired) = (0, 0);
+        for db in self.databases.iter_mut() {
+            loop {
+                let (sampled, expired) = db.expire_sample(ACTIVE_EXPIRE_KEYS_PER_LOOP);
+                total_sampled += sampled;
+                total_expired += expired;
+                if sampled == 0
+                    || expired * 100 <= sampled * ACTIVE_EXPIRE_ACCEPTABLE_STALE
+                    || start.elapsed() > time_limit
+                {
+                    break;
+                }
+            }
+            if start.elapsed() > time_limit {
+                break;
+            }
+        }
+        let current_perc = if total_sampled == 0 {
+            0.0
+        } else {
+            total_expired as f64 / total_sampled as f64
+        };
+        self.expired_stale_perc = current_perc * 0.05 + self.expired_stale_perc * 0.95;
+    }
+
+    /// INFO stats 段中和过期相关的字段
+    pub fn expire_stats(&self) -> String {
+        let expired_keys: u64 = self.databases.iter().map(|db| db.expired_keys).sum();
+        format!(
+            "expired_keys:{}\r\nexpired_stale_perc:{:.2}\r\n",
+            expired_keys,
+            self.expired_stale_perc * 100.0
+        )
+    }
+
+    /// INFO keyspace 段，只列出非空的数据库
+    pub fn info(&self) -> String {
+        let mut info = String::new();
+        for (index, db) in self.databases.iter().enumerate() {
+            if !db.is_empty() {
+                info.push_str(&format!(
+                    "db{}:keys={},expires={},avg_ttl={}\r\n",
+                    index,
+                    db.len(),
+                    db.expires_len(),
+                    db.avg_ttl()
+                ));
+            }
+        }
+        info
+    }
+}
+
+/// 当前的 unix 毫秒时间戳
+pub fn now_ms() -> i64 {
+    unix_ms(SystemTime::now())
+}
+
+pub fn unix_ms(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0)
+}
+
+/// unix 毫秒时间戳转成 SystemTime，负数按 0 处理
+pub fn from_unix_ms(ms: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(ms.max(0) as u64)
+}
+
+/// 读取字符串类型的 key，类型不对时返回 WRONGTYPE 错误
+pub fn get_string(db: &mut Database, key: &[u8]) -> Result<Option<Vec<u8>>, Value> {
+    match db.get(key) {
+        Some(RedisValue::String(s)) => Ok(Some(s.clone())),
+        Some(_) => Err(Value::Error(WRONGTYPE.to_string())),
+        None => Ok(None),
+    }
+}
+
+/// Redis 风格的 glob 匹配，支持 * ? [abc] [^a] [a-z] 和 \ 转义
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // 回溯点：最近一个 * 的位置和它匹配到的字符串位置
+    let mut star: Option<(usize, usize)> = None;
+    while s < string.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    star = Some((p, s));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    s += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(pattern, p, string[s]) {
+                        if matched {
+                            p = next;
+                            s += 1;
+                            continue;
+                        }
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == string[s] {
+                        p += 2;
+                        s += 1;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == string[s] {
+                        p += 1;
+                        s += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        match star {
+            Some((star_p, star_s)) => {
+                p = star_p + 1;
+                s = star_s + 1;
+                star = Some((star_p, star_s + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// 匹配 [...] 字符类，返回 (是否匹配, 字符类之后的位置)
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut p = start + 1;
+    let negate = p < pattern.len() && pattern[p] == b'^';
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            p += 1;
+            matched |= pattern[p] == c;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (lo, hi) = if pattern[p] <= pattern[p + 2] {
+                (pattern[p], pattern[p + 2])
+            } else {
+                (pattern[p + 2], pattern[p])
+            };
+            matched |= lo <= c && c <= hi;
+            p += 2;
+        } else {
+            matched |= pattern[p] == c;
+        }
+        p += 1;
+    }
+    if p >= pattern.len() {
+        return None;
+    }
+    Some((matched != negate, p + 1))
+}
@@ -5,11 +5,20 @@ mod config;
 mod duplication;
 mod slave_stream;
 mod stream;
+mod client;
+mod command;
+mod keyspace;
+mod rdb;
+mod crc64;
+mod persistence;
+mod aof;
+mod backlog;
+mod replication;
 
 use crate::resp::Value;
 use crate::db::RedisDb;
-use crate::slave_stream::Slaves;
-use crate::config::Config;
+use crate::slave_stream::{ReplicaSync, Slaves};
+use crate::config::{Config, ShutdownFlags};
 use tokio::net::{TcpListener, TcpStream};
 use std::net::{ToSocketAddrs, SocketAddr};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -18,6 +27,7 @@ use tokio::sync::Mutex;
 use std::sync::{Arc};
 use std::env;
 use tokio::time;
+use tokio::signal::unix::{signal, SignalKind};
 
 type DataStore = RedisDb;
 type RedisConfig = Arc<Mutex<Config>>;
@@ -27,54 +37,80 @@ async fn main() {
     // 读取输入的命令
     let args: Vec<String> = env::args().collect();
 
-    // 设置数据存储库和基础设置库
-    let database = RedisDb::new();
+    // 设置基础设置库
     let redisconfig = Arc::new(Mutex::new(Config::new()));
 
     // 默认值
     let mut dir = "./".to_string();
     let mut dbfilename = "dump.rdb".to_string();
     let mut port = "6379".to_string();
-    let mut replicaof = "".to_string();
+    let mut replicaof: Option<SocketAddr> = None;
+    let mut timeout = "0".to_string();
+    let mut databases = "16".to_string();
+    let mut rdbchecksum = "yes".to_string();
+    // 没有指定时使用默认的 save 规则
+    let mut save: Option<String> = None;
+    let mut stop_writes_on_bgsave_error = "yes".to_string();
+    let mut appendonly = "no".to_string();
+    let mut appendfilename = "appendonly.aof".to_string();
+    let mut appendfsync = "everysec".to_string();
+    let mut aof_load_truncated = "yes".to_string();
 
     // 解析命令行参数并更新基础设置库
     if args.len() > 1 {
         for i in 0..args.len() - 1 {
             match args[i].as_str() {
                 "--dir" => {
-                    if i + 1 < args.len() {
-                        dir = args[i + 1].clone();
-                    }
+                    dir = args[i + 1].clone();
                 }
                 "--dbfilename" => {
-                    if i + 1 < args.len() {
-                        dbfilename = args[i + 1].clone();
-                    }
+                    dbfilename = args[i + 1].clone();
                 }
                 "--port" => {
-                    if i + 1 < args.len() {
-                        port = args[i + 1].clone();
-                    }
+                    port = args[i + 1].clone();
+                }
+                "--databases" => {
+                    databases = args[i + 1].clone();
+                }
+                "--rdbchecksum" => {
+                    rdbchecksum = args[i + 1].clone();
+                }
+                "--save" => {
+                    save = Some(args[i + 1].clone());
+                }
+                "--stop-writes-on-bgsave-error" => {
+                    stop_writes_on_bgsave_error = args[i + 1].clone();
+                }
+                "--appendonly" => {
+                    appendonly = args[i + 1].to_lowercase();
+                }
+                "--appendfilename" => {
+                    appendfilename = args[i + 1].clone();
+                }
+                "--appendfsync" => {
+                    appendfsync = args[i + 1].clone();
+                }
+                "--aof-load-truncated" => {
+                    aof_load_truncated = args[i + 1].clone();
+                }
+                "--timeout" => {
+                    timeout = args[i + 1].clone();
                 }
                 "--replicaof"=>{
-                    if i + 1 < args.len() {
-                        let replicaof_ip_port = args[i + 1].clone();
-                        let parts: Vec<&str> = replicaof_ip_port.split_whitespace().collect();
-                        if parts.len() != 2 {
-                            println!("Replicaof set failed");
-                        }
-                        let host = parts[0];
-                        let port = parts[1];
-                    
-                        // 尝试将主机名解析为 IP 地址
-                        match format!("{}:{}", host, port).to_socket_addrs() {
-                            Ok(mut addrs) => {
-                                if let Some(addr) = addrs.next() {
-                                    replicaof=format!("{}:{}", addr.ip(), addr.port());
-                                }
-                            },
-                            Err(_) => println!("to_socket_addrs failed"),
-                        }
+                    let replicaof_ip_port = args[i + 1].clone();
+                    let parts: Vec<&str> = replicaof_ip_port.split_whitespace().collect();
+                    if parts.len() != 2 {
+                        println!("Replicaof set failed");
+                    }
+                    let host = parts[0];
+                    let port = parts[1];
+                
+                    // 尝试将主机名解析为 IP 地址
+                    match format!("{}:{}", host, port).to_socket_addrs() {
+                        Ok(mut addrs) => {
+                            replicaof = addrs.next();
+                        },
+                        Err(_) => println!("to_socket_addrs failed"),
                     }
                 }
                 _ => {}
@@ -88,12 +124,60 @@ async fn main() {
         config.insert("dir".to_string(), dir.clone());
         config.insert("dbfilename".to_string(), dbfilename.clone());
         config.insert("port".to_string(), port.clone());
-        if replicaof != "".to_string(){
-            config.set_rcliinfo("role".to_string(), "slave".to_string());
+        match databases.parse::<usize>() {
+            Ok(num) if num > 0 => config.set_databases(num),
+            _ => println!("Invalid databases {:?}, using the default", databases),
+        }
+        if let Value::Error(e) = config.config_set("timeout".to_string(), timeout.clone()).await {
+            println!("Invalid timeout {:?}: {}", timeout, e);
+        }
+        if let Value::Error(e) = config.config_set("rdbchecksum".to_string(), rdbchecksum.clone()).await {
+            println!("Invalid rdbchecksum {:?}: {}", rdbchecksum, e);
+        }
+        if let Some(save) = save {
+            if let Value::Error(e) = config.config_set("save".to_string(), save.clone()).await {
+                println!("Invalid save {:?}: {}", save, e);
+            }
+        }
+        if let Value::Error(e) = config.config_set("stop-writes-on-bgsave-error".to_string(), stop_writes_on_bgsave_error.clone()).await {
+            println!("Invalid stop-writes-on-bgsave-error {:?}: {}", stop_writes_on_bgsave_error, e);
+        }
+        if appendonly != "yes" && appendonly != "no" {
+            println!("Invalid appendonly {:?}, using no", appendonly);
+            appendonly = "no".to_string();
         }
-        config.load_rdb();
-        config.slave_loop().await;
+        config.insert("appendonly".to_string(), appendonly.clone());
+        config.insert("appendfilename".to_string(), appendfilename.clone());
+        if let Value::Error(e) = config.config_set("appendfsync".to_string(), appendfsync.clone()).await {
+            println!("Invalid appendfsync {:?}: {}", appendfsync, e);
+        }
+        if let Value::Error(e) = config.config_set("aof-load-truncated".to_string(), aof_load_truncated.clone()).await {
+            println!("Invalid aof-load-truncated {:?}: {}", aof_load_truncated, e);
+        }
+        // 开启 AOF 时以 AOF 为准，不再加载 RDB
+        // RDB 损坏时拒绝启动，避免之后用空数据覆盖原文件
+        if appendonly != "yes" {
+            if let Err(e) = config.load_rdb() {
+                println!("Failed loading RDB file {}", e);
+                std::process::exit(1);
+            }
+        }
+        config.client_reaper_loop().await;
     }
+    if appendonly == "yes" {
+        // 回放要走命令分发，需要在释放配置锁之后进行
+        if let Err(e) = Config::load_append_only_file(redisconfig.clone()).await {
+            println!("Failed loading append only file {}", e);
+            std::process::exit(1);
+        }
+        if let Err(e) = redisconfig.lock().await.open_append_only_file() {
+            println!("Can't open the append-only file {}", e);
+            std::process::exit(1);
+        }
+    }
+    Config::active_expire_loop(redisconfig.clone());
+    Config::aof_fsync_loop(redisconfig.clone());
+    Config::save_rules_loop(redisconfig.clone());
     // 设置 IP 地址和端口
     let ip = "127.0.0.1".to_string();
     let ip_port = format!("{}:{}", ip, port);
@@ -101,25 +185,54 @@ async fn main() {
     // 绑定监听地址
     let listener = TcpListener::bind(ip_port).await.unwrap();
 
-    if !replicaof.is_empty() {
-        let redisconfig_clone = Arc::clone(&redisconfig);
-        let _ = perform_replication_handshake(&replicaof,database.clone(),redisconfig_clone).await;
+    if let Some(master_addr) = replicaof {
+        Config::replicaof(Arc::clone(&redisconfig), Some(master_addr)).await;
     }
 
+    let mut shutting_down = redisconfig.lock().await.shutdown_watch();
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Failed to install SIGINT handler");
     loop {
-        let stream = listener.accept().await;
-        match stream {
-            Ok((stream, _)) => {
+        // 关闭流程进行中暂停接受新连接，关闭失败后恢复
+        if *shutting_down.borrow() {
+            let _ = shutting_down.changed().await;
+            continue;
+        }
+        tokio::select! {
+            stream = listener.accept() => match stream {
+                Ok((stream, _)) => {
                     let redisconfig_clone = Arc::clone(&redisconfig);
                     tokio::spawn(async move {
                         let db = DataStore::new();
                         handle_conn(stream,db,redisconfig_clone).await
-                        });
+                    });
                 }
                 Err(e) => {
                     println!("error: {}", e);
-
                 }
+            },
+            _ = shutting_down.changed() => {}
+            _ = sigterm.recv() => shutdown_on_signal("SIGTERM", &redisconfig).await,
+            _ = sigint.recv() => shutdown_on_signal("SIGINT", &redisconfig).await,
+        }
+    }
+}
+
+/// 收到信号后按默认选项关闭，失败时继续运行
+async fn shutdown_on_signal(name: &str, redisconfig: &RedisConfig) {
+    println!("Received {} scheduling shutdown...", name);
+    // 和 SHUTDOWN 命令一样先拿写锁，正在执行的写命令传播完之后再收尾
+    let write_lock = redisconfig.lock().await.write_lock();
+    let write_guard = write_lock.lock_owned().await;
+    let mut config = redisconfig.lock().await;
+    match config.prepare_shutdown(ShutdownFlags::default(), &write_guard).await {
+        Ok(()) => {
+            println!("Redis is now ready to exit, bye bye...");
+            std::process::exit(0);
+        }
+        Err(e) => {
+            println!("{}", e);
+            println!("{} received but errors trying to shut down the server, check the logs for more information", name);
         }
     }
 }
@@ -128,37 +241,67 @@ async fn handle_conn(stream: TcpStream, mut db: DataStore, redisconfig: RedisCon
     let addr = stream.peer_addr().unwrap();
     let mut handler = resp::RespHandler::new(stream);
     println!("Starting read loop");
+    let client = {
+        let mut redisconfig_lock = redisconfig.lock().await;
+        redisconfig_lock.register_client(db.id(), addr).await
+    };
     let mut multi_cmd_flag = false;
+    // MULTI 中有命令入队失败时，EXEC 直接放弃整个事务
+    let mut multi_cmd_error = false;
     let mut multi_cmd_vec: Vec<(String, Vec<Value>)> = Vec::new();
     loop {
-        let value = handler.read_value().await.unwrap();
+        let read = tokio::select! {
+            read = handler.read_request() => read,
+            // 空闲超时，直接关闭连接
+            _ = client.killed() => break,
+        };
+        let value = match read {
+            Ok(value) => value,
+            Err(e) => {
+                // 协议错误时回复错误信息并关闭连接
+                println!("Closing connection {}: {:?}", addr, e);
+                let _ = handler.write_value(Value::Error(format!("ERR {}", e.root_cause()))).await;
+                break;
+            }
+        };
         println!("Got value {:?}", value);
+        client.set_in_command(true);
 
         // 提前声明变量
         let (command, args): (String, Vec<Value>);
-        let mut response = Value::BulkString(None);
+        let response;
 
         if let Some(v) = value.clone() {
-            let extracted = extract_command(v).unwrap();
-            command = extracted.0; // 初始化变量
-            args = extracted.1; // 初始化变量
+            (command, args) = match extract_command(v) {
+                Ok(extracted) => extracted,
+                Err(e) => {
+                    let _ = handler.write_value(Value::Error(format!("ERR {}", e))).await;
+                    client.set_in_command(false);
+                    continue;
+                }
+            };
 
             //检查command是不是multi
             match command.to_lowercase().as_str(){
                 "multi" => {
-                    multi_cmd_flag=true;
-                    response = Value::SimpleString("OK".to_string());
+                    if multi_cmd_flag{
+                        response = Value::Error("ERR MULTI calls can not be nested".to_string());
+                    }else{
+                        multi_cmd_flag=true;
+                        multi_cmd_error=false;
+                        response = Value::SimpleString("OK".to_string());
+                    }
+                }
+                "exec" if multi_cmd_flag && multi_cmd_error => {
+                    multi_cmd_flag=false;
+                    multi_cmd_vec.clear();
+                    response = Value::Error("EXECABORT Transaction discarded because of previous errors.".to_string());
                 }
                 "exec" => {
                     if multi_cmd_flag{
                         multi_cmd_flag=false;
-                        let mut multi_cmd_response_vec = Vec::new();
-                        for  (cmd, cmd_args) in &multi_cmd_vec {
-                            response = db.handle_command(cmd.to_string(), cmd_args.clone(), redisconfig.clone(),addr).await;
-                            multi_cmd_response_vec.push(response);
-                        }
-                        multi_cmd_vec.clear();
-                        response = Value::Array(multi_cmd_response_vec);
+                        let cmds = std::mem::take(&mut multi_cmd_vec);
+                        response = db.exec(cmds, redisconfig.clone(), addr).await;
                     }else{
                         response = Value::Error("ERR EXEC without MULTI".to_string());
                     }
@@ -175,8 +318,17 @@ async fn handle_conn(stream: TcpStream, mut db: DataStore, redisconfig: RedisCon
                 }
                 _ => {
                     if multi_cmd_flag{
-                        multi_cmd_vec.push((command.clone(),args.clone()));
-                        response = Value::SimpleString("QUEUED".to_string());
+                        // 入队前检查命令和参数个数
+                        match command::validate(&command, &args) {
+                            Ok(_) => {
+                                multi_cmd_vec.push((command.clone(),args.clone()));
+                                response = Value::SimpleString("QUEUED".to_string());
+                            }
+                            Err(e) => {
+                                multi_cmd_error = true;
+                                response = e;
+                            }
+                        }
                     }else{
                         let respon = db.handle_command(command.clone(), args.clone(), redisconfig.clone(),addr).await;
                         response=respon
@@ -185,38 +337,36 @@ async fn handle_conn(stream: TcpStream, mut db: DataStore, redisconfig: RedisCon
             }
         }
         else{
-            if multi_cmd_flag{
-                continue;
-            }else{
-                break;
-            }
+            // 连接已关闭
+            break;
         }
-        // 记录处理的命令
-
-        {
+        println!("{:?}",response);
+        // HELLO 可能切换了协议版本，回复按新的协议编码
+        handler.set_protocol(db.protocol());
+        client.set_in_command(false);
+        if let Err(e) = handler.write_value(response).await {
+            println!("Closing connection {}: {:?}", addr, e);
+            break;
+        }
+        //处理同步信息，PSYNC 之后这个连接交给复制流使用
+        if let Some(sync) = db.take_replica_sync() {
             let mut redisconfig_lock=redisconfig.lock().await;
-            if let Some(v) = value.clone(){
-                redisconfig_lock.rcliinfo_track_cmd(v).await;
-            }
+            redisconfig_lock.add_slave_resphandler(handler, sync).await;
+            break;
         }
-        println!("{:?}",response);
-        handler.write_value(response).await.unwrap();
-        //处理同步信息
-        match command.to_lowercase().as_str() {
-            "psync" => {
-                let mut redisconfig_lock=redisconfig.lock().await;
-                redisconfig_lock.add_slave_resphandler(handler).await;
-                break;
-            }
-            _ => {}
-        };
     }
+    let mut redisconfig_lock = redisconfig.lock().await;
+    // PSYNC 的回复没有发出去时丢掉为它缓存的复制流
+    if let Some(ReplicaSync::Full(_, _, sync_id)) = db.take_replica_sync() {
+        redisconfig_lock.slaves_handler().write().await.cancel_full_sync(sync_id);
+    }
+    redisconfig_lock.unregister_client(client.id).await;
 }
 fn extract_command(value: Value) -> Result<(String, Vec<Value>)> {
     match value {
-        Value::Array(a) => {
+        Value::Array(a) if !a.is_empty() => {
             Ok((
-                unpack_bulk_str(a.first().unwrap().clone())?,
+                unpack_bulk_str(a[0].clone())?,
                 a.into_iter().skip(1).collect(),
             ))
         },
@@ -225,97 +375,8 @@ fn extract_command(value: Value) -> Result<(String, Vec<Value>)> {
 }
 fn unpack_bulk_str(value: Value) -> Result<String> {
     match value {
-        Value::BulkString(Some(s)) => Ok(s),
+        // 命令名总是 ASCII，参数则保持原始字节
+        Value::BulkString(Some(s)) => Ok(String::from_utf8(s)?),
         _ => Err(anyhow::anyhow!("Expected command to be a bulk string"))
     }
 }
-
-async fn perform_replication_handshake(replicaof: &str,mut db:DataStore,redisconfig: RedisConfig) -> Result<()> {
-    // 尝试连接到主服务器
-    let master_addr: SocketAddr = replicaof.parse().expect("Failed to parse master address");
-    let master_stream = TcpStream::connect(master_addr.clone()).await.expect("Failed to connect to master");
-
-    let mut handler = resp::RespHandler::new(master_stream);
-
-    // Stage 1：sent ping to master
-    handler.write_value(Value::Array(vec![Value::BulkString(Some("PING".to_string()))])).await?;
-    let response = handler.read_value().await?.ok_or_else(|| anyhow::anyhow!("Failed to read response"))?;
-    println!("Master response: {}", response);
-    time::sleep(time::Duration::from_millis(20)).await;
-
-    // Stage2: The replica sends twice to the master (This stageREPLCONF)
-    {
-        let config = redisconfig.lock().await;
-        handler.write_value(Value::Array(vec![
-            Value::BulkString(Some("REPLCONF".to_string())),
-            Value::BulkString(Some("listening-port".to_string())),
-            Value::BulkString(Some(config.get_config("port".to_string()).to_string())),
-        ])).await?;
-    }
-    let response = handler.read_value().await?.ok_or_else(|| anyhow::anyhow!("Failed to read response"))?;
-    println!("Master response: {}", response);
-
-    handler.write_value(Value::Array(vec![
-        Value::BulkString(Some("REPLCONF".to_string())),
-        Value::BulkString(Some("capa".to_string())),
-        Value::BulkString(Some("psync2".to_string().to_string())),
-    ])).await?;
-
-    let response = handler.read_value().await?.ok_or_else(|| anyhow::anyhow!("Failed to read response"))?;
-    println!("Master response: {}", response);
-
-
-
-    // TODO: code needs to be refactored
-    handler.write_value(Value::Array(vec![
-        Value::BulkString(Some("PSYNC".to_string())),
-        Value::BulkString(Some("?".to_string())),
-        Value::BulkString(Some("-1".to_string().to_string())),
-    ])).await?;
-
-    //TODO: realize command execution
-    tokio::spawn(async move {
-        loop {
-            let values = handler.slave_read_value().await.unwrap();
-            println!("Got values {:?}", values);
-    
-            // 提前声明变量
-            let (mut command, mut args): (String, Vec<Value>);
-            
-            if let Some(value) = values {
-                for v in value.iter() {
-                    match v{
-                        Value::Array(_) =>{
-                            let extracted = extract_command(v.clone()).unwrap();
-                            command = extracted.0; // 初始化变量
-                            args = extracted.1; // 初始化变量
-        
-                            let respon = db.handle_command(command.clone(), args.clone(), redisconfig.clone(),master_addr.clone()).await;
-                            println!("{:?}", respon);
-                            match command.to_lowercase().as_str(){
-                                "replconf" => {
-                                    handler.write_value(respon).await;
-                                }
-                                _ => {}
-                            }
-                            
-        
-                            //记录处理的命令
-                            {
-                                let mut redisconfig_lock=redisconfig.lock().await;
-                                redisconfig_lock.rcliinfo_track_slave_cmd_offset(v.clone().serialize().len());
-                            }
-                        }
-                        _=>{}
-                    }
-                }
-
-            } else {
-                break;
-            };
-        };
-        // TODO：Track command offset 
-    });
-
-    Ok(())
-}
\ No newline at end of file
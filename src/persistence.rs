@@ -0,0 +1,94 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// RDB 持久化的运行状态，BGSAVE 任务完成后也要更新，所以单独共享
+#[derive(Debug)]
+pub struct RdbState {
+    // 最近一次保存成功的 unix 秒
+    pub lastsave: u64,
+    pub bgsave_in_progress: bool,
+    // 上次保存之后成功执行的写命令数
+    pub dirty: u64,
+    // BGSAVE 开始时的 dirty，保存成功后从 dirty 中减去
+    pub dirty_before_bgsave: u64,
+    pub last_bgsave_ok: bool,
+    // 最近一次尝试 BGSAVE 的 unix 秒，失败后至少隔一段时间再按规则重试
+    pub last_bgsave_try: u64,
+}
+
+impl RdbState {
+    pub fn new() -> Self {
+        RdbState {
+            lastsave: unix_secs(),
+            bgsave_in_progress: false,
+            dirty: 0,
+            dirty_before_bgsave: 0,
+            last_bgsave_ok: true,
+            last_bgsave_try: 0,
+        }
+    }
+
+    /// 是否满足某条 save 规则：距离上次保存超过 seconds 秒且至少有 changes 次修改
+    /// 上次 BGSAVE 失败时，至少隔 CONFIG_BGSAVE_RETRY_DELAY 秒再重试
+    pub fn should_bgsave(&self, save_params: &[(u64, u64)]) -> bool {
+        if self.bgsave_in_progress {
+            return false;
+        }
+        let now = unix_secs();
+        let retry_allowed = self.last_bgsave_ok || now.saturating_sub(self.last_bgsave_try) > CONFIG_BGSAVE_RETRY_DELAY;
+        retry_allowed
+            && save_params
+                .iter()
+                .any(|(seconds, changes)| self.dirty >= *changes && now.saturating_sub(self.lastsave) > *seconds)
+    }
+
+    /// INFO persistence 段
+    pub fn info(&self) -> String {
+        format!(
+            "loading:0\r\nrdb_changes_since_last_save:{}\r\nrdb_bgsave_in_progress:{}\r\nrdb_last_save_time:{}\r\nrdb_last_bgsave_status:{}\r\n",
+            self.dirty,
+            self.bgsave_in_progress as u8,
+            self.lastsave,
+            if self.last_bgsave_ok { "ok" } else { "err" }
+        )
+    }
+}
+
+// BGSAVE 失败后按规则重试的最小间隔（秒）
+const CONFIG_BGSAVE_RETRY_DELAY: u64 = 5;
+
+/// 解析 save 配置，例如 "900 1 300 10"，空字符串表示关闭自动保存
+pub fn parse_save_params(value: &str) -> Option<Vec<(u64, u64)>> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    if !parts.len().is_multiple_of(2) {
+        return None;
+    }
+    parts
+        .chunks_exact(2)
+        .map(|pair| match (pair[0].parse::<u64>(), pair[1].parse::<u64>()) {
+            (Ok(seconds), Ok(changes)) => Some((seconds, changes)),
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn unix_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// 先写到同目录下的临时文件并 fsync，再 rename 成目标文件，保证不会留下写了一半的 RDB
+pub fn write_rdb_file(dir: &str, filename: &str, data: &[u8]) -> io::Result<()> {
+    let tmp_path = Path::new(dir).join(format!("temp-{}.rdb", std::process::id()));
+    let result = (|| {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, Path::new(dir).join(filename))
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
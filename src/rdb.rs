@@ -0,0 +1,1324 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Cursor, Read};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use crate::crc64::crc64;
+use crate::keyspace::{Keyspace, RedisValue};
+use crate::resp::Value;
+use crate::stream::{Fields, Stream, StreamId};
+
+// 支持读取的最高 RDB 版本
+pub const RDB_VERSION: u32 = 11;
+// 从这个版本开始文件末尾带 8 字节的 CRC64 校验和
+const RDB_CHECKSUM_VERSION: u32 = 5;
+
+// 值类型
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_LIST_ZIPLIST: u8 = 10;
+const RDB_TYPE_SET_INTSET: u8 = 11;
+const RDB_TYPE_ZSET_ZIPLIST: u8 = 12;
+const RDB_TYPE_HASH_ZIPLIST: u8 = 13;
+const RDB_TYPE_LIST_QUICKLIST: u8 = 14;
+const RDB_TYPE_STREAM_LISTPACKS: u8 = 15;
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+// 操作码
+const RDB_OPCODE_FUNCTION2: u8 = 0xF5;
+const RDB_OPCODE_MODULE_AUX: u8 = 0xF7;
+const RDB_OPCODE_IDLE: u8 = 0xF8;
+const RDB_OPCODE_FREQ: u8 = 0xF9;
+const RDB_OPCODE_AUX: u8 = 0xFA;
+const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const RDB_OPCODE_EXPIRETIME: u8 = 0xFD;
+const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+const RDB_OPCODE_EOF: u8 = 0xFF;
+
+// 长度编码的高两位
+const RDB_6BITLEN: u8 = 0;
+const RDB_14BITLEN: u8 = 1;
+const RDB_32BITLEN: u8 = 0x80;
+const RDB_64BITLEN: u8 = 0x81;
+const RDB_ENCVAL: u8 = 3;
+
+// 特殊编码的字符串
+const RDB_ENC_INT8: u64 = 0;
+const RDB_ENC_INT16: u64 = 1;
+const RDB_ENC_INT32: u64 = 2;
+const RDB_ENC_LZF: u64 = 3;
+
+// quicklist 2 的节点类型
+const QUICKLIST_NODE_CONTAINER_PLAIN: u64 = 1;
+const QUICKLIST_NODE_CONTAINER_PACKED: u64 = 2;
+
+// 流条目的标志位
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// 解析整个 RDB 文件，key 放进 keyspace 对应的数据库，AUX 字段放进 metadata
+/// verify_checksum 为 false 时（rdbchecksum no）不校验文件末尾的 CRC64
+/// 出错时错误信息里带上解析失败的字节偏移
+pub fn load(data: &[u8], keyspace: &mut Keyspace, metadata: &mut HashMap<String, Value>, verify_checksum: bool) -> io::Result<()> {
+    let mut reader = RdbReader::new(data);
+    load_from_reader(&mut reader, keyspace, metadata, verify_checksum).map_err(|e| {
+        let kind = match e.kind() {
+            io::ErrorKind::UnexpectedEof => io::ErrorKind::InvalidData,
+            kind => kind,
+        };
+        io::Error::new(kind, format!("{} (at offset {})", e, reader.position()))
+    })
+}
+
+fn load_from_reader(
+    reader: &mut RdbReader,
+    keyspace: &mut Keyspace,
+    metadata: &mut HashMap<String, Value>,
+    verify_checksum: bool,
+) -> io::Result<()> {
+    let version = reader.read_header()?;
+    println!("Loading RDB version {}", version);
+
+    // 0xFE 之前的 key 都放在 0 号数据库
+    let mut db_index = 0;
+    // 0xFC/0xFD 读到的过期时间属于紧跟着的那个 key
+    let mut expire_at: Option<SystemTime> = None;
+    loop {
+        match reader.read_u8()? {
+            RDB_OPCODE_AUX => {
+                let key = reader.read_string()?;
+                let value = reader.read_string()?;
+                metadata.insert(String::from_utf8_lossy(&key).to_string(), Value::BulkString(Some(value)));
+            }
+            RDB_OPCODE_SELECTDB => {
+                db_index = reader.read_length()? as usize;
+                if db_index >= keyspace.len() {
+                    return Err(invalid(format!(
+                        "DB index {} is out of range, databases is {}",
+                        db_index,
+                        keyspace.len()
+                    )));
+                }
+            }
+            RDB_OPCODE_RESIZEDB => {
+                // 哈希表大小和过期哈希表大小，只用来预分配
+                reader.read_length()?;
+                reader.read_length()?;
+            }
+            RDB_OPCODE_EXPIRETIME => {
+                let secs = reader.read_u32_le()?;
+                expire_at = Some(UNIX_EPOCH + Duration::from_secs(secs.into()));
+            }
+            RDB_OPCODE_EXPIRETIME_MS => {
+                let ms = reader.read_u64_le()?;
+                expire_at = Some(UNIX_EPOCH + Duration::from_millis(ms));
+            }
+            RDB_OPCODE_IDLE => {
+                // LRU 空闲时间，没有淘汰策略，跳过
+                reader.read_length()?;
+            }
+            RDB_OPCODE_FREQ => {
+                // LFU 访问频率，跳过
+                reader.read_u8()?;
+            }
+            RDB_OPCODE_MODULE_AUX | RDB_OPCODE_FUNCTION2 => {
+                return Err(invalid("Modules and functions in RDB are not supported"));
+            }
+            RDB_OPCODE_EOF => break,
+            value_type => {
+                let key = reader.read_string()?;
+                let value = reader.read_object(value_type)?;
+                // 加载时已经过期的 key 直接丢弃
+                if matches!(expire_at, Some(when) if when <= SystemTime::now()) {
+                    println!("Skipping expired key {:?}", String::from_utf8_lossy(&key));
+                } else {
+                    keyspace.db(db_index).insert(key, value, expire_at);
+                }
+                expire_at = None;
+            }
+        }
+    }
+
+    if version < RDB_CHECKSUM_VERSION {
+        return Ok(());
+    }
+    let checksum_offset = reader.position() as usize;
+    let expected = reader.read_u64_le()?;
+    if !verify_checksum {
+        println!("WARNING: RDB checksum verification is disabled (rdbchecksum no), data was loaded without verification");
+    } else if expected == 0 {
+        // 保存时关闭了校验和，文件里写的是 0
+        println!("RDB file was saved with checksum disabled: no check performed.");
+    } else {
+        let actual = crc64(0, &reader.data()[..checksum_offset]);
+        if actual != expected {
+            reader.set_position(checksum_offset as u64);
+            return Err(invalid(format!(
+                "Wrong RDB checksum expected: ({:x}) got: ({:x})",
+                expected, actual
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// 按 RDB 格式从字节流中读取长度、字符串和各种类型的值
+pub struct RdbReader<'a> {
+    cursor: Cursor<&'a [u8]>,
+}
+
+impl<'a> RdbReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        RdbReader { cursor: Cursor::new(data) }
+    }
+
+    /// 检查 "REDIS" 魔数并返回版本号
+    pub fn read_header(&mut self) -> io::Result<u32> {
+        let mut magic = [0u8; 9];
+        self.cursor.read_exact(&mut magic)?;
+        if &magic[..5] != b"REDIS" {
+            return Err(invalid("Wrong signature trying to load DB from file"));
+        }
+        let version = std::str::from_utf8(&magic[5..])
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .ok_or_else(|| invalid("Invalid RDB version"))?;
+        if version == 0 || version > RDB_VERSION {
+            return Err(invalid(format!("Can't handle RDB format version {}", version)));
+        }
+        Ok(version)
+    }
+
+    pub fn position(&self) -> u64 {
+        self.cursor.position()
+    }
+
+    fn set_position(&mut self, position: u64) {
+        self.cursor.set_position(position);
+    }
+
+    fn data(&self) -> &'a [u8] {
+        self.cursor.get_ref()
+    }
+
+    fn read_u8(&mut self) -> io::Result<u8> {
+        self.cursor.read_u8()
+    }
+
+    fn read_u32_le(&mut self) -> io::Result<u32> {
+        self.cursor.read_u32::<LittleEndian>()
+    }
+
+    fn read_u64_le(&mut self) -> io::Result<u64> {
+        self.cursor.read_u64::<LittleEndian>()
+    }
+
+    fn read_bytes(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        // 先检查剩余长度，避免损坏的长度字段导致分配超大的缓冲区
+        let remaining = self.cursor.get_ref().len() as u64 - self.cursor.position().min(self.cursor.get_ref().len() as u64);
+        if len as u64 > remaining {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Unexpected end of RDB data"));
+        }
+        let mut buf = vec![0; len];
+        self.cursor.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// 读取长度，第二个返回值表示是否是特殊编码的字符串
+    fn read_length_with_encoding(&mut self) -> io::Result<(u64, bool)> {
+        let first = self.read_u8()?;
+        match first >> 6 {
+            RDB_6BITLEN => Ok(((first & 0x3F) as u64, false)),
+            RDB_14BITLEN => {
+                let next = self.read_u8()?;
+                Ok(((((first & 0x3F) as u64) << 8) | next as u64, false))
+            }
+            RDB_ENCVAL => Ok(((first & 0x3F) as u64, true)),
+            _ => match first {
+                RDB_32BITLEN => Ok((self.cursor.read_u32::<BigEndian>()? as u64, false)),
+                RDB_64BITLEN => Ok((self.cursor.read_u64::<BigEndian>()?, false)),
+                _ => Err(invalid(format!("Unknown length encoding {} in RDB", first))),
+            },
+        }
+    }
+
+    pub fn read_length(&mut self) -> io::Result<u64> {
+        match self.read_length_with_encoding()? {
+            (len, false) => Ok(len),
+            (_, true) => Err(invalid("Unexpected encoded value where a length was expected")),
+        }
+    }
+
+    /// 读取字符串，整数编码的字符串转成十进制文本
+    pub fn read_string(&mut self) -> io::Result<Vec<u8>> {
+        let (len, encoded) = self.read_length_with_encoding()?;
+        if !encoded {
+            return self.read_bytes(len as usize);
+        }
+        match len {
+            RDB_ENC_INT8 => Ok((self.read_u8()? as i8).to_string().into_bytes()),
+            RDB_ENC_INT16 => Ok(self.cursor.read_i16::<LittleEndian>()?.to_string().into_bytes()),
+            RDB_ENC_INT32 => Ok(self.cursor.read_i32::<LittleEndian>()?.to_string().into_bytes()),
+            RDB_ENC_LZF => {
+                let compressed_len = self.read_length()? as usize;
+                let len = self.read_length()? as usize;
+                let compressed = self.read_bytes(compressed_len)?;
+                lzf_decompress(&compressed, len)
+            }
+            _ => Err(invalid(format!("Unknown RDB string encoding type {}", len))),
+        }
+    }
+
+    /// 老格式 ZSET 的分数：一个字节的长度加文本，253/254/255 分别表示 nan/+inf/-inf
+    fn read_double_string(&mut self) -> io::Result<f64> {
+        match self.read_u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => parse_score(&self.read_bytes(len as usize)?),
+        }
+    }
+
+    /// 读取一个 value_type 类型的值
+    pub fn read_object(&mut self, value_type: u8) -> io::Result<RedisValue> {
+        match value_type {
+            RDB_TYPE_STRING => Ok(RedisValue::String(self.read_string()?)),
+            RDB_TYPE_LIST => {
+                let len = self.read_length()?;
+                let mut list = VecDeque::new();
+                for _ in 0..len {
+                    list.push_back(self.read_string()?);
+                }
+                Ok(RedisValue::List(list))
+            }
+            RDB_TYPE_SET => {
+                let len = self.read_length()?;
+                let mut set = HashSet::new();
+                for _ in 0..len {
+                    set.insert(self.read_string()?);
+                }
+                Ok(RedisValue::Set(set))
+            }
+            RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+                let len = self.read_length()?;
+                let mut zset = HashMap::new();
+                for _ in 0..len {
+                    let member = self.read_string()?;
+                    let score = if value_type == RDB_TYPE_ZSET_2 {
+                        f64::from_bits(self.read_u64_le()?)
+                    } else {
+                        self.read_double_string()?
+                    };
+                    zset.insert(member, score);
+                }
+                Ok(RedisValue::ZSet(zset))
+            }
+            RDB_TYPE_HASH => {
+                let len = self.read_length()?;
+                let mut hash = HashMap::new();
+                for _ in 0..len {
+                    let field = self.read_string()?;
+                    let value = self.read_string()?;
+                    hash.insert(field, value);
+                }
+                Ok(RedisValue::Hash(hash))
+            }
+            RDB_TYPE_LIST_ZIPLIST => Ok(RedisValue::List(parse_ziplist(&self.read_string()?)?.into())),
+            RDB_TYPE_SET_INTSET => Ok(RedisValue::Set(parse_intset(&self.read_string()?)?.into_iter().collect())),
+            RDB_TYPE_SET_LISTPACK => Ok(RedisValue::Set(parse_listpack(&self.read_string()?)?.into_iter().collect())),
+            RDB_TYPE_ZSET_ZIPLIST | RDB_TYPE_ZSET_LISTPACK => {
+                let blob = self.read_string()?;
+                let entries = if value_type == RDB_TYPE_ZSET_ZIPLIST { parse_ziplist(&blob)? } else { parse_listpack(&blob)? };
+                let mut zset = HashMap::new();
+                for (member, score) in pairs(entries)? {
+                    zset.insert(member, parse_score(&score)?);
+                }
+                Ok(RedisValue::ZSet(zset))
+            }
+            RDB_TYPE_HASH_ZIPLIST | RDB_TYPE_HASH_LISTPACK => {
+                let blob = self.read_string()?;
+                let entries = if value_type == RDB_TYPE_HASH_ZIPLIST { parse_ziplist(&blob)? } else { parse_listpack(&blob)? };
+                Ok(RedisValue::Hash(pairs(entries)?.into_iter().collect()))
+            }
+            RDB_TYPE_LIST_QUICKLIST | RDB_TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.read_length()?;
+                let mut list = VecDeque::new();
+                for _ in 0..nodes {
+                    if value_type == RDB_TYPE_LIST_QUICKLIST {
+                        list.extend(parse_ziplist(&self.read_string()?)?);
+                        continue;
+                    }
+                    match self.read_length()? {
+                        QUICKLIST_NODE_CONTAINER_PLAIN => list.push_back(self.read_string()?),
+                        QUICKLIST_NODE_CONTAINER_PACKED => list.extend(parse_listpack(&self.read_string()?)?),
+                        container => return Err(invalid(format!("Unknown quicklist node container {}", container))),
+                    }
+                }
+                Ok(RedisValue::List(list))
+            }
+            RDB_TYPE_STREAM_LISTPACKS | RDB_TYPE_STREAM_LISTPACKS_2 | RDB_TYPE_STREAM_LISTPACKS_3 => {
+                Ok(RedisValue::Stream(self.read_stream(value_type)?))
+            }
+            _ => Err(invalid(format!("Unknown RDB value type {}", value_type))),
+        }
+    }
+
+    /// 读取流：若干个 listpack 节点，之后是元数据和消费者组，消费者组读出来后丢弃
+    fn read_stream(&mut self, value_type: u8) -> io::Result<Stream> {
+        let mut stream = Stream::new();
+        let nodes = self.read_length()?;
+        for _ in 0..nodes {
+            let master_id = self.read_string()?;
+            if master_id.len() != 16 {
+                return Err(invalid("Stream node key is not a valid stream ID"));
+            }
+            let master_id = StreamId {
+                ms: u64::from_be_bytes(master_id[..8].try_into().unwrap()),
+                seq: u64::from_be_bytes(master_id[8..].try_into().unwrap()),
+            };
+            let listpack = parse_listpack(&self.read_string()?)?;
+            for (id, fields) in parse_stream_listpack(master_id, listpack)? {
+                stream.load_entry(id, fields);
+            }
+        }
+
+        // 条目数量和最后一个 ID
+        self.read_length()?;
+        let last_id = StreamId { ms: self.read_length()?, seq: self.read_length()? };
+        stream.set_last_id(last_id);
+        if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+            // first_id、max_deleted_entry_id 和 entries_added
+            for _ in 0..5 {
+                self.read_length()?;
+            }
+        }
+
+        let groups = self.read_length()?;
+        for _ in 0..groups {
+            self.read_string()?;
+            self.read_length()?;
+            self.read_length()?;
+            if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+                self.read_length()?;
+            }
+            // 组的待确认列表：ID、投递时间和投递次数
+            let pending = self.read_length()?;
+            for _ in 0..pending {
+                self.read_bytes(16)?;
+                self.read_u64_le()?;
+                self.read_length()?;
+            }
+            let consumers = self.read_length()?;
+            for _ in 0..consumers {
+                self.read_string()?;
+                self.read_u64_le()?;
+                if value_type >= RDB_TYPE_STREAM_LISTPACKS_3 {
+                    self.read_u64_le()?;
+                }
+                let pending = self.read_length()?;
+                for _ in 0..pending {
+                    self.read_bytes(16)?;
+                }
+            }
+        }
+        Ok(stream)
+    }
+}
+
+/// 把 listpack 中的条目按流的节点格式还原成 (ID, 字段)
+fn parse_stream_listpack(master_id: StreamId, entries: Vec<Vec<u8>>) -> io::Result<Vec<(StreamId, Fields)>> {
+    let mut iter = entries.into_iter();
+    let next_int = |iter: &mut std::vec::IntoIter<Vec<u8>>| -> io::Result<i64> {
+        let entry = iter.next().ok_or_else(|| invalid("Truncated stream listpack"))?;
+        std::str::from_utf8(&entry)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or_else(|| invalid("Expected an integer in stream listpack"))
+    };
+    let next_entry = |iter: &mut std::vec::IntoIter<Vec<u8>>| -> io::Result<Vec<u8>> {
+        iter.next().ok_or_else(|| invalid("Truncated stream listpack"))
+    };
+
+    // 主条目：有效条目数、已删除条目数、主字段列表，最后以 0 结束
+    let count = next_int(&mut iter)?;
+    let deleted = next_int(&mut iter)?;
+    let master_fields_count = next_int(&mut iter)?;
+    let mut master_fields = Vec::new();
+    for _ in 0..master_fields_count {
+        master_fields.push(next_entry(&mut iter)?);
+    }
+    next_int(&mut iter)?;
+
+    let mut result = Vec::new();
+    for _ in 0..count.saturating_add(deleted) {
+        let flags = next_int(&mut iter)?;
+        let ms = master_id.ms.wrapping_add(next_int(&mut iter)? as u64);
+        let seq = master_id.seq.wrapping_add(next_int(&mut iter)? as u64);
+        let mut fields = Vec::new();
+        if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            for field in master_fields.iter() {
+                fields.push((field.clone(), next_entry(&mut iter)?));
+            }
+        } else {
+            let num_fields = next_int(&mut iter)?;
+            for _ in 0..num_fields {
+                let field = next_entry(&mut iter)?;
+                fields.push((field, next_entry(&mut iter)?));
+            }
+        }
+        // 每个条目最后是它占用的 listpack 元素个数，用于反向遍历
+        next_int(&mut iter)?;
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            result.push((StreamId { ms, seq }, fields));
+        }
+    }
+    Ok(result)
+}
+
+fn pairs(entries: Vec<Vec<u8>>) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    if !entries.len().is_multiple_of(2) {
+        return Err(invalid("Odd number of elements in encoded hash or zset"));
+    }
+    let mut iter = entries.into_iter();
+    let mut result = Vec::new();
+    while let (Some(a), Some(b)) = (iter.next(), iter.next()) {
+        result.push((a, b));
+    }
+    Ok(result)
+}
+
+fn parse_score(bytes: &[u8]) -> io::Result<f64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .ok_or_else(|| invalid("Invalid zset score in RDB"))
+}
+
+/// 从 buf[pos..] 取 len 个字节，越界时返回错误
+fn slice(buf: &[u8], pos: usize, len: usize) -> io::Result<&[u8]> {
+    buf.get(pos..pos + len).ok_or_else(|| invalid("Truncated encoded value in RDB"))
+}
+
+/// 解压 LZF 压缩的字符串
+fn lzf_decompress(input: &[u8], out_len: usize) -> io::Result<Vec<u8>> {
+    // 长度字段可能已经损坏，不按它预分配
+    let mut output: Vec<u8> = Vec::new();
+    let mut ip = 0;
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+        if ctrl < 32 {
+            // 字面量，长度为 ctrl + 1
+            output.extend_from_slice(slice(input, ip, ctrl + 1)?);
+            ip += ctrl + 1;
+        } else {
+            // 回溯引用之前输出的数据
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *slice(input, ip, 1)?.first().unwrap() as usize;
+                ip += 1;
+            }
+            let offset = ((ctrl & 0x1F) << 8) + *slice(input, ip, 1)?.first().unwrap() as usize + 1;
+            ip += 1;
+            if offset > output.len() {
+                return Err(invalid("Invalid LZF back reference"));
+            }
+            let start = output.len() - offset;
+            // 引用区间可能和正在写的区间重叠，只能逐字节复制
+            for i in 0..len + 2 {
+                output.push(output[start + i]);
+            }
+        }
+        if output.len() > out_len {
+            return Err(invalid("Invalid LZF compressed string"));
+        }
+    }
+    if output.len() != out_len {
+        return Err(invalid("Invalid LZF compressed string"));
+    }
+    Ok(output)
+}
+
+/// 解析 ziplist，整数条目转成十进制文本
+fn parse_ziplist(buf: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    // zlbytes(4) zltail(4) zllen(2)
+    let mut pos = 10;
+    let mut entries = Vec::new();
+    loop {
+        let prevlen = *slice(buf, pos, 1)?.first().unwrap();
+        if prevlen == 0xFF {
+            break;
+        }
+        pos += if prevlen == 0xFE { 5 } else { 1 };
+        let encoding = *slice(buf, pos, 1)?.first().unwrap();
+        pos += 1;
+        let entry = match encoding >> 6 {
+            0 => {
+                let len = (encoding & 0x3F) as usize;
+                let s = slice(buf, pos, len)?.to_vec();
+                pos += len;
+                s
+            }
+            1 => {
+                let len = (((encoding & 0x3F) as usize) << 8) | *slice(buf, pos, 1)?.first().unwrap() as usize;
+                pos += 1;
+                let s = slice(buf, pos, len)?.to_vec();
+                pos += len;
+                s
+            }
+            2 => {
+                let len = u32::from_be_bytes(slice(buf, pos, 4)?.try_into().unwrap()) as usize;
+                pos += 4;
+                let s = slice(buf, pos, len)?.to_vec();
+                pos += len;
+                s
+            }
+            _ => {
+                let value: i64 = match encoding {
+                    0xC0 => i16::from_le_bytes(slice(buf, pos, 2)?.try_into().unwrap()) as i64,
+                    0xD0 => i32::from_le_bytes(slice(buf, pos, 4)?.try_into().unwrap()) as i64,
+                    0xE0 => i64::from_le_bytes(slice(buf, pos, 8)?.try_into().unwrap()),
+                    0xF0 => {
+                        let b = slice(buf, pos, 3)?;
+                        (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as i64
+                    }
+                    0xFE => *slice(buf, pos, 1)?.first().unwrap() as i8 as i64,
+                    0xF1..=0xFD => (encoding & 0x0F) as i64 - 1,
+                    _ => return Err(invalid(format!("Unknown ziplist entry encoding {}", encoding))),
+                };
+                pos += match encoding {
+                    0xC0 => 2,
+                    0xD0 => 4,
+                    0xE0 => 8,
+                    0xF0 => 3,
+                    0xFE => 1,
+                    _ => 0,
+                };
+                value.to_string().into_bytes()
+            }
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// 解析 listpack，整数条目转成十进制文本
+fn parse_listpack(buf: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    // 总字节数(4) 元素个数(2)
+    let mut pos = 6;
+    let mut entries = Vec::new();
+    loop {
+        let start = pos;
+        let encoding = *slice(buf, pos, 1)?.first().unwrap();
+        if encoding == 0xFF {
+            break;
+        }
+        pos += 1;
+        let entry = if encoding & 0x80 == 0 {
+            // 7 位无符号整数
+            (encoding as i64).to_string().into_bytes()
+        } else if encoding & 0xC0 == 0x80 {
+            let len = (encoding & 0x3F) as usize;
+            let s = slice(buf, pos, len)?.to_vec();
+            pos += len;
+            s
+        } else if encoding & 0xE0 == 0xC0 {
+            // 13 位有符号整数
+            let uval = (((encoding & 0x1F) as i64) << 8) | *slice(buf, pos, 1)?.first().unwrap() as i64;
+            pos += 1;
+            let value = if uval >= 1 << 12 { uval - (1 << 13) } else { uval };
+            value.to_string().into_bytes()
+        } else if encoding & 0xF0 == 0xE0 {
+            let len = (((encoding & 0x0F) as usize) << 8) | *slice(buf, pos, 1)?.first().unwrap() as usize;
+            pos += 1;
+            let s = slice(buf, pos, len)?.to_vec();
+            pos += len;
+            s
+        } else {
+            match encoding {
+                0xF0 => {
+                    let len = u32::from_le_bytes(slice(buf, pos, 4)?.try_into().unwrap()) as usize;
+                    pos += 4;
+                    let s = slice(buf, pos, len)?.to_vec();
+                    pos += len;
+                    s
+                }
+                0xF1 => {
+                    let v = i16::from_le_bytes(slice(buf, pos, 2)?.try_into().unwrap()) as i64;
+                    pos += 2;
+                    v.to_string().into_bytes()
+                }
+                0xF2 => {
+                    let b = slice(buf, pos, 3)?;
+                    let v = (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as i64;
+                    pos += 3;
+                    v.to_string().into_bytes()
+                }
+                0xF3 => {
+                    let v = i32::from_le_bytes(slice(buf, pos, 4)?.try_into().unwrap()) as i64;
+                    pos += 4;
+                    v.to_string().into_bytes()
+                }
+                0xF4 => {
+                    let v = i64::from_le_bytes(slice(buf, pos, 8)?.try_into().unwrap());
+                    pos += 8;
+                    v.to_string().into_bytes()
+                }
+                _ => return Err(invalid(format!("Unknown listpack entry encoding {}", encoding))),
+            }
+        };
+        // 跳过记录条目长度的 backlen
+        pos += backlen_size(pos - start);
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// listpack 条目末尾 backlen 占用的字节数
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+/// 解析 intset：编码宽度(4) 元素个数(4)，之后是小端整数
+fn parse_intset(buf: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let width = u32::from_le_bytes(slice(buf, 0, 4)?.try_into().unwrap()) as usize;
+    let len = u32::from_le_bytes(slice(buf, 4, 4)?.try_into().unwrap()) as usize;
+    if !matches!(width, 2 | 4 | 8) {
+        return Err(invalid(format!("Invalid intset encoding {}", width)));
+    }
+    if buf.len() < 8 + len * width {
+        return Err(invalid("Truncated intset in RDB"));
+    }
+    let mut entries = Vec::with_capacity(len);
+    for i in 0..len {
+        let b = slice(buf, 8 + i * width, width)?;
+        let value = match width {
+            2 => i16::from_le_bytes(b.try_into().unwrap()) as i64,
+            4 => i32::from_le_bytes(b.try_into().unwrap()) as i64,
+            _ => i64::from_le_bytes(b.try_into().unwrap()),
+        };
+        entries.push(value.to_string().into_bytes());
+    }
+    Ok(entries)
+}
+
+// 保存流时每个 listpack 节点最多放的条目数
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+/// 把整个键空间序列化成 RDB 文件内容，checksum 为 false 时（rdbchecksum no）校验和写 0
+pub fn dump(keyspace: &Keyspace, checksum: bool) -> Vec<u8> {
+    let mut writer = RdbWriter::new();
+    writer.buf.extend_from_slice(format!("REDIS{:04}", RDB_VERSION).as_bytes());
+    let ctime = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    for (key, value) in [
+        ("redis-ver", "7.2.0".to_string()),
+        ("redis-bits", "64".to_string()),
+        ("ctime", ctime.to_string()),
+        ("used-mem", "0".to_string()),
+        ("aof-base", "0".to_string()),
+    ] {
+        writer.buf.push(RDB_OPCODE_AUX);
+        writer.write_string(key.as_bytes());
+        writer.write_string(value.as_bytes());
+    }
+
+    for (index, db) in keyspace.iter().enumerate() {
+        // 过期还没删除的 key 不写入，RESIZEDB 的大小按实际写入的条目计算
+        let entries: Vec<_> = db.iter().collect();
+        if entries.is_empty() {
+            continue;
+        }
+        let expires = entries.iter().filter(|(_, _, expire)| expire.is_some()).count();
+        writer.buf.push(RDB_OPCODE_SELECTDB);
+        writer.write_length(index as u64);
+        writer.buf.push(RDB_OPCODE_RESIZEDB);
+        writer.write_length(entries.len() as u64);
+        writer.write_length(expires as u64);
+        for (key, value, expire) in entries {
+            if let Some(when) = expire {
+                let ms = when.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+                writer.buf.push(RDB_OPCODE_EXPIRETIME_MS);
+                writer.buf.extend_from_slice(&ms.to_le_bytes());
+            }
+            writer.buf.push(object_type(value));
+            writer.write_string(key);
+            writer.write_object(value);
+        }
+    }
+
+    writer.buf.push(RDB_OPCODE_EOF);
+    let crc = if checksum { crc64(0, &writer.buf) } else { 0 };
+    writer.buf.extend_from_slice(&crc.to_le_bytes());
+    writer.buf
+}
+
+/// DUMP 的序列化格式：类型字节 + 值编码 + 2 字节 RDB 版本 + 8 字节 CRC64，都是小端
+pub fn dump_value(value: &RedisValue) -> Vec<u8> {
+    let mut writer = RdbWriter::new();
+    writer.buf.push(object_type(value));
+    writer.write_object(value);
+    writer.buf.extend_from_slice(&(RDB_VERSION as u16).to_le_bytes());
+    let crc = crc64(0, &writer.buf);
+    writer.buf.extend_from_slice(&crc.to_le_bytes());
+    writer.buf
+}
+
+/// 检查 DUMP 数据的版本和 CRC64，失败时返回 None
+pub fn verify_dump_payload(payload: &[u8]) -> Option<&[u8]> {
+    if payload.len() < 10 {
+        return None;
+    }
+    let (body, footer) = payload.split_at(payload.len() - 8);
+    let version = u16::from_le_bytes([body[body.len() - 2], body[body.len() - 1]]);
+    if u32::from(version) > RDB_VERSION {
+        return None;
+    }
+    let expected = u64::from_le_bytes(footer.try_into().ok()?);
+    if crc64(0, body) != expected {
+        return None;
+    }
+    Some(&body[..body.len() - 2])
+}
+
+/// 解析 verify_dump_payload 校验过的值编码，必须正好用完所有字节
+pub fn restore_value(data: &[u8]) -> io::Result<RedisValue> {
+    let mut reader = RdbReader::new(data);
+    let value_type = reader.read_u8()?;
+    let value = reader.read_object(value_type)?;
+    if reader.position() != data.len() as u64 {
+        return Err(invalid("Trailing data after the serialized value"));
+    }
+    Ok(value)
+}
+
+/// 值在 RDB 中使用的类型
+pub fn object_type(value: &RedisValue) -> u8 {
+    match value {
+        RedisValue::String(_) => RDB_TYPE_STRING,
+        RedisValue::List(_) => RDB_TYPE_LIST,
+        RedisValue::Set(_) => RDB_TYPE_SET,
+        RedisValue::ZSet(_) => RDB_TYPE_ZSET_2,
+        RedisValue::Hash(_) => RDB_TYPE_HASH,
+        RedisValue::Stream(_) => RDB_TYPE_STREAM_LISTPACKS_3,
+    }
+}
+
+/// 按 RDB 格式写入长度、字符串和各种类型的值，和 RdbReader 对应
+pub struct RdbWriter {
+    pub buf: Vec<u8>,
+}
+
+impl RdbWriter {
+    pub fn new() -> Self {
+        RdbWriter { buf: Vec::new() }
+    }
+
+    pub fn write_length(&mut self, len: u64) {
+        if len < 1 << 6 {
+            self.buf.push(len as u8);
+        } else if len < 1 << 14 {
+            self.buf.push((RDB_14BITLEN << 6) | (len >> 8) as u8);
+            self.buf.push(len as u8);
+        } else if len <= u32::MAX as u64 {
+            self.buf.push(RDB_32BITLEN);
+            self.buf.extend_from_slice(&(len as u32).to_be_bytes());
+        } else {
+            self.buf.push(RDB_64BITLEN);
+            self.buf.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+
+    pub fn write_string(&mut self, s: &[u8]) {
+        self.write_length(s.len() as u64);
+        self.buf.extend_from_slice(s);
+    }
+
+    /// 写入值本身，类型字节由 object_type 单独写
+    pub fn write_object(&mut self, value: &RedisValue) {
+        match value {
+            RedisValue::String(s) => self.write_string(s),
+            RedisValue::List(list) => {
+                self.write_length(list.len() as u64);
+                for item in list {
+                    self.write_string(item);
+                }
+            }
+            RedisValue::Set(set) => {
+                self.write_length(set.len() as u64);
+                for member in set {
+                    self.write_string(member);
+                }
+            }
+            RedisValue::ZSet(zset) => {
+                self.write_length(zset.len() as u64);
+                for (member, score) in zset {
+                    self.write_string(member);
+                    self.buf.extend_from_slice(&score.to_bits().to_le_bytes());
+                }
+            }
+            RedisValue::Hash(hash) => {
+                self.write_length(hash.len() as u64);
+                for (field, value) in hash {
+                    self.write_string(field);
+                    self.write_string(value);
+                }
+            }
+            RedisValue::Stream(stream) => self.write_stream(stream),
+        }
+    }
+
+    /// 流按 listpack 节点保存，每个节点的主条目字段取自节点的第一个条目
+    fn write_stream(&mut self, stream: &Stream) {
+        let entries: Vec<(&StreamId, &Fields)> = stream.entries().collect();
+        let nodes: Vec<&[(&StreamId, &Fields)]> = entries.chunks(STREAM_NODE_MAX_ENTRIES).collect();
+        self.write_length(nodes.len() as u64);
+        for node in nodes.iter() {
+            let master_id = *node[0].0;
+            let master_fields: Vec<&[u8]> = node[0].1.iter().map(|(f, _)| f.as_slice()).collect();
+            let mut lp: Vec<Vec<u8>> = Vec::new();
+            lp.push(node.len().to_string().into_bytes());
+            lp.push(b"0".to_vec());
+            lp.push(master_fields.len().to_string().into_bytes());
+            lp.extend(master_fields.iter().map(|f| f.to_vec()));
+            lp.push(b"0".to_vec());
+            for (id, fields) in node.iter() {
+                let same_fields = fields.len() == master_fields.len()
+                    && fields.iter().zip(master_fields.iter()).all(|((f, _), m)| f.as_slice() == *m);
+                let flags = if same_fields { STREAM_ITEM_FLAG_SAMEFIELDS } else { 0 };
+                lp.push(flags.to_string().into_bytes());
+                lp.push((id.ms.wrapping_sub(master_id.ms) as i64).to_string().into_bytes());
+                lp.push((id.seq.wrapping_sub(master_id.seq) as i64).to_string().into_bytes());
+                if same_fields {
+                    lp.extend(fields.iter().map(|(_, v)| v.clone()));
+                } else {
+                    lp.push(fields.len().to_string().into_bytes());
+                    for (f, v) in fields.iter() {
+                        lp.push(f.clone());
+                        lp.push(v.clone());
+                    }
+                }
+                // 条目占用的元素个数，不包括这个字段本身
+                let lp_count = if same_fields { fields.len() + 3 } else { fields.len() * 2 + 4 };
+                lp.push(lp_count.to_string().into_bytes());
+            }
+            let mut key = Vec::with_capacity(16);
+            key.extend_from_slice(&master_id.ms.to_be_bytes());
+            key.extend_from_slice(&master_id.seq.to_be_bytes());
+            self.write_string(&key);
+            self.write_string(&encode_listpack(&lp));
+        }
+
+        // 条目数、最后一个 ID、第一个 ID、最大删除 ID、累计添加的条目数
+        let last_id = stream.last_id();
+        let first_id = entries.first().map(|(id, _)| **id).unwrap_or(StreamId::MIN);
+        self.write_length(entries.len() as u64);
+        self.write_length(last_id.ms);
+        self.write_length(last_id.seq);
+        self.write_length(first_id.ms);
+        self.write_length(first_id.seq);
+        self.write_length(0);
+        self.write_length(0);
+        self.write_length(entries.len() as u64);
+        // 没有消费者组
+        self.write_length(0);
+    }
+}
+
+/// 按 listpack 格式编码，规范的十进制整数按整数编码
+fn encode_listpack(entries: &[Vec<u8>]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for entry in entries {
+        let start = body.len();
+        let int = std::str::from_utf8(entry)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok().filter(|v| v.to_string() == s));
+        match int {
+            Some(v) if (0..=127).contains(&v) => body.push(v as u8),
+            Some(v) if (-4096..=4095).contains(&v) => {
+                let u = (v as u64 & 0x1FFF) as u16;
+                body.push(0xC0 | (u >> 8) as u8);
+                body.push(u as u8);
+            }
+            Some(v) if v >= i16::MIN as i64 && v <= i16::MAX as i64 => {
+                body.push(0xF1);
+                body.extend_from_slice(&(v as i16).to_le_bytes());
+            }
+            Some(v) if (-(1 << 23)..(1 << 23)).contains(&v) => {
+                body.push(0xF2);
+                body.extend_from_slice(&(v as i32).to_le_bytes()[..3]);
+            }
+            Some(v) if v >= i32::MIN as i64 && v <= i32::MAX as i64 => {
+                body.push(0xF3);
+                body.extend_from_slice(&(v as i32).to_le_bytes());
+            }
+            Some(v) => {
+                body.push(0xF4);
+                body.extend_from_slice(&v.to_le_bytes());
+            }
+            None if entry.len() < 64 => {
+                body.push(0x80 | entry.len() as u8);
+                body.extend_from_slice(entry);
+            }
+            None if entry.len() < 4096 => {
+                body.push(0xE0 | (entry.len() >> 8) as u8);
+                body.push(entry.len() as u8);
+                body.extend_from_slice(entry);
+            }
+            None => {
+                body.push(0xF0);
+                body.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+                body.extend_from_slice(entry);
+            }
+        }
+        // backlen：条目长度，每字节 7 位，高位字节在前，除了第一个字节都带 128 标志
+        let len = body.len() - start;
+        let n = backlen_size(len);
+        for i in (0..n).rev() {
+            let b = ((len >> (7 * i)) & 127) as u8;
+            body.push(if i == n - 1 { b } else { b | 128 });
+        }
+    }
+    body.push(0xFF);
+
+    let mut lp = Vec::with_capacity(body.len() + 6);
+    lp.extend_from_slice(&((body.len() + 6) as u32).to_le_bytes());
+    let count = if entries.len() < u16::MAX as usize { entries.len() as u16 } else { u16::MAX };
+    lp.extend_from_slice(&count.to_le_bytes());
+    lp.extend_from_slice(&body);
+    lp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyspace::{from_unix_ms, now_ms};
+
+    fn strings(items: &[&str]) -> Vec<Vec<u8>> {
+        items.iter().map(|s| s.as_bytes().to_vec()).collect()
+    }
+
+    fn stream_entries(stream: &Stream) -> Vec<(StreamId, Fields)> {
+        stream.entries().map(|(id, fields)| (*id, fields.clone())).collect()
+    }
+
+    /// RedisValue 没有实现 PartialEq，按类型逐个比较
+    fn assert_same_value(a: &RedisValue, b: &RedisValue) {
+        match (a, b) {
+            (RedisValue::String(a), RedisValue::String(b)) => assert_eq!(a, b),
+            (RedisValue::List(a), RedisValue::List(b)) => assert_eq!(a, b),
+            (RedisValue::Hash(a), RedisValue::Hash(b)) => assert_eq!(a, b),
+            (RedisValue::Set(a), RedisValue::Set(b)) => assert_eq!(a, b),
+            (RedisValue::ZSet(a), RedisValue::ZSet(b)) => assert_eq!(a, b),
+            (RedisValue::Stream(a), RedisValue::Stream(b)) => {
+                assert_eq!(stream_entries(a), stream_entries(b));
+                assert_eq!(a.last_id(), b.last_id());
+            }
+            (a, b) => panic!("type mismatch: {:?} vs {:?}", a, b),
+        }
+    }
+
+    fn object_round_trip(value: &RedisValue) -> RedisValue {
+        let mut writer = RdbWriter::new();
+        writer.write_object(value);
+        let mut reader = RdbReader::new(&writer.buf);
+        let loaded = reader.read_object(object_type(value)).unwrap();
+        assert_eq!(reader.position(), writer.buf.len() as u64);
+        loaded
+    }
+
+    /// 每种类型各一个值
+    fn sample_values() -> Vec<(&'static str, RedisValue)> {
+        let mut stream = Stream::new();
+        stream.add(b"1-1", vec![(b"a".to_vec(), b"1".to_vec())]).unwrap();
+        stream.add(b"2-0", vec![(b"b".to_vec(), b"2".to_vec()), (b"c".to_vec(), b"3".to_vec())]).unwrap();
+        vec![
+            ("string", RedisValue::String(b"hello".to_vec())),
+            ("int", RedisValue::String(b"-12345".to_vec())),
+            ("list", RedisValue::List(strings(&["a", "1", "b"]).into_iter().collect())),
+            ("set", RedisValue::Set(strings(&["x", "y", "10"]).into_iter().collect())),
+            ("zset", RedisValue::ZSet([(b"m1".to_vec(), 1.5), (b"m2".to_vec(), -2.0), (b"inf".to_vec(), f64::INFINITY)].into_iter().collect())),
+            ("hash", RedisValue::Hash([(b"f1".to_vec(), b"v1".to_vec()), (b"f2".to_vec(), b"20".to_vec())].into_iter().collect())),
+            ("stream", RedisValue::Stream(stream)),
+        ]
+    }
+
+    #[test]
+    fn length_encodings() {
+        for (len, size) in [
+            (0, 1),
+            (63, 1),
+            (64, 2),
+            (16383, 2),
+            (16384, 5),
+            (u32::MAX as u64, 5),
+            (u32::MAX as u64 + 1, 9),
+        ] {
+            let mut writer = RdbWriter::new();
+            writer.write_length(len);
+            assert_eq!(writer.buf.len(), size, "length {}", len);
+            assert_eq!(RdbReader::new(&writer.buf).read_length().unwrap(), len);
+        }
+        // 特殊编码的字符串不能当作长度
+        assert!(RdbReader::new(&[0xC0, 1]).read_length().is_err());
+        assert!(RdbReader::new(&[0x40]).read_length().is_err());
+    }
+
+    #[test]
+    fn int_encoded_strings() {
+        assert_eq!(RdbReader::new(&[0xC0, 0x85]).read_string().unwrap(), b"-123");
+        assert_eq!(RdbReader::new(&[0xC1, 0x39, 0x30]).read_string().unwrap(), b"12345");
+        assert_eq!(RdbReader::new(&[0xC2, 0x00, 0x94, 0x35, 0x77]).read_string().unwrap(), b"2000000000");
+        assert!(RdbReader::new(&[0xC4]).read_string().is_err());
+    }
+
+    #[test]
+    fn lzf_strings() {
+        // 一个字面量 'a'，再回溯 1 个字节复制 9 次
+        let compressed = [0x00, b'a', 0xE0, 0x00, 0x00];
+        assert_eq!(lzf_decompress(&compressed, 10).unwrap(), b"aaaaaaaaaa");
+        // 0xC3 压缩后长度 解压后长度 数据
+        let mut data = vec![0xC3, compressed.len() as u8, 10];
+        data.extend_from_slice(&compressed);
+        assert_eq!(RdbReader::new(&data).read_string().unwrap(), b"aaaaaaaaaa");
+
+        assert_eq!(lzf_decompress(&[0x02, b'a', b'b', b'c'], 3).unwrap(), b"abc");
+        assert!(lzf_decompress(&[0x02, b'a', b'b', b'c'], 4).is_err());
+        assert!(lzf_decompress(&[0x02, b'a', b'b'], 3).is_err());
+        // 回溯超出已经输出的数据
+        assert!(lzf_decompress(&[0x00, b'a', 0x20, 0x05], 4).is_err());
+    }
+
+    #[test]
+    fn listpack_round_trip() {
+        let long = "x".repeat(5000);
+        let entries = strings(&[
+            "0", "127", "128", "-1", "-4096", "4095", "-32768", "32767", "8388607", "-8388608",
+            "2147483647", "-2147483648", "9223372036854775807", "-9223372036854775808",
+            "", "hello", "007", "1.5", &"y".repeat(63), &"y".repeat(64), &long,
+        ]);
+        let encoded = encode_listpack(&entries);
+        assert_eq!(u32::from_le_bytes(encoded[..4].try_into().unwrap()) as usize, encoded.len());
+        assert_eq!(parse_listpack(&encoded).unwrap(), entries);
+        assert!(parse_listpack(&encoded[..encoded.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn intset_entries() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&2u32.to_le_bytes());
+        buf.extend_from_slice(&2u32.to_le_bytes());
+        buf.extend_from_slice(&(-2i16).to_le_bytes());
+        buf.extend_from_slice(&300i16.to_le_bytes());
+        assert_eq!(parse_intset(&buf).unwrap(), strings(&["-2", "300"]));
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&8u32.to_le_bytes());
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        buf.extend_from_slice(&i64::MIN.to_le_bytes());
+        assert_eq!(parse_intset(&buf).unwrap(), strings(&["-9223372036854775808"]));
+        assert!(parse_intset(&buf[..buf.len() - 1]).is_err());
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&3u32.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        assert!(parse_intset(&buf).is_err());
+    }
+
+    #[test]
+    fn ziplist_entries() {
+        let mut buf = vec![0; 10];
+        // 6 位长度的字符串
+        buf.extend_from_slice(&[0x00, 0x03, b'a', b'b', b'c']);
+        // 14 位长度的字符串
+        buf.extend_from_slice(&[0x05, 0x40, 0x02, b'h', b'i']);
+        // int16、int32、int64
+        buf.extend_from_slice(&[0x05, 0xC0]);
+        buf.extend_from_slice(&(-300i16).to_le_bytes());
+        buf.extend_from_slice(&[0x04, 0xD0]);
+        buf.extend_from_slice(&100000i32.to_le_bytes());
+        buf.extend_from_slice(&[0x06, 0xE0]);
+        buf.extend_from_slice(&(-5000000000i64).to_le_bytes());
+        // 24 位整数 -2，8 位整数 -5，直接编码在类型字节中的 3
+        buf.extend_from_slice(&[0x0A, 0xF0, 0xFE, 0xFF, 0xFF]);
+        buf.extend_from_slice(&[0x05, 0xFE, 0xFB]);
+        buf.extend_from_slice(&[0x03, 0xF4]);
+        buf.push(0xFF);
+        assert_eq!(
+            parse_ziplist(&buf).unwrap(),
+            strings(&["abc", "hi", "-300", "100000", "-5000000000", "-2", "-5", "3"])
+        );
+        assert!(parse_ziplist(&buf[..buf.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn stream_layout_round_trip() {
+        let mut stream = Stream::new();
+        for i in 0..250u64 {
+            let fields = if i % 3 == 0 {
+                vec![(b"other".to_vec(), i.to_string().into_bytes())]
+            } else {
+                vec![(b"name".to_vec(), b"n".to_vec()), (b"value".to_vec(), (i * 7).to_string().into_bytes())]
+            };
+            stream.load_entry(StreamId { ms: 1000 + i / 2, seq: i % 2 }, fields);
+        }
+        stream.set_last_id(StreamId { ms: 5000, seq: 3 });
+        let value = RedisValue::Stream(stream);
+        assert_same_value(&object_round_trip(&value), &value);
+
+        let empty = RedisValue::Stream(Stream::new());
+        assert_same_value(&object_round_trip(&empty), &empty);
+    }
+
+    #[test]
+    fn stream_listpack_deleted_and_samefields_entries() {
+        let master = StreamId { ms: 10, seq: 0 };
+        let entries = strings(&[
+            // 有效条目 1 个、删除 1 个、主字段 f
+            "1", "1", "1", "f", "0",
+            // 删除的条目
+            "3", "0", "0", "a", "4",
+            // 字段和主条目相同的条目
+            "2", "5", "1", "b", "4",
+        ]);
+        let parsed = parse_stream_listpack(master, entries).unwrap();
+        assert_eq!(parsed, vec![(StreamId { ms: 15, seq: 1 }, vec![(b"f".to_vec(), b"b".to_vec())])]);
+        assert!(parse_stream_listpack(master, strings(&["1", "0"])).is_err());
+    }
+
+    #[test]
+    fn dump_load_round_trip() {
+        let mut keyspace = Keyspace::new(16);
+        for (key, value) in sample_values() {
+            keyspace.db(0).insert(key.as_bytes().to_vec(), value, None);
+        }
+        let expire = from_unix_ms(now_ms() + 60_000);
+        keyspace.db(1).insert(b"volatile".to_vec(), RedisValue::String(b"v".to_vec()), Some(expire));
+
+        let data = dump(&keyspace, true);
+        let mut loaded = Keyspace::new(16);
+        let mut metadata = HashMap::new();
+        load(&data, &mut loaded, &mut metadata, true).unwrap();
+
+        assert!(metadata.contains_key("redis-ver"));
+        assert_eq!(loaded.db(0).len(), sample_values().len());
+        for (key, value) in sample_values() {
+            assert_same_value(loaded.db(0).get(key.as_bytes()).unwrap(), &value);
+            assert_eq!(loaded.db(0).get_expire(key.as_bytes()), None);
+        }
+        assert_eq!(loaded.db(1).len(), 1);
+        assert_eq!(loaded.db(1).get_expire(b"volatile"), Some(expire));
+    }
+
+    #[test]
+    fn load_skips_expired_keys() {
+        let mut writer = RdbWriter::new();
+        writer.buf.extend_from_slice(b"REDIS0011");
+        writer.buf.push(RDB_OPCODE_SELECTDB);
+        writer.write_length(0);
+        writer.buf.push(RDB_OPCODE_EXPIRETIME_MS);
+        writer.buf.extend_from_slice(&1000u64.to_le_bytes());
+        writer.buf.push(RDB_TYPE_STRING);
+        writer.write_string(b"old");
+        writer.write_string(b"v");
+        writer.buf.push(RDB_TYPE_STRING);
+        writer.write_string(b"new");
+        writer.write_string(b"v");
+        writer.buf.push(RDB_OPCODE_EOF);
+        // 校验和为 0 表示保存时没有计算
+        writer.buf.extend_from_slice(&0u64.to_le_bytes());
+
+        let mut keyspace = Keyspace::new(16);
+        load(&writer.buf, &mut keyspace, &mut HashMap::new(), true).unwrap();
+        assert_eq!(keyspace.db(0).len(), 1);
+        assert!(keyspace.db(0).get(b"new").is_some());
+    }
+
+    #[test]
+    fn load_checks_checksum() {
+        let mut keyspace = Keyspace::new(16);
+        keyspace.db(0).insert(b"key".to_vec(), RedisValue::String(b"value".to_vec()), None);
+        let mut data = dump(&keyspace, true);
+        let crc = u64::from_le_bytes(data[data.len() - 8..].try_into().unwrap());
+        assert_eq!(crc, crc64(0, &data[..data.len() - 8]));
+
+        // 改掉值里的一个字节，只有关闭校验时才能加载
+        let pos = data.windows(5).position(|w| w == b"value").unwrap();
+        data[pos] = b'V';
+        assert!(load(&data, &mut Keyspace::new(16), &mut HashMap::new(), true).is_err());
+        let mut loaded = Keyspace::new(16);
+        load(&data, &mut loaded, &mut HashMap::new(), false).unwrap();
+        assert_same_value(loaded.db(0).get(b"key").unwrap(), &RedisValue::String(b"Value".to_vec()));
+
+        // 关闭校验和保存的文件写 0，加载时不检查
+        let data = dump(&keyspace, false);
+        assert_eq!(&data[data.len() - 8..], &[0; 8]);
+        load(&data, &mut Keyspace::new(16), &mut HashMap::new(), true).unwrap();
+
+        // 截断的文件
+        assert!(load(&data[..data.len() - 9], &mut Keyspace::new(16), &mut HashMap::new(), true).is_err());
+    }
+
+    #[test]
+    fn dump_payload_round_trip() {
+        for (key, value) in sample_values() {
+            let payload = dump_value(&value);
+            let body = verify_dump_payload(&payload).unwrap_or_else(|| panic!("{} payload rejected", key));
+            assert_eq!(body[0], object_type(&value));
+            assert_same_value(&restore_value(body).unwrap(), &value);
+        }
+    }
+
+    #[test]
+    fn dump_payload_rejects_bad_crc_and_version() {
+        let payload = dump_value(&RedisValue::String(b"hello".to_vec()));
+        let crc_at = payload.len() - 8;
+
+        let mut bad_crc = payload.clone();
+        bad_crc[crc_at] ^= 0xFF;
+        assert!(verify_dump_payload(&bad_crc).is_none());
+
+        let mut bad_body = payload.clone();
+        bad_body[2] ^= 0xFF;
+        assert!(verify_dump_payload(&bad_body).is_none());
+
+        // 版本比自己新的数据即使 CRC 正确也拒绝
+        let mut newer = payload[..crc_at].to_vec();
+        let version_at = newer.len() - 2;
+        newer[version_at..].copy_from_slice(&(RDB_VERSION as u16 + 1).to_le_bytes());
+        let crc = crc64(0, &newer);
+        newer.extend_from_slice(&crc.to_le_bytes());
+        assert!(verify_dump_payload(&newer).is_none());
+
+        assert!(verify_dump_payload(&payload[..9]).is_none());
+        assert!(verify_dump_payload(&[]).is_none());
+    }
+
+    #[test]
+    fn restore_rejects_trailing_data() {
+        let payload = dump_value(&RedisValue::String(b"hello".to_vec()));
+        let mut body = verify_dump_payload(&payload).unwrap().to_vec();
+        body.push(0);
+        assert!(restore_value(&body).is_err());
+        assert!(restore_value(&body[..body.len() - 3]).is_err());
+        assert!(restore_value(&[]).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use crate::resp::{self, Value};
+use crate::db::RedisDb;
+use crate::config::Config;
+use tokio::net::TcpStream;
+use std::net::SocketAddr;
+use anyhow::Result;
+use tokio::sync::Mutex;
+use std::sync::Arc;
+use std::future::Future;
+use std::pin::Pin;
+use tokio::time;
+
+type RedisConfig = Arc<Mutex<Config>>;
+
+// 和主节点的连接断开后，重新连接前等待的时间
+const REPL_RETRY_PERIOD: time::Duration = time::Duration::from_secs(1);
+// 副本主动向主节点回报偏移量的间隔
+const REPL_ACK_PERIOD: time::Duration = time::Duration::from_secs(1);
+
+/// 副本的复制循环：连接断开后隔一段时间重新连接，能部分同步时不再传输整个快照
+/// 由 REPLICAOF 启动，切换主节点或者 REPLICAOF NO ONE 时整个任务被取消
+/// 复制流中的命令经过 handle_command，而 REPLICAOF 又会启动这个任务，装箱后编译器才能确定它是 Send 的
+pub fn replication_loop(master_addr: SocketAddr, redisconfig: RedisConfig) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    Box::pin(async move {
+        // 部分同步时复制流接着之前选择的数据库继续执行，连接之间保留
+        let mut db = RedisDb::master_client();
+        loop {
+            if let Err(e) = perform_replication_handshake(master_addr, &mut db, redisconfig.clone()).await {
+                println!("Replication with master failed: {}", e);
+            }
+            redisconfig.lock().await.set_master_link_status(false);
+            println!("Connection with master lost, reconnecting...");
+            time::sleep(REPL_RETRY_PERIOD).await;
+        }
+    })
+}
+
+async fn perform_replication_handshake(master_addr: SocketAddr, db: &mut RedisDb, redisconfig: RedisConfig) -> Result<()> {
+    // 尝试连接到主服务器
+    let master_stream = TcpStream::connect(master_addr).await?;
+    master_stream.set_nodelay(true)?;
+
+    let mut handler = resp::RespHandler::new(master_stream);
+
+    // Stage 1：sent ping to master
+    handler.write_value(Value::Array(vec![Value::BulkString(Some("PING".into()))])).await?;
+    let response = handler.read_value().await?.ok_or_else(|| anyhow::anyhow!("Failed to read response"))?;
+    println!("Master response: {}", response);
+    time::sleep(time::Duration::from_millis(20)).await;
+
+    // Stage2: The replica sends twice to the master (This stageREPLCONF)
+    {
+        let config = redisconfig.lock().await;
+        handler.write_value(Value::Array(vec![
+            Value::BulkString(Some("REPLCONF".into())),
+            Value::BulkString(Some("listening-port".into())),
+            Value::BulkString(Some(config.get_config("port".to_string()).into())),
+        ])).await?;
+    }
+    let response = handler.read_value().await?.ok_or_else(|| anyhow::anyhow!("Failed to read response"))?;
+    println!("Master response: {}", response);
+
+    handler.write_value(Value::Array(vec![
+        Value::BulkString(Some("REPLCONF".into())),
+        Value::BulkString(Some("capa".into())),
+        Value::BulkString(Some("psync2".into())),
+    ])).await?;
+
+    let response = handler.read_value().await?.ok_or_else(|| anyhow::anyhow!("Failed to read response"))?;
+    println!("Master response: {}", response);
+
+    // Stage3: 之前同步过时带上主节点的复制 ID 和偏移量，尝试部分同步
+    let (replid, offset) = redisconfig.lock().await.replica_psync_args();
+    handler.write_value(Value::Array(vec![
+        Value::BulkString(Some("PSYNC".into())),
+        Value::BulkString(Some(replid.into_bytes())),
+        Value::BulkString(Some(offset.into_bytes())),
+    ])).await?;
+    let response = handler.read_value().await?.ok_or_else(|| anyhow::anyhow!("Failed to read response"))?;
+    println!("Master response: {}", response);
+
+    let reply = response.as_str().unwrap_or_default().to_string();
+    let parts: Vec<&str> = reply.split_whitespace().collect();
+    match parts.as_slice() {
+        ["FULLRESYNC", replid, offset] => {
+            let offset = offset.parse::<usize>()?;
+            // 先加载主节点的快照，替换掉原有数据之后才开始执行命令流
+            let payload = handler.read_rdb_payload().await?;
+            println!("MASTER <-> REPLICA sync: receiving {} bytes from master", payload.len());
+            let mut config = redisconfig.lock().await;
+            println!("MASTER <-> REPLICA sync: Flushing old data");
+            config.load_rdb_bytes(&payload)?;
+            config.set_master_sync(replid.to_string(), offset);
+            // 自己的副本还是旧数据，断开让它们从新的数据重新同步
+            config.disconnect_replicas().await;
+            // 主节点在快照之后的复制流开头会先发 SELECT
+            *db = RedisDb::master_client();
+            println!("MASTER <-> REPLICA sync: Finished with success");
+        }
+        ["CONTINUE", rest @ ..] => {
+            // 主节点的复制 ID 可能变了，偏移量保持不变
+            let mut config = redisconfig.lock().await;
+            if let Some(new_replid) = rest.first() {
+                let offset = config.rcliinfo_get_slave_cmd_offset();
+                config.set_master_sync(new_replid.to_string(), offset);
+            }
+            println!("MASTER <-> REPLICA sync: Master accepted a Partial Resynchronization.");
+        }
+        _ => return Err(anyhow::anyhow!("Unexpected reply to PSYNC from master: {}", reply)),
+    }
+    redisconfig.lock().await.set_master_link_status(true);
+
+    // 复制流中 MULTI 和 EXEC 之间的命令，收到 EXEC 后一起执行
+    let mut multi_cmds: Option<Vec<(String, Vec<Value>)>> = None;
+    // 已经收到、还没有随 EXEC 计入偏移量的事务字节数
+    let mut multi_bytes = 0;
+    let mut ack_interval = time::interval(REPL_ACK_PERIOD);
+    loop {
+        let read = tokio::select! {
+            read = handler.slave_read_value() => read?,
+            _ = ack_interval.tick() => {
+                // 没有收到 GETACK 时也定期回报，主节点据此更新副本的偏移量
+                let offset = redisconfig.lock().await.rcliinfo_get_slave_cmd_offset();
+                handler.write_value(Value::Array(vec![
+                    Value::BulkString(Some("REPLCONF".into())),
+                    Value::BulkString(Some("ACK".into())),
+                    Value::BulkString(Some(offset.to_string().into_bytes())),
+                ])).await?;
+                continue;
+            }
+        };
+        let values = match read {
+            Some(values) => values,
+            None => return Ok(()),
+        };
+        for v in values {
+            if let Value::Array(_) = v {
+                let (command, args) = crate::extract_command(v.clone())?;
+                let len = v.serialize().len();
+
+                if command.eq_ignore_ascii_case("replconf") {
+                    // REPLCONF 不属于事务，夹在 MULTI 和 EXEC 之间时也立即回复
+                    let respon = db.handle_command(command, args, redisconfig.clone(), master_addr).await;
+                    handler.write_value(respon).await?;
+                } else if command.eq_ignore_ascii_case("multi") {
+                    multi_cmds = Some(Vec::new());
+                } else if command.eq_ignore_ascii_case("exec") {
+                    let cmds = multi_cmds.take().unwrap_or_default();
+                    db.exec(cmds, redisconfig.clone(), master_addr).await;
+                } else if let Some(cmds) = multi_cmds.as_mut() {
+                    cmds.push((command, args));
+                } else {
+                    db.handle_command(command, args, redisconfig.clone(), master_addr).await;
+                }
+
+                //记录处理的命令，MULTI 到 EXEC 之间的字节等 EXEC 执行之后一起计入，
+                //连接中断时从 MULTI 之前重新同步，不会丢掉已经入队的命令
+                if multi_cmds.is_some() {
+                    multi_bytes += len;
+                } else {
+                    let mut redisconfig_lock=redisconfig.lock().await;
+                    redisconfig_lock.rcliinfo_track_slave_cmd_offset(multi_bytes + len);
+                    multi_bytes = 0;
+                }
+            }
+        }
+    }
+}
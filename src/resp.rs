@@ -1,190 +1,619 @@
 use tokio::{net::TcpStream, io::{AsyncReadExt, AsyncWriteExt}};
-use std::fmt::{Write};
-use bytes::BytesMut;
+use bytes::{Buf, BytesMut};
 use anyhow::Result;
-use tokio::time::{self,sleep, timeout, Duration};
 use anyhow::Context;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 // 参数用于输入到database中
 
-#[derive(Clone, Debug,Eq, Hash, PartialEq,PartialOrd)]
-#[warn(dead_code)]
-#[warn(unused_variables)]
+// RESP 协议版本，HELLO 命令可以在连接上切换
+pub const RESP2: u8 = 2;
+pub const RESP3: u8 = 3;
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum Value {
     SimpleString(String),
     Error(String),
-    BulkString(Option<String>),
+    // 批量字符串按字节保存，保证二进制安全
+    BulkString(Option<Vec<u8>>),
     Integer(i64),
     Array(Vec<Value>),
     RdbFile(Vec<u8>),
+    // 以下为 RESP3 类型，RESP2 连接上会自动降级为等价的 RESP2 编码
+    Map(Vec<(Value, Value)>),
+    Set(Vec<Value>),
+    Double(f64),
+    Boolean(bool),
+    BigNumber(String),
+    // (格式, 内容)，格式固定为三个字符，例如 "txt"
+    VerbatimString(String, Vec<u8>),
+    Null,
+    Push(Vec<Value>),
+}
+// 解析 Double 时拒绝 NaN，因此可以安全地把 Value 当作 HashMap 的键
+impl Eq for Value {}
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Value::SimpleString(s) | Value::Error(s) | Value::BigNumber(s) => s.hash(state),
+            Value::BulkString(s) => s.hash(state),
+            Value::Integer(i) => i.hash(state),
+            Value::Array(v) | Value::Set(v) | Value::Push(v) => v.hash(state),
+            Value::RdbFile(f) => f.hash(state),
+            Value::Map(m) => m.hash(state),
+            Value::Double(d) => d.to_bits().hash(state),
+            Value::Boolean(b) => b.hash(state),
+            Value::VerbatimString(format, s) => {
+                format.hash(state);
+                s.hash(state);
+            }
+            Value::Null => {}
+        }
+    }
 }
 impl Value {
-    pub fn serialize(self) -> String {
+    /// 按 RESP2 编码
+    pub fn serialize(self) -> Vec<u8> {
+        self.serialize_with(RESP2)
+    }
+    /// 按指定的协议版本编码，RESP2 下 RESP3 类型会降级
+    pub fn serialize_with(self, protocol: u8) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.serialize_into(&mut buf, protocol);
+        buf
+    }
+    fn serialize_into(self, buf: &mut Vec<u8>, protocol: u8) {
+        let resp3 = protocol >= RESP3;
         match self {
-            Value::SimpleString(s) => format!("+{}\r\n", s),
-            Value::Error(s) => format!("-{}\r\n", s),
-            Value::BulkString(Some(s)) => format!("${}\r\n{}\r\n", s.chars().count(), s),
-            Value::BulkString(None) => format!("$-1\r\n",),
-            Value::Integer(i) => format!(":{}\r\n", i),
-            Value::Array(v) => {
-                let mut s = String::new();
-                write!(s, "*{}\r\n", v.len()).unwrap();
-                for item in v {
-                    write!(s, "{}", item.serialize()).unwrap();
+            Value::SimpleString(s) => buf.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Value::Error(s) => buf.extend_from_slice(format!("-{}\r\n", s).as_bytes()),
+            Value::BulkString(Some(s)) => {
+                // 长度按字节计算，而不是字符数
+                buf.extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+                buf.extend_from_slice(&s);
+                buf.extend_from_slice(b"\r\n");
+            }
+            Value::BulkString(None) | Value::Null => {
+                if resp3 {
+                    buf.extend_from_slice(b"_\r\n");
+                } else {
+                    buf.extend_from_slice(b"$-1\r\n");
+                }
+            }
+            Value::Integer(i) => buf.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            Value::Array(v) => Self::serialize_aggregate(buf, b'*', v, protocol),
+            Value::RdbFile(s) => {
+                // RDB 文件传输格式: $<len>\r\n<payload>，末尾没有 \r\n
+                buf.extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+                buf.extend_from_slice(&s);
+            }
+            Value::Map(m) => {
+                if resp3 {
+                    buf.extend_from_slice(format!("%{}\r\n", m.len()).as_bytes());
+                } else {
+                    buf.extend_from_slice(format!("*{}\r\n", m.len() * 2).as_bytes());
+                }
+                for (k, v) in m {
+                    k.serialize_into(buf, protocol);
+                    v.serialize_into(buf, protocol);
+                }
+            }
+            Value::Set(v) => Self::serialize_aggregate(buf, if resp3 { b'~' } else { b'*' }, v, protocol),
+            Value::Push(v) => Self::serialize_aggregate(buf, if resp3 { b'>' } else { b'*' }, v, protocol),
+            Value::Double(d) => {
+                let repr = format_double(d);
+                if resp3 {
+                    buf.extend_from_slice(format!(",{}\r\n", repr).as_bytes());
+                } else {
+                    Value::BulkString(Some(repr.into_bytes())).serialize_into(buf, protocol);
+                }
+            }
+            Value::Boolean(b) => {
+                if resp3 {
+                    buf.extend_from_slice(if b { b"#t\r\n" } else { b"#f\r\n" });
+                } else {
+                    Value::Integer(b as i64).serialize_into(buf, protocol);
+                }
+            }
+            Value::BigNumber(n) => {
+                if resp3 {
+                    buf.extend_from_slice(format!("({}\r\n", n).as_bytes());
+                } else {
+                    Value::BulkString(Some(n.into_bytes())).serialize_into(buf, protocol);
                 }
-                s
             }
-            Value::RdbFile(s) => format!("${}\r\n", s.len()),
+            Value::VerbatimString(format, s) => {
+                if resp3 {
+                    buf.extend_from_slice(format!("={}\r\n{}:", s.len() + 4, format).as_bytes());
+                    buf.extend_from_slice(&s);
+                    buf.extend_from_slice(b"\r\n");
+                } else {
+                    Value::BulkString(Some(s)).serialize_into(buf, protocol);
+                }
+            }
+        }
+    }
+    fn serialize_aggregate(buf: &mut Vec<u8>, prefix: u8, items: Vec<Value>, protocol: u8) {
+        buf.push(prefix);
+        buf.extend_from_slice(format!("{}\r\n", items.len()).as_bytes());
+        for item in items {
+            item.serialize_into(buf, protocol);
+        }
+    }
+    /// 以 UTF-8 字符串形式读取批量字符串，用于命令名、选项和数字参数
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::BulkString(Some(s)) => std::str::from_utf8(s).ok(),
+            Value::SimpleString(s) => Some(s),
+            _ => None,
         }
     }
 }
+/// 与 Redis 一致的浮点数格式: inf / -inf，整数值不带小数点
+fn format_double(d: f64) -> String {
+    if d.is_infinite() {
+        if d > 0.0 { "inf".to_string() } else { "-inf".to_string() }
+    } else {
+        format!("{}", d)
+    }
+}
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Value::SimpleString(s) => write!(f, "{}", s),
+            Value::SimpleString(s) | Value::BigNumber(s) => write!(f, "{}", s),
             Value::Error(e) => write!(f, "{}", e),
-            Value::BulkString(Some(s)) => write!(f, "{}", s),
-            Value::BulkString(None) => write!(f, "(nil)"),
+            Value::BulkString(Some(s)) | Value::VerbatimString(_, s) => write!(f, "{}", String::from_utf8_lossy(s)),
+            Value::BulkString(None) | Value::Null => write!(f, "(nil)"),
             Value::Integer(i) => write!(f, "{}", i),
-            Value::Array(arr) => {
+            Value::Double(d) => write!(f, "{}", format_double(*d)),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Array(arr) | Value::Set(arr) | Value::Push(arr) => {
                 let mut output = String::new();
                 for item in arr {
                     output.push_str(&format!("{}\n", item));
                 }
                 write!(f, "{}", output.trim_end())
             },
+            Value::Map(m) => {
+                let mut output = String::new();
+                for (k, v) in m {
+                    output.push_str(&format!("{} => {}\n", k, v));
+                }
+                write!(f, "{}", output.trim_end())
+            },
             Value::RdbFile(_s) => write!(f, "file"),
         }
     }
 }
 
+// 单个批量字符串的最大长度，与 Redis 的 proto-max-bulk-len 默认值一致
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+// inline 命令一行的最大长度
+const MAX_INLINE_LEN: usize = 64 * 1024;
+// 一条命令最多的参数个数，和 Redis 一致
+const MAX_MULTIBULK_LEN: i64 = 1024 * 1024;
+// 回复中聚合类型的最大嵌套深度，超过时按协议错误处理，避免递归解析耗尽栈空间
+const MAX_NESTING_DEPTH: usize = 128;
+
+// 从缓冲区头部解析一个帧的函数，返回解析出的值和消耗的字节数
+type ParseFn = fn(&[u8]) -> Result<Option<(Value, usize)>>;
+
 #[derive(Debug)]
 pub struct RespHandler {
     stream: TcpStream,
+    // 尚未解析完的字节会留在缓冲区里，等待下一次读取补全
     buffer: BytesMut,
+    // 当前连接使用的协议版本，决定回复的编码方式
+    protocol: u8,
 }
 impl RespHandler {
     pub fn new(stream: TcpStream) -> Self {
         RespHandler {
             stream,
-            buffer: BytesMut::with_capacity(4294967),
+            buffer: BytesMut::with_capacity(16 * 1024),
+            protocol: RESP2,
         }
     }
+    pub fn set_protocol(&mut self, protocol: u8) {
+        self.protocol = protocol;
+    }
+    /// 从缓冲区中取出下一个完整的回复，不够一帧时继续从连接读取
     pub async fn read_value(&mut self) -> Result<Option<Value>> {
-        // 设置超时时间为5秒
-        let timeout_duration = Duration::from_secs(1);
-    
-        // 使用tokio::time::timeout来添加超时控制
-        let bytes_read = time::timeout(timeout_duration, self.stream.read_buf(&mut self.buffer))
-            .await
-            .with_context(|| format!("Operation timed out after {:?}", timeout_duration))?;
-    
-        // 如果读取到的字节数为0，说明连接可能已经关闭
-        if bytes_read? == 0 {
-            return Ok(None);
+        self.read_with(parse_message).await
+    }
+
+    /// 读取客户端发来的下一条命令，只接受批量字符串数组或者 inline 命令
+    pub async fn read_request(&mut self) -> Result<Option<Value>> {
+        self.read_with(parse_request).await
+    }
+
+    async fn read_with(&mut self, parse: ParseFn) -> Result<Option<Value>> {
+        loop {
+            if let Some((v, bytes_consumed)) = parse(&self.buffer)
+                .with_context(|| "Failed to parse message")? {
+                self.buffer.advance(bytes_consumed);
+                return Ok(Some(v));
+            }
+
+            let bytes_read = self.stream.read_buf(&mut self.buffer).await?;
+
+            // 如果读取到的字节数为0，说明连接已经关闭
+            if bytes_read == 0 {
+                if !self.buffer.is_empty() {
+                    println!("Connection closed with {} unparsed bytes", self.buffer.len());
+                }
+                return Ok(None);
+            }
         }
-    
-        // 解析消息
-        let (v, bytes_consumed) = parse_message(self.buffer.split())
-            .with_context(|| "Failed to parse message")?;
-    
-        Ok(Some(v))
     }
 
-    pub async fn slave_read_value(&mut self) -> Result<Option<Vec<Value>>> {
-        let bytes_read = self.stream.read_buf(&mut self.buffer).await?;
-        if bytes_read == 0 {
-            return Ok(None);
+    /// 读取主节点在 FULLRESYNC 之后发送的 RDB 快照: "$<len>\r\n" 加 len 个字节，末尾没有 \r\n
+    /// 主节点准备快照期间可能先发送单独的 "\n" 保活，直接跳过
+    pub async fn read_rdb_payload(&mut self) -> Result<Vec<u8>> {
+        loop {
+            while self.buffer.first() == Some(&b'\n') {
+                self.buffer.advance(1);
+            }
+            if let Some((line, header_len)) = read_until_crlf(&self.buffer) {
+                if line.first() != Some(&b'$') {
+                    return Err(anyhow::anyhow!("Bad protocol from MASTER, the first byte is not '$' (we received '{}')", String::from_utf8_lossy(line)));
+                }
+                let len = parse_int(&line[1..])?;
+                if !(0..=MAX_BULK_LEN).contains(&len) {
+                    return Err(anyhow::anyhow!("invalid RDB payload length {}", len));
+                }
+                let end = header_len + len as usize;
+                if self.buffer.len() >= end {
+                    let payload = self.buffer[header_len..end].to_vec();
+                    self.buffer.advance(end);
+                    return Ok(payload);
+                }
+            }
+
+            let bytes_read = self.stream.read_buf(&mut self.buffer).await?;
+            if bytes_read == 0 {
+                return Err(anyhow::anyhow!("Connection with master lost while receiving the RDB payload"));
+            }
         }
+    }
 
+    /// 读取至少一个完整的帧，并返回缓冲区中所有已完整到达的帧
+    pub async fn slave_read_value(&mut self) -> Result<Option<Vec<Value>>> {
         let mut buf = Vec::new();
-        println!("slave_read_value:{:?}",self.buffer); 
-        while self.buffer.len()!=0{
-            let (v, bytes_consumed) = parse_message(self.buffer.clone())?;
-            self.buffer=self.buffer.split_off(bytes_consumed);
-            println!("slave_read_value123:{:?},{}",self.buffer,bytes_consumed); 
-            buf.push(v);
+        loop {
+            while let Some((v, bytes_consumed)) = parse_request(&self.buffer)? {
+                self.buffer.advance(bytes_consumed);
+                buf.push(v);
+            }
+            if !buf.is_empty() {
+                return Ok(Some(buf));
+            }
+
+            let bytes_read = self.stream.read_buf(&mut self.buffer).await?;
+            if bytes_read == 0 {
+                return Ok(None);
+            }
         }
-        println!("{:?}",buf);
-        
-        Ok(Some(buf)) 
+    }
+    /// 拆出底层连接和还没有解析的数据，连接转为复制流时读写分开处理
+    pub fn into_parts(self) -> (TcpStream, BytesMut) {
+        (self.stream, self.buffer)
     }
     pub async fn write_value(&mut self, value: Value) -> Result<()> {
-        match value{
-            Value::RdbFile(f) => {
-                // 创建一个缓冲区来存储要写入的所有字节
-                let mut buffer = Vec::new();
-                buffer.extend_from_slice(format!("${}\r\n", f.len()).as_bytes());
-                buffer.extend_from_slice(&f);
-                // 一次性写入所有数据
-                self.stream.write_all(&buffer).await?;
-            },
-            _=> {
-                self.stream.write_all(value.serialize().as_bytes()).await?;
-            }
-        };
-        
+        // 一次性写入所有数据
+        self.stream.write_all(&value.serialize_with(self.protocol)).await?;
         Ok(())
     }
 }
-fn parse_message(buffer: BytesMut) -> Result<(Value, usize)> {
-    match buffer[0] as char {
-        '+' => parse_simple_string(buffer),
-        '*' => parse_array(buffer),
-        '$' => parse_bulk_string(buffer),
-        _ => Err(anyhow::anyhow!("Not a known value type {:?}", buffer)),
+
+/// 尝试从缓冲区头部解析一个回复，支持 RESP2 和 RESP3 的所有类型。
+/// 返回 Ok(None) 表示数据还不完整，需要等待更多字节；格式错误时返回 Err。
+pub fn parse_message(buffer: &[u8]) -> Result<Option<(Value, usize)>> {
+    parse_value(buffer, 0)
+}
+
+/// 尝试从缓冲区头部解析一条命令，和 Redis 一样只接受批量字符串组成的数组，
+/// 其他开头的数据按 inline 命令处理，例如 telnet 发送的 "PING\r\n"
+/// 空行和 "*0"、"*-1" 这样的空命令直接跳过，连续超过 MAX_INLINE_LEN 字节时按协议错误处理
+pub fn parse_request(buffer: &[u8]) -> Result<Option<(Value, usize)>> {
+    let mut start = 0;
+    loop {
+        let parsed = match buffer.get(start) {
+            None => return Ok(None),
+            Some(b'*') => parse_multibulk(&buffer[start..])?,
+            Some(_) => parse_inline(&buffer[start..])?,
+        };
+        match parsed {
+            None => return Ok(None),
+            Some((Some(value), len)) => return Ok(Some((value, start + len))),
+            Some((None, len)) => {
+                start += len;
+                if start > MAX_INLINE_LEN {
+                    return Err(anyhow::anyhow!("Protocol error: too many empty requests"));
+                }
+            }
+        }
     }
 }
-fn parse_simple_string(buffer: BytesMut) -> Result<(Value, usize)> {
-    if let Some((line, len)) = read_until_crlf(&buffer[1..]) {
-        let string = String::from_utf8(line.to_vec()).unwrap();
-        println!("{:?}",string);
-        return Ok((Value::SimpleString(string), len + 1))
+/// 读取请求中 "*<n>" 或 "$<n>" 的长度行，迟迟没有 \r\n 时按协议错误处理，避免缓冲区无限增长
+fn read_request_header<'a>(buffer: &'a [u8], what: &str) -> Result<Option<(&'a [u8], usize)>> {
+    match read_until_crlf(&buffer[1..]) {
+        Some((line, len)) => Ok(Some((line, len + 1))),
+        None if buffer.len() > MAX_INLINE_LEN => Err(anyhow::anyhow!("Protocol error: too big {} count string", what)),
+        None => Ok(None),
     }
-    return Err(anyhow::anyhow!("Invalid string {:?}", buffer));
 }
-fn parse_array(buffer: BytesMut) -> Result<(Value, usize)> {
-    let (array_length, mut bytes_consumed) = if let Some((line, len)) = read_until_crlf(&buffer[1..]) {
-        let array_length = parse_int(line)?;
-        (array_length, len + 1)
-    } else {
-        return Err(anyhow::anyhow!("Invalid array format {:?}", buffer));
+/// 解析 "*<n>" 开头的命令，每个参数都必须是批量字符串，空命令返回 None
+#[allow(clippy::type_complexity)]
+fn parse_multibulk(buffer: &[u8]) -> Result<Option<(Option<Value>, usize)>> {
+    let (count, mut bytes_consumed) = match read_request_header(buffer, "mbulk")? {
+        Some((line, len)) => match parse_int(line) {
+            Ok(count) if count <= MAX_MULTIBULK_LEN => (count, len),
+            _ => return Err(anyhow::anyhow!("Protocol error: invalid multibulk length")),
+        },
+        None => return Ok(None),
     };
-    let mut items = vec![];
+    if count <= 0 {
+        return Ok(Some((None, bytes_consumed)));
+    }
+    let mut items = Vec::with_capacity(count.min(1024) as usize);
+    for _ in 0..count {
+        let rest = &buffer[bytes_consumed..];
+        match rest.first() {
+            None => return Ok(None),
+            Some(b'$') => {}
+            Some(&c) => return Err(anyhow::anyhow!("Protocol error: expected '$', got '{}'", c as char)),
+        }
+        if read_request_header(rest, "bulk")?.is_none() {
+            return Ok(None);
+        }
+        match parse_bulk_string(rest)? {
+            Some((Value::BulkString(None), _)) => return Err(anyhow::anyhow!("Protocol error: invalid bulk length")),
+            Some((item, len)) => {
+                items.push(item);
+                bytes_consumed += len;
+            }
+            None => return Ok(None),
+        }
+    }
+    Ok(Some((Some(Value::Array(items)), bytes_consumed)))
+}
+fn parse_value(buffer: &[u8], depth: usize) -> Result<Option<(Value, usize)>> {
+    if buffer.is_empty() {
+        return Ok(None);
+    }
+    if depth > MAX_NESTING_DEPTH && matches!(buffer[0], b'*' | b'~' | b'>' | b'%') {
+        return Err(anyhow::anyhow!("Protocol error: too many nested aggregates"));
+    }
+    match buffer[0] {
+        b'+' => parse_simple_string(buffer),
+        b'-' => parse_error(buffer),
+        b':' => parse_integer(buffer),
+        b'*' => parse_aggregate(buffer, depth).map(|r| r.map(|(v, n)| (v.map(Value::Array).unwrap_or(Value::BulkString(None)), n))),
+        b'$' => parse_bulk_string(buffer),
+        // RESP3 类型
+        b'~' => parse_aggregate(buffer, depth).map(|r| r.map(|(v, n)| (Value::Set(v.unwrap_or_default()), n))),
+        b'>' => parse_aggregate(buffer, depth).map(|r| r.map(|(v, n)| (Value::Push(v.unwrap_or_default()), n))),
+        b'%' => parse_map(buffer, depth),
+        b'_' => parse_line(buffer, |_| Ok(Value::Null)),
+        b'#' => parse_line(buffer, |line| match line {
+            b"t" => Ok(Value::Boolean(true)),
+            b"f" => Ok(Value::Boolean(false)),
+            _ => Err(anyhow::anyhow!("invalid boolean {:?}", line)),
+        }),
+        b',' => parse_line(buffer, |line| {
+            let s = std::str::from_utf8(line)?;
+            Ok(Value::Double(match s {
+                "inf" => f64::INFINITY,
+                "-inf" => f64::NEG_INFINITY,
+                _ => match s.parse::<f64>()? {
+                    // Value 实现了 Eq 和 Hash，不能出现 NaN
+                    v if v.is_nan() => return Err(anyhow::anyhow!("Protocol error: invalid double {:?}", s)),
+                    v => v,
+                },
+            }))
+        }),
+        b'(' => parse_line(buffer, |line| Ok(Value::BigNumber(String::from_utf8(line.to_vec())?))),
+        b'=' => parse_verbatim_string(buffer),
+        c => Err(anyhow::anyhow!("Protocol error: unexpected type byte '{}'", c as char)),
+    }
+}
+/// 解析 inline 命令: 一行以空白分隔的参数，支持单引号和双引号，空行返回 None
+fn parse_inline(buffer: &[u8]) -> Result<Option<(Option<Value>, usize)>> {
+    let newline = match buffer.iter().position(|&b| b == b'\n') {
+        Some(pos) => pos,
+        None if buffer.len() > MAX_INLINE_LEN => return Err(anyhow::anyhow!("Protocol error: too big inline request")),
+        None => return Ok(None),
+    };
+    let mut line = &buffer[..newline];
+    if line.last() == Some(&b'\r') {
+        line = &line[..line.len() - 1];
+    }
+    let args = split_args(line).ok_or_else(|| anyhow::anyhow!("Protocol error: unbalanced quotes in request"))?;
+    if args.is_empty() {
+        return Ok(Some((None, newline + 1)));
+    }
+    let items = args.into_iter().map(|arg| Value::BulkString(Some(arg))).collect();
+    Ok(Some((Some(Value::Array(items)), newline + 1)))
+}
+/// 按 Redis sdssplitargs 的规则拆分参数，引号不匹配时返回 None
+fn split_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i >= line.len() {
+            return Some(args);
+        }
+        let mut current = Vec::new();
+        let mut in_dquotes = false;
+        let mut in_squotes = false;
+        loop {
+            if in_dquotes {
+                if i >= line.len() {
+                    return None;
+                }
+                if line[i] == b'\\' && i + 3 < line.len() && line[i + 1] == b'x'
+                    && line[i + 2].is_ascii_hexdigit() && line[i + 3].is_ascii_hexdigit() {
+                    let hex = std::str::from_utf8(&line[i + 2..i + 4]).ok()?;
+                    current.push(u8::from_str_radix(hex, 16).ok()?);
+                    i += 3;
+                } else if line[i] == b'\\' && i + 1 < line.len() {
+                    i += 1;
+                    current.push(match line[i] {
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        b'b' => 0x08,
+                        b'a' => 0x07,
+                        c => c,
+                    });
+                } else if line[i] == b'"' {
+                    // 右引号后面必须是空白或者行尾
+                    if i + 1 < line.len() && !line[i + 1].is_ascii_whitespace() {
+                        return None;
+                    }
+                    in_dquotes = false;
+                    i += 1;
+                    break;
+                } else {
+                    current.push(line[i]);
+                }
+            } else if in_squotes {
+                if i >= line.len() {
+                    return None;
+                }
+                if line[i] == b'\\' && i + 1 < line.len() && line[i + 1] == b'\'' {
+                    i += 1;
+                    current.push(b'\'');
+                } else if line[i] == b'\'' {
+                    if i + 1 < line.len() && !line[i + 1].is_ascii_whitespace() {
+                        return None;
+                    }
+                    in_squotes = false;
+                    i += 1;
+                    break;
+                } else {
+                    current.push(line[i]);
+                }
+            } else {
+                if i >= line.len() {
+                    break;
+                }
+                match line[i] {
+                    b' ' | b'\n' | b'\r' | b'\t' | b'\0' => break,
+                    b'"' => in_dquotes = true,
+                    b'\'' => in_squotes = true,
+                    c => current.push(c),
+                }
+            }
+            i += 1;
+        }
+        debug_assert!(!in_dquotes && !in_squotes);
+        args.push(current);
+    }
+}
+/// 解析只有一行内容的类型，例如 +OK、:1、#t
+fn parse_line<F>(buffer: &[u8], f: F) -> Result<Option<(Value, usize)>>
+where
+    F: FnOnce(&[u8]) -> Result<Value>,
+{
+    match read_until_crlf(&buffer[1..]) {
+        Some((line, len)) => Ok(Some((f(line)?, len + 1))),
+        None => Ok(None),
+    }
+}
+fn parse_simple_string(buffer: &[u8]) -> Result<Option<(Value, usize)>> {
+    parse_line(buffer, |line| Ok(Value::SimpleString(String::from_utf8(line.to_vec())?)))
+}
+fn parse_error(buffer: &[u8]) -> Result<Option<(Value, usize)>> {
+    parse_line(buffer, |line| Ok(Value::Error(String::from_utf8(line.to_vec())?)))
+}
+fn parse_integer(buffer: &[u8]) -> Result<Option<(Value, usize)>> {
+    parse_line(buffer, |line| Ok(Value::Integer(parse_int(line)?)))
+}
+/// 解析数组、集合、推送消息，长度为 -1 时返回 None 表示空数组
+#[allow(clippy::type_complexity)]
+fn parse_aggregate(buffer: &[u8], depth: usize) -> Result<Option<(Option<Vec<Value>>, usize)>> {
+    let (array_length, mut bytes_consumed) = match read_until_crlf(&buffer[1..]) {
+        Some((line, len)) => (parse_int(line)?, len + 1),
+        None => return Ok(None),
+    };
+    if array_length < 0 {
+        return Ok(Some((None, bytes_consumed)));
+    }
+    let mut items = Vec::with_capacity(array_length.min(1024) as usize);
     for _ in 0..array_length {
-        let (array_item, len) = parse_message(BytesMut::from(&buffer[bytes_consumed..]))?;
-        items.push(array_item);
+        match parse_value(&buffer[bytes_consumed..], depth + 1)? {
+            Some((array_item, len)) => {
+                items.push(array_item);
+                bytes_consumed += len;
+            }
+            None => return Ok(None),
+        }
+    }
+    Ok(Some((Some(items), bytes_consumed)))
+}
+fn parse_map(buffer: &[u8], depth: usize) -> Result<Option<(Value, usize)>> {
+    let (map_length, mut bytes_consumed) = match read_until_crlf(&buffer[1..]) {
+        Some((line, len)) => (parse_int(line)?, len + 1),
+        None => return Ok(None),
+    };
+    let mut items = Vec::with_capacity(map_length.clamp(0, 1024) as usize);
+    for _ in 0..map_length {
+        let (k, len) = match parse_value(&buffer[bytes_consumed..], depth + 1)? {
+            Some(item) => item,
+            None => return Ok(None),
+        };
         bytes_consumed += len;
+        let (v, len) = match parse_value(&buffer[bytes_consumed..], depth + 1)? {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        bytes_consumed += len;
+        items.push((k, v));
     }
-    return Ok((Value::Array(items), bytes_consumed))
+    Ok(Some((Value::Map(items), bytes_consumed)))
 }
-fn parse_bulk_string(buffer: BytesMut) -> Result<(Value, usize)> {
-    let (bulk_str_len, bytes_consumed) = if let Some((line, len)) = read_until_crlf(&buffer[1..]) {
-        let bulk_str_len = parse_int(line)?;
-        (bulk_str_len, len + 1)
-    } else {
-        return Err(anyhow::anyhow!("Invalid array format {:?}", buffer));
+fn parse_verbatim_string(buffer: &[u8]) -> Result<Option<(Value, usize)>> {
+    let (len, bytes_consumed) = match read_until_crlf(&buffer[1..]) {
+        Some((line, len)) => (parse_int(line)?, len + 1),
+        None => return Ok(None),
     };
-    
-    let mut end_of_bulk_str = 0 as usize;
-    let mut total_parsed = 0 as usize;
-
-    if bulk_str_len >50 {
-        let temp_buf = &buffer[bytes_consumed..bytes_consumed+5];
-
-        end_of_bulk_str = bytes_consumed + bulk_str_len as usize;
-        total_parsed = end_of_bulk_str;
-
-        if String::from_utf8(temp_buf.to_vec()).unwrap() == "REDIS".to_string(){
-            return Ok((Value::SimpleString("RDBFILE".to_string()),total_parsed))
-        }
-    }else{
-        end_of_bulk_str = bytes_consumed + bulk_str_len as usize;
-        total_parsed = end_of_bulk_str + 2;
+    if !(4..=MAX_BULK_LEN).contains(&len) {
+        return Err(anyhow::anyhow!("invalid verbatim string length {}", len));
+    }
+    let end = bytes_consumed + len as usize;
+    if buffer.len() < end + 2 {
+        return Ok(None);
     }
+    let format = String::from_utf8(buffer[bytes_consumed..bytes_consumed + 3].to_vec())?;
+    Ok(Some((Value::VerbatimString(format, buffer[bytes_consumed + 4..end].to_vec()), end + 2)))
+}
+fn parse_bulk_string(buffer: &[u8]) -> Result<Option<(Value, usize)>> {
+    let (bulk_str_len, bytes_consumed) = match read_until_crlf(&buffer[1..]) {
+        Some((line, len)) => (parse_int(line)?, len + 1),
+        None => return Ok(None),
+    };
 
+    if bulk_str_len < 0 {
+        return Ok(Some((Value::BulkString(None), bytes_consumed)));
+    }
+    if bulk_str_len > MAX_BULK_LEN {
+        return Err(anyhow::anyhow!("invalid bulk length {}", bulk_str_len));
+    }
+    let end_of_bulk_str = bytes_consumed + bulk_str_len as usize;
 
-    Ok((Value::BulkString(Some(String::from_utf8(buffer[bytes_consumed..end_of_bulk_str].to_vec())?)), total_parsed))
+    if buffer.len() < end_of_bulk_str + 2 {
+        return Ok(None);
+    }
+    if &buffer[end_of_bulk_str..end_of_bulk_str + 2] != b"\r\n" {
+        return Err(anyhow::anyhow!("bulk string is not terminated by CRLF"));
+    }
+    Ok(Some((Value::BulkString(Some(buffer[bytes_consumed..end_of_bulk_str].to_vec())), end_of_bulk_str + 2)))
 }
 fn read_until_crlf(buffer: &[u8]) -> Option<(&[u8], usize)> {
     for i in 1..buffer.len() {
@@ -192,8 +621,129 @@ fn read_until_crlf(buffer: &[u8]) -> Option<(&[u8], usize)> {
             return Some((&buffer[0..(i - 1)], i + 1));
         }
     }
-    return None;
+    None
 }
 fn parse_int(buffer: &[u8]) -> Result<i64> {
-    Ok(String::from_utf8(buffer.to_vec())?.parse::<i64>()?)
-}
\ No newline at end of file
+    Ok(std::str::from_utf8(buffer)?.parse::<i64>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bulk(s: &str) -> Value {
+        Value::BulkString(Some(s.as_bytes().to_vec()))
+    }
+
+    fn command(args: &[&str]) -> Value {
+        Value::Array(args.iter().map(|s| bulk(s)).collect())
+    }
+
+    #[test]
+    fn empty_buffer_needs_more_data() {
+        assert!(parse_message(b"").unwrap().is_none());
+        assert!(parse_request(b"").unwrap().is_none());
+    }
+
+    #[test]
+    fn split_frame_waits_for_the_rest() {
+        let frame = command(&["SET", "key", "value"]).serialize();
+        for end in 0..frame.len() {
+            assert!(parse_request(&frame[..end]).unwrap().is_none(), "prefix of {} bytes", end);
+        }
+        let (value, len) = parse_request(&frame).unwrap().unwrap();
+        assert_eq!(value, command(&["SET", "key", "value"]));
+        assert_eq!(len, frame.len());
+    }
+
+    #[test]
+    fn pipelined_frames_are_parsed_one_by_one() {
+        let mut data = command(&["PING"]).serialize();
+        let first_len = data.len();
+        data.extend(command(&["ECHO", "hi"]).serialize());
+        let (first, len) = parse_request(&data).unwrap().unwrap();
+        assert_eq!(first, command(&["PING"]));
+        assert_eq!(len, first_len);
+        let (second, len) = parse_request(&data[first_len..]).unwrap().unwrap();
+        assert_eq!(second, command(&["ECHO", "hi"]));
+        assert_eq!(first_len + len, data.len());
+    }
+
+    #[test]
+    fn nested_replies_are_parsed() {
+        let data = b"*2\r\n*1\r\n:1\r\n%1\r\n+k\r\n$1\r\nv\r\n";
+        let (value, len) = parse_message(data).unwrap().unwrap();
+        assert_eq!(len, data.len());
+        assert_eq!(value, Value::Array(vec![
+            Value::Array(vec![Value::Integer(1)]),
+            Value::Map(vec![(Value::SimpleString("k".to_string()), bulk("v"))]),
+        ]));
+    }
+
+    #[test]
+    fn deep_nesting_is_a_protocol_error() {
+        let data = b"*1\r\n".repeat(MAX_NESTING_DEPTH + 2);
+        assert!(parse_message(&data).is_err());
+        let data = b"*1\r\n".repeat(MAX_NESTING_DEPTH);
+        assert!(parse_message(&data).unwrap().is_none());
+    }
+
+    #[test]
+    fn requests_only_accept_bulk_strings() {
+        assert!(parse_request(b"*1\r\n*1\r\n$4\r\nPING\r\n").is_err());
+        assert!(parse_request(b"*1\r\n:1\r\n").is_err());
+        assert!(parse_request(b"*1\r\n$-1\r\n").is_err());
+        assert!(parse_request(b"*abc\r\n").is_err());
+    }
+
+    #[test]
+    fn empty_requests_are_skipped() {
+        let data = b"\r\n\n*0\r\n*-1\r\n*1\r\n$4\r\nPING\r\n";
+        let (value, len) = parse_request(data).unwrap().unwrap();
+        assert_eq!(value, command(&["PING"]));
+        assert_eq!(len, data.len());
+        assert!(parse_request(b"*0\r\n\r\n").unwrap().is_none());
+        assert!(parse_request(&b"\r\n".repeat(MAX_INLINE_LEN)).is_err());
+    }
+
+    #[test]
+    fn inline_commands_are_split_like_redis() {
+        let (value, len) = parse_request(b"SET k \"a b\\x41\" 'c'\r\n").unwrap().unwrap();
+        assert_eq!(value, command(&["SET", "k", "a bA", "c"]));
+        assert_eq!(len, 21);
+        assert!(parse_request(b"SET k \"a\r\n").is_err());
+    }
+
+    #[test]
+    fn unterminated_lines_are_capped() {
+        assert!(parse_request(&vec![b'a'; MAX_INLINE_LEN]).unwrap().is_none());
+        assert!(parse_request(&vec![b'a'; MAX_INLINE_LEN + 1]).is_err());
+        let mut header = b"*".to_vec();
+        header.extend(vec![b'1'; MAX_INLINE_LEN]);
+        assert!(parse_request(&header).is_err());
+        let mut header = b"*1\r\n$".to_vec();
+        header.extend(vec![b'1'; MAX_INLINE_LEN]);
+        assert!(parse_request(&header).is_err());
+        assert!(parse_request(b"*2000000\r\n").is_err());
+    }
+
+    #[test]
+    fn doubles_reject_nan() {
+        assert_eq!(parse_message(b",1.5\r\n").unwrap().unwrap().0, Value::Double(1.5));
+        assert_eq!(parse_message(b",-inf\r\n").unwrap().unwrap().0, Value::Double(f64::NEG_INFINITY));
+        assert!(parse_message(b",nan\r\n").is_err());
+    }
+
+    #[test]
+    fn serialized_values_parse_back() {
+        let value = Value::Array(vec![
+            bulk("a"),
+            Value::Integer(-3),
+            Value::SimpleString("OK".to_string()),
+            Value::Error("ERR x".to_string()),
+            Value::BulkString(None),
+        ]);
+        let data = value.clone().serialize();
+        assert_eq!(parse_message(&data).unwrap().unwrap(), (value, data.len()));
+    }
+}
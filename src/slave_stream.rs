@@ -1,169 +1,357 @@
 // 定义 Slaves 结构体
-use crate::resp::RespHandler;
+use crate::resp::{self, RespHandler};
 use crate::resp::Value;
-use tokio::net::TcpStream;
-use tokio::sync::Mutex;
-use std::sync::Arc;
+use crate::backlog::ReplBacklog;
 use std::collections::HashMap;
-use hex;
-use std::fs::File;
-use std::io::{self, Read};
-use std::collections::VecDeque;
-use anyhow::Result;
-use tokio::time;
-#[warn(unused_variables)]
+use std::sync::Arc;
+use crate::keyspace::Keyspace;
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{watch, RwLock};
+use tokio::time::{self, Duration, Instant};
+
+// 复制积压缓冲区的大小，和 Redis 的 repl-backlog-size 默认值一致
+pub const REPL_BACKLOG_SIZE: usize = 1024 * 1024;
+// 全量同步期间为单个副本缓存的复制流上限，和 Redis 副本输出缓冲区的硬限制一致
+const REPLICA_OUTPUT_BUFFER_LIMIT: usize = 256 * 1024 * 1024;
+
+/// PSYNC 的结果，连接回复之后据此登记副本
+#[derive(Debug)]
+pub enum ReplicaSync {
+    // 全量同步：PSYNC 时的偏移量、同一时刻的键空间快照和缓存传输期间复制流的副本 id
+    Full(u64, Box<Keyspace>, u64),
+    // 部分同步：副本已经收到的偏移量
+    Partial(u64),
+}
+
+/// 一个已经完成同步、正在接收复制流的副本
+#[derive(Debug)]
+struct Replica {
+    id: u64,
+    // 副本通过 REPLCONF ACK 回报的偏移量
+    ack_offset: u64,
+    // 已经写进连接的字节偏移量，只由这个副本的发送任务更新
+    sent_offset: u64,
+}
+
 #[derive(Debug)]
 pub struct Slaves {
     slave_addrs: HashMap<String,String>,
-    slave_handler:Vec<RespHandler>,
-    slave_offsets:Vec<i32>,
-    master_index: i32, // 记录 master 已执行的命令在 command_hash 数组中的下标
-    master_offset:i32,
-
-    command_hash: Vec<Value>,
-    slave_command_hash_index:Vec<i64>,
+    replicas: Vec<Replica>,
+    next_replica_id: u64,
+    // 第一个副本连接时才创建，之前的写命令不计入偏移量
+    backlog:Option<ReplBacklog>,
+    // 正在接收 RDB 的副本，传输期间的复制流缓存在各自的缓冲区里，不受积压缓冲区大小限制
+    syncing: HashMap<u64, Vec<u8>>,
+    // 复制流中最后一次 SELECT 的数据库，None 时下一条命令前一定补一条 SELECT
+    selected_db:Option<usize>,
+    // 复制流有新数据或者副本被移除时唤醒各个副本的发送任务
+    offset_tx: watch::Sender<u64>,
+    // 收到副本的 ACK 时唤醒等待中的 WAIT
+    ack_tx: watch::Sender<()>,
 }
 
 impl Slaves {
     pub fn new() -> Self {
         Slaves {
             slave_addrs: HashMap::new(),
-            slave_handler: Vec::new(),
-            slave_offsets: Vec::new(),
+            replicas: Vec::new(),
+            next_replica_id: 0,
+            backlog: None,
+            syncing: HashMap::new(),
+            selected_db: None,
+            offset_tx: watch::channel(0).0,
+            ack_tx: watch::channel(()).0,
+        }
+    }
 
-            command_hash: Vec::new(),
-            master_index: 0,
-            master_offset: 0,
-            slave_command_hash_index: Vec::new(),
+    /// WAIT：等到 numreplicas 个副本确认收到 offset 之前的数据，或者超时，返回确认的副本数
+    /// 已经有足够的副本确认时直接返回，否则先发送 GETACK 让副本立即回报；timeout 为 None 时一直等待
+    pub async fn wait(slaves: Arc<RwLock<Slaves>>, numreplicas: usize, offset: u64, timeout: Option<Duration>) -> usize {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        // 先订阅再统计，统计之后到达的 ACK 一定会唤醒
+        let mut ack_rx = {
+            let mut slaves_lock = slaves.write().await;
+            let ack_rx = slaves_lock.ack_tx.subscribe();
+            let acked = slaves_lock.acked_replicas(offset);
+            if acked >= numreplicas {
+                return acked;
+            }
+            slaves_lock.request_ack();
+            ack_rx
+        };
+        loop {
+            let changed = match deadline {
+                Some(deadline) => time::timeout_at(deadline, ack_rx.changed()).await.ok(),
+                None => Some(ack_rx.changed().await),
+            };
+            let acked = slaves.read().await.acked_replicas(offset);
+            if acked >= numreplicas || !matches!(changed, Some(Ok(()))) {
+                return acked;
+            }
         }
     }
-    //用于循环发送心跳包给slave
-    pub async fn r#loop(&mut self) {
-        // println!("{:?},{:?},{:?}",self.command_hash,self.slave_command_hash_index,self.command_hash.len());
-        for (index, item) in self.slave_command_hash_index.iter_mut().enumerate() {
-            if *item<self.command_hash.len() as i64{
-                if self.master_index !=0 && index ==0{
-                    self.master_offset+=37 as i32;
-                }
-                for (command_index,command) in self.command_hash.iter().enumerate().skip(*item as usize) {
-                    // self.slave_handler
-                    // 写入数据
-                    println!("{:?}{:?}",command_index,item);
-                    if index ==0 {
-                        self.master_offset += command.clone().serialize().len() as i32; 
-                        self.master_index+=1;
-                    }
 
-                    if let Some(handler) = self.slave_handler.get_mut(index) {
-                        println!("{:?}",command);
-                        handler.write_value(command.clone()).await;
-                    } 
-                    else {
-                        println!("Index {} is out of bounds", index);
+    /// 已经确认收到 offset 之前数据的副本数
+    pub fn acked_replicas(&self, offset: u64) -> usize {
+        self.replicas.iter().filter(|r| r.ack_offset >= offset).count()
+    }
+
+    /// 在复制流中追加 REPLCONF GETACK *，副本收到后立即回报偏移量
+    fn request_ack(&mut self) {
+        let getack = Value::Array(vec![
+            Value::BulkString(Some("REPLCONF".into())),
+            Value::BulkString(Some("GETACK".into())),
+            Value::BulkString(Some("*".into())),
+        ]);
+        self.feed_backlog(&getack.serialize());
+    }
+
+    /// 是否还有副本没有收到的数据
+    pub fn has_pending_commands(&self) -> bool {
+        let master_offset = self.master_repl_offset();
+        self.replicas.iter().any(|r| r.sent_offset < master_offset)
+    }
+    pub async fn shake_hand_addr_info(&mut self, in_addr: String,listen_addr: String) {
+        println!("New ShakeHand connection came");
+        self.slave_addrs.insert(in_addr,listen_addr);
+    }
+
+    pub fn connected_slaves(&self) -> usize {
+        self.replicas.len()
+    }
+
+    pub fn backlog(&self) -> Option<&ReplBacklog> {
+        self.backlog.as_ref()
+    }
+
+    pub fn master_repl_offset(&self) -> u64 {
+        self.backlog.as_ref().map(|b| b.master_repl_offset()).unwrap_or(0)
+    }
+
+    /// 有副本开始同步时创建积压缓冲区
+    pub fn create_backlog(&mut self) {
+        if self.backlog.is_none() {
+            self.backlog = Some(ReplBacklog::new(REPL_BACKLOG_SIZE, 0));
+            self.selected_db = None;
+        }
+    }
+
+    /// 全量同步的副本从快照之后的复制流开始执行，第一条命令前需要 SELECT
+    pub fn reset_selected_db(&mut self) {
+        self.selected_db = None;
+    }
+
+    /// 全量同步开始，之后的复制流缓存起来，RDB 传输完成后先发给副本
+    pub fn start_full_sync(&mut self) -> u64 {
+        let id = self.next_replica_id;
+        self.next_replica_id += 1;
+        self.syncing.insert(id, Vec::new());
+        id
+    }
+
+    /// 全量同步失败，丢掉为这个副本缓存的复制流
+    pub fn cancel_full_sync(&mut self, id: u64) {
+        self.syncing.remove(&id);
+    }
+
+    /// 副本已经有 offset 个字节时，积压缓冲区能否补齐剩下的数据
+    pub fn can_continue_from(&self, offset: u64) -> bool {
+        self.backlog.as_ref().map(|b| b.bytes_from(offset).is_some()).unwrap_or(false)
+    }
+
+    /// 登记副本，从 offset 开始发送之后的复制流
+    /// 全量同步时 offset 是生成快照时的偏移量，sync_id 对应传输期间缓存的复制流；部分同步时 offset 是副本已经收到的偏移量
+    /// 每个副本一个发送任务和一个读取 ACK 的任务，慢的副本不会拖住其他副本
+    pub async fn attach(slaves: Arc<RwLock<Slaves>>, handler: RespHandler, offset: u64, sync_id: Option<u64>) {
+        let (id, pending, offset_rx) = {
+            let mut slaves_lock = slaves.write().await;
+            let (id, pending) = match sync_id {
+                Some(id) => match slaves_lock.syncing.remove(&id) {
+                    Some(pending) => (id, pending),
+                    None => {
+                        // 缓冲区超过上限或者副本被断开，关闭连接让副本重新同步
+                        println!("Replica {} output buffer was dropped during the transfer, closing the link", id);
+                        return;
                     }
-                    *item+=1;
-                    // break;
-                }
-                if let Some(handler) = self.slave_handler.get_mut(index) {
-                    let mut getack_cmd_vec = Vec::new();
-                    getack_cmd_vec.push(Value::BulkString(Some("REPLCONF".to_string())));
-                    getack_cmd_vec.push(Value::BulkString(Some("GETACK".to_string())));
-                    getack_cmd_vec.push(Value::BulkString(Some("*".to_string())));
-                    time::sleep(time::Duration::from_millis(200)).await;
-    
-                    handler.write_value(Value::Array(getack_cmd_vec.clone())).await;
-                    // if index ==0 {
-                    //     self.master_offset += Value::Array(getack_cmd_vec.clone()).clone().serialize().len() as i32; 
-                    //     println!("{:?},{:?}", self.master_offset,index);
-                    // } 
-                    //等待回复，回复设置offset
-    
-                    // let response = handler.read_value().await?;
-                    let response = handler.read_value().await;
-                    
-                    println!("{:?}",response); 
-    
-                    match response {
-                        Ok(Some(Value::Array(v))) => {
-                            let offset = match v.get(2) {
-                                Some(Value::BulkString(Some(s))) => s.parse::<i32>().unwrap_or(0),
-                                _ => 0,
-                            };
-    
-                            if let Some(handler_offsets) = self.slave_offsets.get_mut(index) { 
-                                // 如果不等的话重新计算,这个item
-                                *handler_offsets = offset.clone();
-                                println!("Handler offset: {}", *handler_offsets);
-                            }
-                        }
-                        Err(e) => eprintln!("Error reading response: {}", e),
-                        _ => println!("Unexpected response format"),
+                },
+                None => {
+                    if !slaves_lock.can_continue_from(offset) {
+                        println!("Replication backlog no longer covers offset {}, closing the link with the replica", offset);
+                        return;
                     }
-                } 
+                    let id = slaves_lock.next_replica_id;
+                    slaves_lock.next_replica_id += 1;
+                    (id, Vec::new())
+                }
+            };
+            slaves_lock.replicas.push(Replica { id, ack_offset: offset, sent_offset: offset });
+            (id, pending, slaves_lock.offset_tx.subscribe())
+        };
+        let (stream, buffer) = handler.into_parts();
+        // 复制流和 ACK 都是小包，关掉 Nagle 避免和延迟确认叠加出几十毫秒的等待
+        let _ = stream.set_nodelay(true);
+        let (reader, writer) = stream.into_split();
+        tokio::spawn(Slaves::replica_writer(slaves.clone(), id, writer, pending, offset_rx));
+        tokio::spawn(Slaves::replica_reader(slaves, id, reader, buffer));
     }
+
+    /// 先写出全量同步期间缓存的复制流，再把积压缓冲区中副本还没有收到的数据写进连接，没有新数据时等待通知
+    async fn replica_writer(slaves: Arc<RwLock<Slaves>>, id: u64, mut writer: OwnedWriteHalf, pending: Vec<u8>, mut offset_rx: watch::Receiver<u64>) {
+        if let Err(e) = writer.write_all(&pending).await {
+            println!("Error writing to replica {}: {}", id, e);
+            slaves.write().await.remove_replica(id);
+            return;
+        }
+        if let Some(replica) = slaves.write().await.replica_mut(id) {
+            replica.sent_offset += pending.len() as u64;
+        }
+        loop {
+            // 先标记已读再取数据，之后追加的数据一定会再次唤醒
+            offset_rx.borrow_and_update();
+            let (sent_offset, data) = {
+                let slaves_lock = slaves.read().await;
+                let sent_offset = match slaves_lock.replica(id) {
+                    Some(replica) => replica.sent_offset,
+                    None => return,
+                };
+                match slaves_lock.backlog.as_ref().and_then(|b| b.bytes_from(sent_offset)) {
+                    Some(data) => (sent_offset, data),
+                    None => {
+                        drop(slaves_lock);
+                        // 需要的数据已经被覆盖，只能断开让副本重新全量同步
+                        println!("Replica {} is lagging behind the replication backlog, dropping it", id);
+                        slaves.write().await.remove_replica(id);
+                        return;
+                    }
+                }
+            };
+            if data.is_empty() {
+                if offset_rx.changed().await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            if let Err(e) = writer.write_all(&data).await {
+                println!("Error writing to replica {}: {}", id, e);
+                slaves.write().await.remove_replica(id);
+                return;
+            }
+            if let Some(replica) = slaves.write().await.replica_mut(id) {
+                replica.sent_offset = sent_offset + data.len() as u64;
+            }
         }
     }
 
-    pub fn wait(&mut self, slave_num:i32)->Result<Value>{
-        // 等待当前命令完成
-        let mut slave_done = 0;
-        for (index, item) in self.slave_offsets.iter_mut().enumerate() {
-            if *item == self.master_offset && self.master_offset != 0{
-                slave_done +=1;
+    /// 读取副本发来的 REPLCONF ACK，连接断开时移除副本
+    async fn replica_reader(slaves: Arc<RwLock<Slaves>>, id: u64, mut reader: OwnedReadHalf, mut buffer: BytesMut) {
+        loop {
+            loop {
+                let (value, len) = match resp::parse_request(&buffer) {
+                    Ok(Some(parsed)) => parsed,
+                    Ok(None) => break,
+                    Err(e) => {
+                        println!("Protocol error from replica {}: {}", id, e);
+                        slaves.write().await.remove_replica(id);
+                        return;
+                    }
+                };
+                buffer.advance(len);
+                if let Some(offset) = parse_ack(&value) {
+                    let mut slaves_lock = slaves.write().await;
+                    if let Some(replica) = slaves_lock.replica_mut(id) {
+                        replica.ack_offset = replica.ack_offset.max(offset);
+                        slaves_lock.ack_tx.send_replace(());
+                    }
+                }
             }
-            else if (*item == self.master_offset && self.master_offset != 0)||(*item==0 && self.master_offset==0){
-                slave_done +=1;
+            match reader.read_buf(&mut buffer).await {
+                Ok(0) => println!("Connection with replica {} lost", id),
+                Ok(_) => continue,
+                Err(e) => println!("Error reading from replica {}: {}", id, e),
             }
+            slaves.write().await.remove_replica(id);
+            return;
+        }
+    }
+
+    /// 断开所有副本，发送任务退出时关闭连接，副本随后重新同步
+    pub fn disconnect_all(&mut self) {
+        self.syncing.clear();
+        if !self.replicas.is_empty() {
+            println!("Disconnecting {} replicas", self.replicas.len());
+            self.replicas.clear();
+            self.offset_tx.send_replace(self.master_repl_offset());
         }
+    }
 
-        Ok(Value::Integer(slave_done as i64))
+    fn replica(&self, id: u64) -> Option<&Replica> {
+        self.replicas.iter().find(|r| r.id == id)
     }
-    pub async fn shake_hand_addr_info(&mut self, in_addr: String,listen_addr: String) {
-        println!("New ShakeHand connection came");
-        self.slave_addrs.insert(in_addr,listen_addr);
+
+    fn replica_mut(&mut self, id: u64) -> Option<&mut Replica> {
+        self.replicas.iter_mut().find(|r| r.id == id)
     }
 
-    pub async fn add_new_slave_handler(&mut self,mut handler:RespHandler){
-        //写入数据进去
-        let val=Slaves::get_empty_rdbfile();
-        time::sleep(time::Duration::from_millis(50)).await;
-        handler.write_value(val).await.unwrap();
-        
-        self.slave_handler.push(handler);
-        self.slave_offsets.push(0 as i32);
-
-        self.slave_command_hash_index.push(0);
-    }
-    pub fn get_empty_rdbfile()->Value{
-        // 打开文件
-        let empty_file_payload = hex::decode("524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2").unwrap();
-        // 创建一个缓冲区
-        let mut buffer = Vec::new();
-        // 将解码后的字节数据放入缓冲区
-        buffer.extend_from_slice(&empty_file_payload);
-
-        Value::RdbFile(buffer)
-    }
-    pub async fn get_new_client_cmd(&mut self, cmd:Value)->Result<String>{
-        let command_string = match cmd.clone() {
-            Value::Array(a) => {
-                   let cmd_clone=a.first().unwrap().clone();
-                   match cmd_clone {
-                       Value::BulkString(Some(s)) => s.clone(),
-                       _ => return Err(anyhow::anyhow!("Unexpected command format")),
-                   }
-            },
-            _ => return Err(anyhow::anyhow!("Unexpected command format")),
-        };
-        match command_string.to_lowercase().as_str(){
-            "set"|"del" =>{
-                self.command_hash.push(cmd.clone());
+    /// 移除副本并唤醒它的发送任务，发送任务退出时关闭连接
+    fn remove_replica(&mut self, id: u64) {
+        let before = self.replicas.len();
+        self.replicas.retain(|r| r.id != id);
+        if self.replicas.len() != before {
+            self.offset_tx.send_replace(self.master_repl_offset());
+        }
+    }
+
+    fn feed_backlog(&mut self, data: &[u8]) {
+        self.syncing.retain(|id, pending| {
+            if pending.len() + data.len() > REPLICA_OUTPUT_BUFFER_LIMIT {
+                println!("Replica {} output buffer exceeded {} bytes during the transfer, dropping it", id, REPLICA_OUTPUT_BUFFER_LIMIT);
+                return false;
             }
-            _ => {
+            pending.extend_from_slice(data);
+            true
+        });
+        if let Some(backlog) = self.backlog.as_mut() {
+            backlog.feed(data);
+            self.offset_tx.send_replace(backlog.master_repl_offset());
+        }
+    }
+
+    /// 把在 db_index 上执行的写命令追加到复制流，数据库变化时先补一条 SELECT
+    /// 还没有副本连接过时不需要记录
+    pub fn feed(&mut self, db_index:usize, cmds:&[Value]){
+        if self.backlog.is_none() {
+            return;
+        }
+        let mut buf = Vec::new();
+        if self.selected_db != Some(db_index) {
+            let select = Value::Array(vec![
+                Value::BulkString(Some("SELECT".into())),
+                Value::BulkString(Some(db_index.to_string().into_bytes())),
+            ]);
+            buf.extend(select.serialize());
+            self.selected_db = Some(db_index);
+        }
+        for cmd in cmds {
+            buf.extend(cmd.clone().serialize());
+        }
+        self.feed_backlog(&buf);
+    }
+}
 
+/// 解析 REPLCONF ACK <offset>，其他命令返回 None
+fn parse_ack(value: &Value) -> Option<u64> {
+    match value {
+        Value::Array(items) if items.len() >= 3 => {
+            let is_ack = matches!(items[0].as_str(), Some(s) if s.eq_ignore_ascii_case("replconf"))
+                && matches!(items[1].as_str(), Some(s) if s.eq_ignore_ascii_case("ack"));
+            if is_ack {
+                items[2].as_str().and_then(|s| s.parse::<u64>().ok())
+            } else {
+                None
             }
         }
-        //TODO: realize command logic
-        Ok("Yes, command".to_string())
+        _ => None,
     }
-}
\ No newline at end of file
+}
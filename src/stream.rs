@@ -1,308 +1,189 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::time::{SystemTime, UNIX_EPOCH};
 use crate::resp::Value;
 use anyhow::anyhow;
 use anyhow::Result;
-use regex::Regex;
 
-#[derive(Debug, Clone)]
-pub struct Stream {
-    stream_items:HashMap<Value,HashMap<Value,HashMap<Value,Value>>>,
-    last_ids: HashMap<Value, Value>, // 用于存储每个 name_key 的最后一个条目的 ID
+/// 流条目的 ID，格式为 <毫秒时间戳>-<序列号>
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
 }
 
-impl Stream {
-    pub fn new() -> Self {
-        Stream {
-            stream_items: HashMap::new(),
-            last_ids: HashMap::new(),
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    /// 解析完整或只有时间戳的 ID，只有时间戳时序列号取 missing_seq
+    fn parse_with_default(s: &[u8], missing_seq: u64) -> Result<StreamId> {
+        let s = std::str::from_utf8(s).map_err(|_| invalid_id())?;
+        let (ms, seq) = match s.split_once('-') {
+            Some((ms, seq)) => (ms.parse::<u64>(), seq.parse::<u64>()),
+            None => (s.parse::<u64>(), Ok(missing_seq)),
+        };
+        match (ms, seq) {
+            (Ok(ms), Ok(seq)) => Ok(StreamId { ms, seq }),
+            _ => Err(invalid_id()),
         }
     }
 
-    pub fn insert_stream_item(&mut self, (name_key, name_value): (Value, Value), entry: HashMap<Value, Value>) -> Result<Value> {
-        let id = self.generate_id(name_key.clone(),name_value.clone());
-        // println!("{}", id);
-
-        if id <= Value::BulkString(Some("0-0".to_string())) {
-            return Err(anyhow!("ERR The ID specified in XADD must be greater than 0-0"));
-        }
-        let last_id = self.last_ids.get(&name_key).cloned().unwrap_or_else(|| Value::BulkString(Some("0-0".to_string())));
+    pub fn parse(s: &[u8]) -> Result<StreamId> {
+        Self::parse_with_default(s, 0)
+    }
 
-        if !self.is_valid_id(&id, &last_id) {
-            return Err(anyhow!("ERR The ID specified in XADD is equal or smaller than the target stream top item"));
+    /// XRANGE 的起始 ID，"-" 表示最小值
+    pub fn parse_range_start(s: &[u8]) -> Result<StreamId> {
+        if s == b"-" {
+            return Ok(StreamId::MIN);
         }
+        Self::parse_with_default(s, 0)
+    }
 
-        let stream_name_hashmap = self.stream_items.entry(name_key.clone()).or_insert_with(HashMap::new);
-
-        if stream_name_hashmap.contains_key(&id) {
-            return Err(anyhow!("ID {:?} already exists", id.clone()));
+    /// XRANGE 的结束 ID，"+" 表示最大值
+    pub fn parse_range_end(s: &[u8]) -> Result<StreamId> {
+        if s == b"+" {
+            return Ok(StreamId::MAX);
         }
+        Self::parse_with_default(s, u64::MAX)
+    }
 
-        stream_name_hashmap.insert(id.clone(), entry.clone());
-
-        self.last_ids.insert(name_key.clone(), id.clone()); // 更新最后一个条目的 I
+    pub fn to_value(self) -> Value {
+        Value::BulkString(Some(self.to_string().into_bytes()))
+    }
+}
 
-        Ok(id)
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
     }
+}
 
-    /// 获取每个给定流中的最新条目的 ID，如果流为空，则返回 "0-0"
-    pub fn xread_latest(&self, streams: Value) -> Result<Value> {
+fn invalid_id() -> anyhow::Error {
+    anyhow!("ERR Invalid stream ID specified as stream command argument")
+}
 
-        let latest_id = self.stream_items
-            .get(&streams)
-            .and_then(|stream| {
-                // 寻找最大的 ID
-                stream.keys()
-                    .max_by(|a, b| compare_ids(a.clone().clone(), b.clone().clone()))
-                    .cloned()
-            })
-            .unwrap_or_else(|| Value::BulkString(Some("0-0".to_string())));
+/// 一个条目的字段和值，保持插入顺序
+pub type Fields = Vec<(Vec<u8>, Vec<u8>)>;
 
-        Ok(latest_id)
+/// 单个 key 对应的流，条目按 ID 有序保存，字段保持插入顺序
+#[derive(Debug, Clone)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    last_id: StreamId,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Stream {
+            entries: BTreeMap::new(),
+            last_id: StreamId::MIN,
+        }
     }
 
-    pub fn xread(&mut self, streams: Vec<(Value, Value)>) -> Result<Vec<Value>> {
-        let mut stream_entries: HashMap<Value, Vec<Vec<Value>>> = HashMap::new();
-        
-        // 收集所有流的数据
-        for (stream_name, stream_key) in &streams {
-            if let Some(stream_name_hashmap) = self.stream_items.get(stream_name) {
-                let mut min_id = None;
-                
-                for (id, entry) in stream_name_hashmap.iter() {
-                    if *id > *stream_key {
-                        if min_id.is_none() || *id < min_id.clone().unwrap() {
-                            min_id = Some(id.clone());
-                        }
-                    }
-                }
-                
-                if let Some(min_id) = min_id {
-                    if let Some(entry) = stream_name_hashmap.get(&min_id) {
-                        let entry_array = vec![
-                            min_id.clone(),
-                            Value::Array(
-                                entry.iter()
-                                    .flat_map(|(k, v)| vec![k.clone(), v.clone()])
-                                    .collect(),
-                            ),
-                        ];
-                        stream_entries
-                            .entry(stream_name.clone())
-                            .or_insert_with(Vec::new)
-                            .push(entry_array);
-                    }
-                }
-            }
+    /// XADD，id 可以是 "*"、"<ms>-*" 或完整的 ID
+    pub fn add(&mut self, id: &[u8], fields: Fields) -> Result<StreamId> {
+        let id = self.generate_id(id)?;
+        if id == StreamId::MIN {
+            return Err(anyhow!("ERR The ID specified in XADD must be greater than 0-0"));
         }
-        
-        // 根据输入的顺序构建结果
-        let mut results = Vec::new();
-        for (stream_name, _) in &streams {
-            if let Some(entries) = stream_entries.get(stream_name) {
-                let stream_result = vec![
-                    stream_name.clone(),
-                    Value::Array(entries.iter().cloned().map(|e| Value::Array(e)).collect()),
-                ];
-                results.push(Value::Array(stream_result));
-            }
+        if id <= self.last_id {
+            return Err(anyhow!("ERR The ID specified in XADD is equal or smaller than the target stream top item"));
         }
-    
-        // println!("{:?}", results);
-    
-        Ok(results)
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        Ok(id)
     }
 
-    pub fn xrange(&mut self, name_key:Value,start_id: Value, end_id: Value) -> Result<Vec<Value>> {
-        let mut entries = Vec::new();
-
-        let stream_name_hashmap = self.stream_items.entry(name_key.clone()).or_insert_with(HashMap::new);
-
-        // 检查 start_id 和 end_id 是否包含 "-"
-        if !contains_hyphen(&start_id) && !contains_hyphen(&end_id) {
-            for (id, entry) in stream_name_hashmap {
-                let mut entry_array = Vec::new();
-                let id_no_tail = remove_trailing(&id);
-                
-                if id_no_tail >= start_id && id_no_tail <= end_id {
-                    entry_array.push(id.clone());
-                    let mut array = Vec::new();
-                    for item in entry.iter() {
-                        let (key, value) = item;
-                        array.push(key.clone());
-                        array.push(value.clone());
-                    }
-                    entry_array.push(Value::Array(array));
-                    entries.push(entry_array);
-                }
-            }
-        } else if !contains_hyphen(&start_id) && contains_plus(&end_id){
-            for (id, entry) in stream_name_hashmap {
-                let mut entry_array = Vec::new();
-                let id_no_tail = remove_trailing(&id);
-                
-                if id_no_tail >= start_id && id_no_tail <= end_id {
-                    entry_array.push(id.clone());
-                    let mut array = Vec::new();
-                    for item in entry.iter() {
-                        let (key, value) = item;
-                        array.push(key.clone());
-                        array.push(value.clone());
-                    }
-                    entry_array.push(Value::Array(array));
-                    entries.push(entry_array);
-                }
+    fn generate_id(&self, id: &[u8]) -> Result<StreamId> {
+        if id == b"*" {
+            // 自动生成时间和序列号，时钟回拨时沿用上一个时间戳
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_millis() as u64;
+            if now > self.last_id.ms {
+                return Ok(StreamId { ms: now, seq: 0 });
             }
+            return self.next_seq_after(self.last_id.ms);
         }
-        else if contains_hyphen(&start_id) && contains_plus(&end_id) {
-            for (id, entry) in stream_name_hashmap {
-                let mut entry_array = Vec::new();
-                let id_no_tail = remove_trailing(&id);
-                
-                if *id >= start_id{
-                    entry_array.push(id.clone());
-                    let mut array = Vec::new();
-                    for item in entry.iter() {
-                        let (key, value) = item;
-                        array.push(key.clone());
-                        array.push(value.clone());
-                    }
-                    entry_array.push(Value::Array(array));
-                    entries.push(entry_array);
-                }
+        if let Some(ms) = id.strip_suffix(b"-*") {
+            // 自动生成序列号
+            let ms = std::str::from_utf8(ms)
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .ok_or_else(invalid_id)?;
+            if ms > self.last_id.ms {
+                return Ok(StreamId { ms, seq: if ms == 0 { 1 } else { 0 } });
             }
-        }
-        else {
-            for (id, entry) in stream_name_hashmap {
-                let mut entry_array = Vec::new();
-                
-                if *id >= start_id && *id <= end_id {
-                    entry_array.push(id.clone());
-                    let mut array = Vec::new();
-                    for item in entry.iter() {
-                        let (key, value) = item;
-                        array.push(key.clone());
-                        array.push(value.clone());
-                    }
-                    entry_array.push(Value::Array(array));
-                    entries.push(entry_array);
-                }
+            if ms < self.last_id.ms {
+                return Err(anyhow!("ERR The ID specified in XADD is equal or smaller than the target stream top item"));
             }
+            return self.next_seq_after(ms);
         }
-
-        // 对 entries 进行排序
-        entries.sort_by(|a, b| {
-            let a_id = a.get(0).unwrap().clone();
-            let b_id = b.get(0).unwrap().clone();
-            compare_ids(a_id, b_id)
-        });
-
-        // 将排序后的 entries 转换为 result
-        let result: Vec<Value> = entries.into_iter().map(|e| Value::Array(e)).collect();
-
-        Ok(result)
-
+        // 显式指定 id
+        StreamId::parse(id)
     }
 
-
-    fn generate_id(&mut self, name_key:Value,id: Value) -> Value {
-        match id {
-            Value::BulkString(Some(ref s)) if s == "*" => {
-                // 自动生成时间和序列号
-                let timestamp = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Time went backwards")
-                    .as_millis();
-                let sequence = 0;
-                self.generate_unique_id(name_key,format!("{}", timestamp))
-            }
-            Value::BulkString(Some(ref s)) if s.ends_with('*') => {
-                // 自动生成序列号
-                let timestamp = s.trim_end_matches('*').trim_end_matches('-');
-                self.generate_unique_id(name_key,format!("{}", timestamp))
-            }
-            Value::BulkString(Some(ref s)) => {
-                // 显式指定 id
-                Value::BulkString(Some(s.clone()))
-            }
-            _ => {
-                // 处理其他类型的 Value
-                Value::BulkString(Some(id.to_string()))
-            }
+    fn next_seq_after(&self, ms: u64) -> Result<StreamId> {
+        match self.last_id.seq.checked_add(1) {
+            Some(seq) => Ok(StreamId { ms, seq }),
+            None => Err(anyhow!("ERR The stream has exhausted the last possible ID, unable to add more items")),
         }
     }
 
-    fn generate_unique_id(&mut self, name_key:Value,mut base_id: String) -> Value {
-        let stream_name_hashmap = self.stream_items.entry(name_key.clone()).or_insert_with(HashMap::new);
-        let mut sequence = if base_id=="0".to_string(){
-            1
-        }else{
-            0
-        };
-        // let mut sequence = 0;
-        loop {
-            let id = format!("{}-{}", base_id, sequence);
-            if !stream_name_hashmap.contains_key(&Value::BulkString(Some(id.clone()))) {
-                return Value::BulkString(Some(id));
-            }
-            sequence += 1;
+    /// 返回 [start, end] 范围内的条目
+    pub fn range(&self, start: StreamId, end: StreamId, count: Option<usize>) -> Vec<Value> {
+        if start > end {
+            return vec![];
         }
+        self.entries
+            .range(start..=end)
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| entry_to_value(id, fields))
+            .collect()
     }
 
-    fn is_valid_id(&self, new_id: &Value, last_id: &Value) -> bool {
-        let (new_timestamp, new_sequence) = parse_id(new_id);
-        let (last_timestamp, last_sequence) = parse_id(last_id);
-
-        new_timestamp > last_timestamp || (new_timestamp == last_timestamp && new_sequence > last_sequence)
+    /// 返回 ID 严格大于 after 的条目，用于 XREAD
+    pub fn read_after(&self, after: StreamId, count: Option<usize>) -> Vec<Value> {
+        use std::ops::Bound::{Excluded, Unbounded};
+        self.entries
+            .range((Excluded(after), Unbounded))
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| entry_to_value(id, fields))
+            .collect()
     }
-}
 
-fn parse_id(id: &Value) -> (u128, u64) {
-    match id {
-        Value::BulkString(Some(ref s)) => {
-            let parts: Vec<&str> = s.split('-').collect();
-            if parts.len() != 2 {
-                panic!("Invalid ID format: {}", s);
-            }
-            let timestamp = parts[0].parse::<u128>().expect("Invalid timestamp");
-            let sequence = parts[1].parse::<u64>().expect("Invalid sequence number");
-            (timestamp, sequence)
-        }
-        _ => panic!("Invalid ID type"),
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
     }
-}
 
-fn remove_trailing(id: &Value) -> Value {
-    match id {
-        Value::BulkString(Some(ref s)) => {
-            let re = Regex::new(r"-[0-9]+$").unwrap();
-            // println!("{:?}",s.trim_end_matches("-").to_string());
-            Value::BulkString(Some(re.replace_all(s, "").to_string()))
-        }
-        _ => {Value::BulkString(Some("".to_string()))},
+    /// 按 ID 顺序遍历所有条目，用于保存 RDB
+    pub fn entries(&self) -> std::collections::btree_map::Iter<'_, StreamId, Fields> {
+        self.entries.iter()
     }
-}
-fn contains_hyphen(id: &Value) -> bool {
-    match id {
-        Value::BulkString(Some(ref s)) => s.contains('-'),
-        _ => false,
+
+    /// 加载 RDB 时直接放入已有 ID 的条目
+    pub fn load_entry(&mut self, id: StreamId, fields: Fields) {
+        self.entries.insert(id, fields);
+        self.last_id = self.last_id.max(id);
     }
-}
-fn contains_plus(id: &Value) -> bool {
-    match id {
-        Value::BulkString(Some(ref s)) => s.contains('+'),
-        _ => false,
+
+    /// RDB 中记录的最后一个 ID，可能比现存条目的最大 ID 更大（条目被删除后）
+    pub fn set_last_id(&mut self, id: StreamId) {
+        self.last_id = self.last_id.max(id);
     }
 }
-// 辅助函数：比较两个 ID
-fn compare_ids(a: Value, b: Value) -> std::cmp::Ordering {
-    match (a, b) {
-        (Value::BulkString(Some(a_str)), Value::BulkString(Some(b_str))) => {
-            let (a_timestamp, a_sequence) = parse_id(&Value::BulkString(Some(a_str)));
-            let (b_timestamp, b_sequence) = parse_id(&Value::BulkString(Some(b_str)));
 
-            a_timestamp.cmp(&b_timestamp).then_with(|| a_sequence.cmp(&b_sequence))
-        }
-        _ => std::cmp::Ordering::Equal, // 如果类型不匹配，默认相等
-    }
-}
\ No newline at end of file
+fn entry_to_value(id: &StreamId, fields: &[(Vec<u8>, Vec<u8>)]) -> Value {
+    Value::Array(vec![
+        id.to_value(),
+        Value::Array(
+            fields
+                .iter()
+                .flat_map(|(k, v)| vec![Value::BulkString(Some(k.clone())), Value::BulkString(Some(v.clone()))])
+                .collect(),
+        ),
+    ])
+}